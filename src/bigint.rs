@@ -2,6 +2,7 @@ use crate::object::*;
 use crate::vtable::*;
 
 pub static BIGINT_VTBL: VTable = VTable {
+    name: "BigInt",
     element_size: 0,
     instance_size: std::mem::size_of::<BigIntObject>(),
     parent: None,
@@ -16,7 +17,6 @@ pub static BIGINT_VTBL: VTable = VTable {
 };
 
 fn destroy_bigint(x: Ref<Obj>) {
-    println!("ded");
     let this = x.cast::<BigIntObject>();
     unsafe {
         std::ptr::drop_in_place(this.ptr.as_ptr() as *mut BigIntObject);
@@ -2,15 +2,18 @@ use crate::object::*;
 use crate::value::*;
 use crate::vtable::*;
 use crate::*;
+use bytecode::virtual_register::VirtualRegister;
+use interpreter::callframe::CallFrame;
 pub static ARRAY_VTBL: VTable = VTable {
+    name: "Array",
     element_size: 8,
-    instance_size: 0,
+    instance_size: std::mem::size_of::<Array>(),
     parent: None,
     lookup_fn: Some(array_lookup),
     index_fn: None,
-    calc_size_fn: Some(determine_array_size),
+    calc_size_fn: None,
     apply_fn: None,
-    destroy_fn: None,
+    destroy_fn: Some(destroy_array),
     set_fn: Some(array_set),
     trace_fn: Some(trace_array),
     set_index_fn: None,
@@ -21,53 +24,242 @@ pub fn array_lookup(vm: &VM, this: Ref<Obj>, key: Value) -> WaffleResult {
     if key == vm.length {
         return WaffleResult::okay(Value::new_int(this.len() as _));
     } else if key.is_number() {
-        let idx = key.to_number().trunc() as usize;
-        if idx < this.len() {
-            return WaffleResult::okay(this.get_at(idx));
+        let idx = key.to_number().trunc();
+        if idx >= 0.0 && (idx as usize) < this.len() {
+            return WaffleResult::okay(this.get_at(idx as usize));
         } else {
             WaffleResult::okay(Value::undefined())
         }
+    } else if vm.array_prototype.is_cell() {
+        let proto = vm.array_prototype.as_cell();
+        proto.vtable.lookup_fn.unwrap()(vm, proto, key)
     } else {
         WaffleResult::okay(Value::undefined())
     }
 }
 
+/// Number of `undefined` holes a store past the end may add on top of the length.
+pub const MAX_ARRAY_GAP: usize = 1 << 16;
+
+/// Stores past the end grow the array, filling the gap with `undefined`. Arrays grow by at
+/// most their length plus `MAX_ARRAY_GAP` at once, stores further away throw a `RangeError`.
 pub fn array_set(_: &VM, this: Ref<Obj>, key: Value, value: Value) -> WaffleResult {
     if !key.is_number() {
         return WaffleResult::okay(Value::new_bool(false));
     }
     let mut this = this.cast::<Array>();
-    let idx = key.to_number().trunc() as usize;
-    if idx < this.len() {
-        this.set_at(idx, value);
-        WaffleResult::okay(Value::new_bool(true))
-    } else {
-        WaffleResult::okay(Value::new_bool(false))
+    let idx = key.to_number().trunc();
+    if idx < 0.0 || idx >= (this.len() * 2 + MAX_ARRAY_GAP) as f64 {
+        return WaffleResult::error(Value::from(
+            WaffleString::new(
+                &mut get_vm().heap,
+                format!("RangeError: array index {} is out of bounds", key.to_number()),
+            )
+            .cast(),
+        ));
+    }
+    let idx = idx as usize;
+    if idx >= this.len() {
+        this.resize(idx + 1);
     }
+    this.set_at(idx, value);
+    WaffleResult::okay(Value::new_bool(true))
 }
 
 pub fn trace_array(arr: Ref<Obj>, trace: &mut dyn FnMut(*const Ref<Obj>)) {
     let arr = arr.cast::<Array>();
     debug_assert!(arr.vtable as *const VTable == &ARRAY_VTBL as *const _);
     for i in 0..arr.len() {
-        let item = arr.get_at(i);
+        let item = unsafe { &*arr.data().offset(i as isize) };
         if item.is_cell() {
             trace(item.as_cell_ref());
         }
     }
 }
 
-fn determine_array_size(obj: Ref<Obj>) -> usize {
-    let handle: Ref<Array> = obj.cast();
+fn destroy_array(arr: Ref<Obj>) {
+    arr.cast::<Array>().free_storage();
+}
+
+fn this_array(cf: &mut CallFrame, name: &str) -> Result<Ref<Array>, WaffleResult> {
+    if cf.this.is_cell() && cf.this.as_cell().is_array_ref() {
+        Ok(cf.this.as_cell().cast())
+    } else {
+        Err(WaffleResult::error(Value::from(
+            WaffleString::new(
+                &mut get_vm().heap,
+                format!("Array.{} called on non-array value", name),
+            )
+            .cast(),
+        )))
+    }
+}
+
+pub extern "C" fn array_push(cf: &mut CallFrame) -> WaffleResult {
+    let mut arr = match this_array(cf, "push") {
+        Ok(arr) => arr,
+        Err(e) => return e,
+    };
+    for i in 0..cf.passed_argc {
+        let val = cf.get_register(VirtualRegister::new_argument(i as _));
+        arr.push(val);
+    }
+    WaffleResult::okay(Value::new_int(arr.len() as _))
+}
+
+pub extern "C" fn array_pop(cf: &mut CallFrame) -> WaffleResult {
+    let mut arr = match this_array(cf, "pop") {
+        Ok(arr) => arr,
+        Err(e) => return e,
+    };
+    WaffleResult::okay(arr.pop())
+}
+
+pub static TUPLE_VTBL: VTable = VTable {
+    name: "Tuple",
+    element_size: 8,
+    instance_size: 0,
+    parent: None,
+    lookup_fn: Some(tuple_lookup),
+    index_fn: None,
+    calc_size_fn: Some(determine_tuple_size),
+    apply_fn: None,
+    destroy_fn: None,
+    set_fn: Some(tuple_set),
+    trace_fn: Some(trace_tuple),
+    set_index_fn: None,
+};
+
+pub fn tuple_lookup(vm: &VM, this: Ref<Obj>, key: Value) -> WaffleResult {
+    let this = this.cast::<Tuple>();
+    if key == vm.length {
+        WaffleResult::okay(Value::new_int(this.len() as _))
+    } else if key.is_number() && key.to_number() >= 0.0 {
+        WaffleResult::okay(this.get_at(key.to_number().trunc() as usize))
+    } else {
+        WaffleResult::okay(Value::undefined())
+    }
+}
+
+fn tuple_set(_: &VM, _: Ref<Obj>, _: Value, _: Value) -> WaffleResult {
+    WaffleResult::error(Value::from(
+        WaffleString::new(&mut get_vm().heap, "tuples are immutable").cast(),
+    ))
+}
+
+pub fn trace_tuple(tuple: Ref<Obj>, trace: &mut dyn FnMut(*const Ref<Obj>)) {
+    let tuple = tuple.cast::<Tuple>();
+    for i in 0..tuple.len() {
+        let item = unsafe { &*tuple.data().offset(i as isize) };
+        if item.is_cell() {
+            trace(item.as_cell_ref());
+        }
+    }
+}
 
-    let calc = Header::size() as usize
-        + std::mem::size_of::<usize>()
-        + std::mem::size_of::<usize>()
-        + handle.vtable.element_size * handle.len() as usize;
-    calc
+fn determine_tuple_size(obj: Ref<Obj>) -> usize {
+    Tuple::size_for(obj.cast::<Tuple>().len())
+}
+
+pub static UPVALUE_VTBL: VTable = VTable {
+    name: "Upvalue",
+    element_size: 0,
+    instance_size: std::mem::size_of::<Upvalue>(),
+    parent: None,
+    lookup_fn: None,
+    index_fn: None,
+    calc_size_fn: None,
+    apply_fn: None,
+    destroy_fn: None,
+    set_fn: None,
+    trace_fn: Some(trace_upvalue),
+    set_index_fn: None,
+};
+
+pub fn trace_upvalue(upvalue: Ref<Obj>, trace: &mut dyn FnMut(*const Ref<Obj>)) {
+    let upvalue = upvalue.cast::<Upvalue>();
+    if upvalue.value.is_cell() {
+        trace(upvalue.value.as_cell_ref());
+    }
+}
+
+pub static WEAK_REF_VTBL: VTable = VTable {
+    name: "WeakRef",
+    element_size: 0,
+    instance_size: std::mem::size_of::<WeakRef>(),
+    parent: None,
+    lookup_fn: Some(weak_ref_lookup),
+    index_fn: None,
+    calc_size_fn: None,
+    apply_fn: None,
+    destroy_fn: None,
+    set_fn: None,
+    // target is weak, the collector updates it after tracing.
+    trace_fn: None,
+    set_index_fn: None,
+};
+
+/// `ref.target` is the referenced object or `undefined` once it was collected.
+fn weak_ref_lookup(_: &VM, this: Ref<Obj>, key: Value) -> WaffleResult {
+    if key.is_cell()
+        && key.as_cell().is_string()
+        && key.as_cell().cast::<WaffleString>().str() == "target"
+    {
+        return WaffleResult::okay(this.cast::<WeakRef>().get());
+    }
+    WaffleResult::okay(Value::undefined())
+}
+
+pub static WEAK_MAP_VTBL: VTable = VTable {
+    name: "WeakMap",
+    element_size: 0,
+    instance_size: std::mem::size_of::<WeakMap>(),
+    parent: None,
+    lookup_fn: Some(weak_map_lookup),
+    index_fn: None,
+    calc_size_fn: None,
+    apply_fn: None,
+    destroy_fn: Some(destroy_weak_map),
+    set_fn: Some(weak_map_set),
+    // entries are ephemerons, the collector traces values of live keys itself.
+    trace_fn: None,
+    set_index_fn: None,
+};
+
+fn weak_map_lookup(_: &VM, this: Ref<Obj>, key: Value) -> WaffleResult {
+    if !key.is_cell() || key.is_empty() {
+        return WaffleResult::okay(Value::undefined());
+    }
+    let value = this.cast::<WeakMap>().get(key.as_cell());
+    WaffleResult::okay(value.unwrap_or(Value::undefined()))
+}
+
+/// `map[key] = value` adds an entry, storing `undefined` removes it. Keys must be objects.
+fn weak_map_set(_: &VM, this: Ref<Obj>, key: Value, value: Value) -> WaffleResult {
+    if !key.is_cell() || key.is_empty() {
+        return WaffleResult::error(Value::from(
+            WaffleString::new(
+                &mut get_vm().heap,
+                format!("WeakMap key '{}' is not an object", runtime::val_str(key)),
+            )
+            .cast(),
+        ));
+    }
+    let mut map = this.cast::<WeakMap>();
+    if value.is_undefined() {
+        map.remove(key.as_cell());
+    } else {
+        map.set(key.as_cell(), value);
+    }
+    WaffleResult::okay(Value::new_bool(true))
+}
+
+fn destroy_weak_map(map: Ref<Obj>) {
+    map.cast::<WeakMap>().drop_entries();
 }
 
 pub static STRING_VTBL: VTable = VTable {
+    name: "String",
     element_size: std::mem::size_of::<WaffleString>(),
     instance_size: 0,
     parent: None,
@@ -75,8 +267,15 @@ pub static STRING_VTBL: VTable = VTable {
     index_fn: None,
     calc_size_fn: None,
     apply_fn: None,
-    destroy_fn: None,
+    destroy_fn: Some(destroy_string),
     set_fn: None,
     trace_fn: None,
     set_index_fn: None,
 };
+
+fn destroy_string(string: Ref<Obj>) {
+    let mut string = string.cast::<WaffleString>();
+    unsafe {
+        std::ptr::drop_in_place(&mut string.string);
+    }
+}
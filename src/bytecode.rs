@@ -2,8 +2,10 @@ use crate::jit::*;
 use crate::object::*;
 use crate::value::Value;
 pub mod call_link_info;
+pub mod liveness;
 pub mod opcode_size;
 pub mod profile;
+pub mod property_cache;
 pub mod virtual_register;
 use derive_more::Display;
 pub use profile::*;
@@ -23,9 +25,21 @@ pub enum Ins {
     #[display(fmt = "storeid {}, const{}, {}", _0, _1, _2)]
     StoreId(VirtualRegister, u32, VirtualRegister),
     #[display(fmt = "loadu {}, u{}", _0, _1)]
+    /// Loads value of upvalue cell `_1` of the current closure.
     LoadU(VirtualRegister, u32),
     #[display(fmt = "storeu {}, u{}", _0, _1)]
+    /// Stores `_0` into upvalue cell `_1` of the current closure.
     StoreU(VirtualRegister, u32),
+    #[display(fmt = "loadu_cell {}, u{}", _0, _1)]
+    /// Loads upvalue cell `_1` itself, used to pass it on to nested closures.
+    LoadUCell(VirtualRegister, u32),
+    #[display(fmt = "new_cell {}, {}", _0, _1)]
+    /// Boxes `_1` into a new upvalue cell, used for locals captured by closures.
+    NewCell(VirtualRegister, VirtualRegister),
+    #[display(fmt = "load_cell {}, {}", _0, _1)]
+    LoadCell(VirtualRegister, VirtualRegister),
+    #[display(fmt = "store_cell {}, {}", _0, _1)]
+    StoreCell(VirtualRegister /* cell */, VirtualRegister),
     #[display(fmt = "load_global {}, const{}", _0, _1)]
     LoadGlobal(VirtualRegister, u32 /* id constant */),
     #[display(fmt = "store_global {}, const{}", _0, _1)]
@@ -114,8 +128,9 @@ pub enum Ins {
     Throw(VirtualRegister),
     #[display(fmt = "catch {}", _0)]
     Catch(VirtualRegister),
-    #[display(fmt = "closure {}, ->{}", _0, _1)]
-    Closure(VirtualRegister, u32),
+    #[display(fmt = "closure {}, {}, ->{}", _0, _1, _2)]
+    /// Replaces function in `_0` with its closure over `_2` upvalue cells starting at `_1`.
+    Closure(VirtualRegister, VirtualRegister, u32),
     #[display(fmt = "call {},{},{}, ->{}", _0, _1, _2, _3)]
     Call(
         VirtualRegister, /* dest */
@@ -132,6 +147,18 @@ pub enum Ins {
     #[display(fmt = "new_object {}", _0)]
     /// Initializes new empty object.
     NewObject(VirtualRegister),
+    #[display(fmt = "new_array {}, {}, ->{}", _0, _1, _2)]
+    /// Allocates array from `_2` values stored in consecutive registers starting at `_1`.
+    NewArray(VirtualRegister, VirtualRegister, u32),
+    #[display(fmt = "new_tuple {}, {}, ->{}", _0, _1, _2)]
+    /// Allocates tuple from `_2` values stored in consecutive registers starting at `_1`.
+    NewTuple(VirtualRegister, VirtualRegister, u32),
+    #[display(fmt = "slice {}, {}, {}..-{}", _0, _1, _2, _3)]
+    /// Copies elements of `_1` from index `_2` up to `length - _3` into new array.
+    Slice(VirtualRegister, VirtualRegister, u32, u32),
+    #[display(fmt = "inherit {}, {}", _0, _1)]
+    /// Sets prototype of `_0.prototype` to `_1.prototype`.
+    Inherit(VirtualRegister /* class */, VirtualRegister /* parent */),
     #[display(fmt = "return {}", _0)]
     Return(VirtualRegister),
 }
@@ -152,6 +179,7 @@ pub struct CodeBlock {
     pub metadata: Vec<OpcodeMetadata>,
 }
 pub static CB_VTBL: vtable::VTable = vtable::VTable {
+    name: "CodeBlock",
     element_size: 0,
     instance_size: std::mem::size_of::<CodeBlock>(),
     parent: None,
@@ -172,15 +200,49 @@ fn trace(cb: Ref<Obj>, f: &mut dyn FnMut(*const Ref<Obj>)) {
             f(c.as_cell_ref());
         }
     }
+    for metadata in cb.metadata.iter() {
+        for stub in metadata.call_link_info.stubs() {
+            if let Some(callee) = &stub.code_block {
+                f(callee as *const Ref<CodeBlock> as *const Ref<Obj>);
+            }
+        }
+    }
 }
 
 #[derive(Default)]
 pub struct JITData {
-    pub add_ics: HashMap<*const ArithProfile, mathic::MathIC<add_generator::AddGenerator>>,
-    pub sub_ics: HashMap<*const ArithProfile, mathic::MathIC<sub_generator::SubGenerator>>,
-    pub mul_ics: HashMap<*const ArithProfile, mathic::MathIC<mul_generator::MulGenerator>>,
     pub code_map: std::collections::HashMap<u32, *mut u8>,
+    /// Depends on the bytecode only, later compilations reuse the stack map of the first.
+    pub stack_map: stack_map::StackMap,
     pub executable_addr: usize,
+    /// Exits of JIT code by bytecode index, shared by all compilations like the stack map.
+    pub osr_exits: HashMap<u32, osr_exit::OSRExit>,
+    /// Machine code at `executable_addr`.
+    pub code: Option<JITCode>,
+}
+
+/// Machine code of one compilation with the inline caches and structures it uses. Code
+/// that is jettisoned or compiled again moves to `VM::jettisoned_code` and is freed once no
+/// frame of its code block runs JIT code.
+pub struct JITCode {
+    pub code_block: *const CodeBlock,
+    /// Owns the machine code.
+    pub memory: masm::linkbuffer::Memory,
+    pub add_ics: Vec<Box<mathic::MathIC<add_generator::AddGenerator>>>,
+    pub sub_ics: Vec<Box<mathic::MathIC<sub_generator::SubGenerator>>>,
+    pub mul_ics: Vec<Box<mathic::MathIC<mul_generator::MulGenerator>>>,
+    /// Structures checked by the code.
+    pub structures: Vec<*const crate::table::Structure>,
+    /// Call and property cache stubs the code was linked to before it was replaced.
+    pub stubs: Vec<thunk_generator::StubCode>,
+}
+
+impl Drop for JITCode {
+    fn drop(&mut self) {
+        for structure in self.structures.drain(..) {
+            crate::table::Structure::release(structure);
+        }
+    }
 }
 
 impl CodeBlock {
@@ -215,49 +277,50 @@ impl CodeBlock {
         self.jit_data.lock()
     }
 
-    pub fn metadata(&self, op: u32) -> &OpcodeMetadata {
-        &self.metadata[op as usize]
+    /// Throw away JIT code after one of its assumptions broke. Frames running the code
+    /// continue until they reach an OSR exit, new calls are interpreted until the code block
+    /// gets hot again.
+    pub fn jettison(&mut self) {
+        log!("Jettison JIT code of code block at {:p}", self as *const Self);
+        if self.jit_data().executable_addr == 0 {
+            return;
+        }
+        self.retire_code();
+        self.exc_counter = 0;
     }
 
-    pub fn add_jit_addic(
-        &self,
-        profile: *const ArithProfile,
-    ) -> &mut mathic::MathIC<add_generator::AddGenerator> {
-        let mut data = self.jit_data();
-        let mut ic = mathic::MathIC::<add_generator::AddGenerator>::new();
-        ic.arith_profile = Some(profile);
-        data.add_ics.insert(profile, ic);
-        data.add_ics
-            .get(&profile)
-            .and_then(|x| unsafe { Some(&mut *(x as *const _ as *mut _)) })
-            .unwrap()
-    }
-    pub fn add_jit_subic(
-        &self,
-        profile: *const ArithProfile,
-    ) -> &mut mathic::MathIC<sub_generator::SubGenerator> {
-        let mut data = self.jit_data();
-        let mut ic = mathic::MathIC::<sub_generator::SubGenerator>::new();
-        ic.arith_profile = Some(profile);
-        data.sub_ics.insert(profile, ic);
-        data.sub_ics
-            .get(&profile)
-            .and_then(|x| unsafe { Some(&mut *(x as *const _ as *mut _)) })
-            .unwrap()
+    /// Move the current JIT code to `VM::jettisoned_code`. Caches stop repatching its call
+    /// sites and hand the stubs they linked over to it, code compiled next links its own.
+    pub fn retire_code(&self) {
+        let vm = crate::get_vm();
+        let mut jit_data = self.jit_data();
+        jit_data.executable_addr = 0;
+        jit_data.code_map.clear();
+        let mut code = match jit_data.code.take() {
+            Some(code) => code,
+            None => return,
+        };
+        drop(jit_data);
+        for metadata in self.metadata.iter() {
+            let metadata =
+                unsafe { &mut *(metadata as *const OpcodeMetadata as *mut OpcodeMetadata) };
+            let info = &mut metadata.call_link_info;
+            info.call_location = 0;
+            code.stubs.extend(info.stub.take());
+            let cache = &mut metadata.property_cache;
+            cache.call_location = 0;
+            cache.needs_repatch = true;
+            code.stubs.extend(cache.stub.take());
+        }
+        // call sites linked to the code have to relink.
+        vm.call_link_epoch += 1;
+        vm.jettisoned_code.push(code);
     }
-    pub fn add_jit_mulic(
-        &self,
-        profile: *const ArithProfile,
-    ) -> &mut mathic::MathIC<mul_generator::MulGenerator> {
-        let mut data = self.jit_data();
-        let mut ic = mathic::MathIC::<mul_generator::MulGenerator>::new();
-        ic.arith_profile = Some(profile);
-        data.mul_ics.insert(profile, ic);
-        data.mul_ics
-            .get(&profile)
-            .and_then(|x| unsafe { Some(&mut *(x as *const _ as *mut _)) })
-            .unwrap()
+
+    pub fn metadata(&self, op: u32) -> &OpcodeMetadata {
+        &self.metadata[op as usize]
     }
+
     pub fn dump(&self, buffer: &mut dyn std::fmt::Write) -> std::fmt::Result {
         use crate::runtime::val_str;
         writeln!(buffer, "CodeBlock at {:p}", self)?;
@@ -304,12 +367,18 @@ impl CodeBlock {
 
 pub struct OpcodeMetadata {
     pub arith_profile: ArithProfile,
+    /// Used by `LoadId` and `StoreId`.
+    pub property_cache: property_cache::PropertyCache,
+    /// Used by `Call`.
+    pub call_link_info: call_link_info::CallLinkInfo,
 }
 
 impl OpcodeMetadata {
     pub fn new() -> Self {
         Self {
             arith_profile: ArithProfile::Binary(0),
+            property_cache: property_cache::PropertyCache::new(),
+            call_link_info: call_link_info::CallLinkInfo::new(),
         }
     }
 }
@@ -1 +1,118 @@
+//! Call inline caches of `Call` sites compiled by the baseline JIT. A site calls through a
+//! repatchable call that starts at the link thunk. The thunk links the site to the JIT
+//! entry of its callee and repatches the call to a stub that checks the callee and jumps
+//! straight into its code. Sites that see more than one callee get a stub with one check
+//! per callee, after `MAX_POLYMORPHIC_CALLEES` they call the generic path.
+//!
+//! Stubs are keyed on the code block of the callee (or its native function) so closures of
+//! one function share a stub. A stub is stale once a code block that already had JIT code
+//! is compiled again or jettisoned, `VM::call_link_epoch` changes then and stubs send their
+//! calls back to the link thunk, which unlinks the site before linking it again.
+use crate::bytecode::CodeBlock;
+use crate::interpreter::callframe::CallFrame;
+use crate::object::*;
+use crate::value::Value;
+use crate::WaffleResult;
 
+/// Number of callees a site links to before it goes megamorphic.
+pub const MAX_POLYMORPHIC_CALLEES: usize = 4;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CallLinkState {
+    /// Site did not call compiled code yet or was unlinked.
+    Unlinked,
+    Monomorphic,
+    Polymorphic,
+    /// Site saw more than `MAX_POLYMORPHIC_CALLEES` callees, always takes the generic path.
+    Megamorphic,
+}
+
+#[derive(Copy, Clone)]
+pub struct CallStub {
+    /// Address of the callee code block or native function.
+    pub target: usize,
+    /// Traced so `target` can't be reused by another code block.
+    pub code_block: Option<Ref<CodeBlock>>,
+    pub entry: extern "C" fn(&mut CallFrame) -> WaffleResult,
+}
+
+pub struct CallLinkInfo {
+    pub state: CallLinkState,
+    stubs: Vec<CallStub>,
+    epoch: u64,
+    pub misses: u32,
+    /// Address right after the repatchable call of the site, set when its code is linked.
+    pub call_location: usize,
+    /// Stub the call of the site currently points to.
+    pub stub: Option<crate::jit::thunk_generator::StubCode>,
+}
+
+impl CallLinkInfo {
+    pub fn new() -> Self {
+        Self {
+            state: CallLinkState::Unlinked,
+            stubs: vec![],
+            epoch: 0,
+            misses: 0,
+            call_location: 0,
+            stub: None,
+        }
+    }
+
+    pub fn stubs(&self) -> &[CallStub] {
+        &self.stubs
+    }
+
+    /// Code block or native function `callee` would run, `None` if it is not a function.
+    pub fn target_of(callee: Value) -> Option<usize> {
+        if !callee.is_cell() || callee.is_empty() || !callee.as_cell().is_function() {
+            return None;
+        }
+        let function = callee.as_cell().cast::<crate::function::Function>();
+        if function.native {
+            Some(function.native_code)
+        } else {
+            function.code_block.map(|cb| cb.raw() as usize)
+        }
+    }
+
+    /// `VM::call_link_epoch` the stubs were linked in.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Add `stub` after the site missed. Returns false once the site is megamorphic.
+    pub fn link(&mut self, stub: CallStub) -> bool {
+        self.misses += 1;
+        if self.epoch != crate::get_vm().call_link_epoch {
+            self.unlink();
+        }
+        if self.state == CallLinkState::Megamorphic {
+            return false;
+        }
+        if self.stubs.len() == MAX_POLYMORPHIC_CALLEES {
+            self.unlink();
+            self.state = CallLinkState::Megamorphic;
+            return false;
+        }
+        self.stubs.push(stub);
+        self.state = if self.stubs.len() == 1 {
+            CallLinkState::Monomorphic
+        } else {
+            CallLinkState::Polymorphic
+        };
+        true
+    }
+
+    /// Drop all stubs, a megamorphic site may link again afterwards.
+    pub fn unlink(&mut self) {
+        let heap = &mut crate::get_vm().heap;
+        for stub in self.stubs.drain(..) {
+            if let Some(cb) = stub.code_block {
+                heap.satb_barrier(Value::from(cb.cast()));
+            }
+        }
+        self.state = CallLinkState::Unlinked;
+        self.epoch = crate::get_vm().call_link_epoch;
+    }
+}
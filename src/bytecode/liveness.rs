@@ -0,0 +1,287 @@
+//! Backward dataflow over bytecode computing which locals hold live values before each
+//! instruction. JIT stack maps are built from it.
+use super::virtual_register::*;
+use super::*;
+
+/// Set of locals, one bit per local.
+#[derive(Clone, PartialEq, Eq, Default)]
+pub struct LocalSet {
+    words: Vec<u64>,
+}
+
+impl LocalSet {
+    pub fn new(locals: usize) -> Self {
+        Self {
+            words: vec![0; (locals + 63) / 64],
+        }
+    }
+    pub fn insert(&mut self, local: usize) {
+        if local / 64 >= self.words.len() {
+            self.words.resize(local / 64 + 1, 0);
+        }
+        self.words[local / 64] |= 1 << (local % 64);
+    }
+    pub fn remove(&mut self, local: usize) {
+        if let Some(word) = self.words.get_mut(local / 64) {
+            *word &= !(1 << (local % 64));
+        }
+    }
+    pub fn contains(&self, local: usize) -> bool {
+        self.words
+            .get(local / 64)
+            .map(|word| word & (1 << (local % 64)) != 0)
+            .unwrap_or(false)
+    }
+    pub fn union(&mut self, other: &Self) {
+        if other.words.len() > self.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+        for (x, y) in self.words.iter_mut().zip(other.words.iter()) {
+            *x |= *y;
+        }
+    }
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.words.len() * 64).filter(move |local| self.contains(*local))
+    }
+}
+
+fn range(first: VirtualRegister, count: u32, uses: &mut Vec<VirtualRegister>) {
+    if !first.is_local() {
+        return;
+    }
+    for i in 0..count as i32 {
+        uses.push(virtual_register_for_local(first.to_local() + i));
+    }
+}
+
+fn next(r: VirtualRegister) -> VirtualRegister {
+    if r.is_local() {
+        virtual_register_for_local(r.to_local() + 1)
+    } else {
+        r
+    }
+}
+
+/// Registers read and written by `ins`.
+pub fn uses_and_defs(ins: &Ins) -> (Vec<VirtualRegister>, Option<VirtualRegister>) {
+    use Ins::*;
+    let mut uses = vec![];
+    let def = match *ins {
+        Move(dst, src) | ToBoolean(dst, src) | Not(dst, src) | Neg(dst, src) => {
+            uses.push(src);
+            Some(dst)
+        }
+        NewCell(dst, src) | LoadCell(dst, src) | LoadId(dst, src, _) | Slice(dst, src, _, _) => {
+            uses.push(src);
+            Some(dst)
+        }
+        Load(dst, object, key) => {
+            uses.extend_from_slice(&[object, key]);
+            Some(dst)
+        }
+        Store(object, key, val) => {
+            uses.extend_from_slice(&[object, key, val]);
+            None
+        }
+        StoreId(object, _, val) => {
+            uses.extend_from_slice(&[object, val]);
+            None
+        }
+        StoreCell(cell, val) | Inherit(cell, val) => {
+            uses.extend_from_slice(&[cell, val]);
+            None
+        }
+        LoadU(dst, _) | LoadUCell(dst, _) | LoadGlobal(dst, _) | LoadThis(dst) | Catch(dst)
+        | NewObject(dst) => Some(dst),
+        StoreU(src, _) | StoreGlobal(src, _) | StoreThis(src) | Throw(src) | Return(src)
+        | JmpIfZero(src, _) | JmpIfNotZero(src, _) => {
+            uses.push(src);
+            None
+        }
+        Add(dst, x, y)
+        | Sub(dst, x, y)
+        | Mul(dst, x, y)
+        | Div(dst, x, y)
+        | Rem(dst, x, y)
+        | Mod(dst, x, y)
+        | LShift(dst, x, y)
+        | RShift(dst, x, y)
+        | URShift(dst, x, y)
+        | BitAnd(dst, x, y)
+        | BitOr(dst, x, y)
+        | BitXor(dst, x, y)
+        | Equal(dst, x, y)
+        | NotEqual(dst, x, y)
+        | Greater(dst, x, y)
+        | GreaterOrEqual(dst, x, y)
+        | Less(dst, x, y)
+        | LessOrEqual(dst, x, y) => {
+            uses.extend_from_slice(&[x, y]);
+            Some(dst)
+        }
+        JEq(x, y, _)
+        | JNEq(x, y, _)
+        | JLess(x, y, _)
+        | JLessEq(x, y, _)
+        | JGreater(x, y, _)
+        | JGreaterEq(x, y, _)
+        | JNGreater(x, y, _)
+        | JNGreaterEq(x, y, _)
+        | JNLessEq(x, y, _)
+        | JNLess(x, y, _) => {
+            uses.extend_from_slice(&[x, y]);
+            None
+        }
+        Closure(f, first, count) => {
+            uses.push(f);
+            range(first, count, &mut uses);
+            Some(f)
+        }
+        NewArray(dst, first, count) | NewTuple(dst, first, count) => {
+            range(first, count, &mut uses);
+            Some(dst)
+        }
+        // arguments are passed in registers right after the callee
+        Call(dst, this, callee, argc) => {
+            uses.extend_from_slice(&[this, callee]);
+            range(next(callee), argc, &mut uses);
+            Some(dst)
+        }
+        New(dst, callee, argc) => {
+            uses.push(callee);
+            range(next(callee), argc, &mut uses);
+            Some(dst)
+        }
+        Enter | Safepoint | LoopHint | Jmp(_) | Try(_) | TryEnd => None,
+    };
+    uses.retain(|r| r.is_local());
+    (uses, def.filter(|r| r.is_local()))
+}
+
+fn successors(code: &[Ins], pc: usize, handlers: &[usize], succ: &mut Vec<usize>) {
+    use Ins::*;
+    let target = |off: i32| (pc as i32 + off) as usize;
+    match code[pc] {
+        Jmp(off) => succ.push(target(off)),
+        JmpIfZero(_, off) | JmpIfNotZero(_, off) => {
+            succ.push(pc + 1);
+            succ.push(target(off));
+        }
+        JEq(_, _, off)
+        | JNEq(_, _, off)
+        | JLess(_, _, off)
+        | JLessEq(_, _, off)
+        | JGreater(_, _, off)
+        | JGreaterEq(_, _, off)
+        | JNGreater(_, _, off)
+        | JNGreaterEq(_, _, off)
+        | JNLessEq(_, _, off)
+        | JNLess(_, _, off) => {
+            succ.push(pc + 1);
+            succ.push(target(off));
+        }
+        Return(_) | Throw(_) => (),
+        _ => succ.push(pc + 1),
+    }
+    // any instruction inside of `try` may continue in the handler.
+    succ.extend_from_slice(handlers);
+    succ.retain(|x| *x < code.len());
+}
+
+/// Returns set of live locals before each instruction of `code`.
+pub fn compute_liveness(code: &[Ins], locals: usize) -> Vec<LocalSet> {
+    let mut handlers = vec![vec![]; code.len()];
+    for (pc, ins) in code.iter().enumerate() {
+        if let Ins::Try(size) = ins {
+            let handler = pc + *size as usize;
+            for i in pc + 1..handler.min(code.len()) {
+                handlers[i].push(handler);
+            }
+        }
+    }
+    let info = code.iter().map(uses_and_defs).collect::<Vec<_>>();
+    let mut live_in = vec![LocalSet::new(locals); code.len()];
+    let mut succ = vec![];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for pc in (0..code.len()).rev() {
+            succ.clear();
+            successors(code, pc, &handlers[pc], &mut succ);
+            let mut live = LocalSet::new(locals);
+            for s in succ.iter() {
+                live.union(&live_in[*s]);
+            }
+            let (uses, def) = &info[pc];
+            if let Some(def) = def {
+                live.remove(def.to_local() as usize);
+            }
+            for r in uses.iter() {
+                live.insert(r.to_local() as usize);
+            }
+            if live != live_in[pc] {
+                live_in[pc] = live;
+                changed = true;
+            }
+        }
+    }
+    live_in
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn live(code: &[Ins], locals: usize) -> Vec<Vec<usize>> {
+        compute_liveness(code, locals)
+            .iter()
+            .map(|set| set.iter().collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_straight_line() {
+        let r = virtual_register_for_local;
+        let code = [
+            Ins::Move(r(1), r(0)),
+            Ins::Add(r(2), r(1), r(1)),
+            Ins::Return(r(2)),
+        ];
+        assert_eq!(live(&code, 3), vec![vec![0], vec![1], vec![2]]);
+    }
+
+    #[test]
+    fn test_loop_keeps_values_alive() {
+        let r = virtual_register_for_local;
+        let c = VirtualRegister::new_constant_index(0);
+        let code = [
+            Ins::Move(r(1), c),
+            Ins::JmpIfZero(r(0), 3),
+            Ins::Add(r(1), r(1), r(0)),
+            Ins::Jmp(-2),
+            Ins::Return(r(1)),
+        ];
+        let live = live(&code, 2);
+        assert_eq!(live[0], vec![0]);
+        assert_eq!(live[1], vec![0, 1]);
+        assert_eq!(live[3], vec![0, 1]);
+    }
+
+    #[test]
+    fn test_handler_uses_are_live_in_try() {
+        let r = virtual_register_for_local;
+        let c = VirtualRegister::new_constant_index(0);
+        let code = [
+            Ins::Move(r(1), r(0)),
+            Ins::Try(3),
+            Ins::Move(r(0), c),
+            Ins::Return(r(0)),
+            Ins::Catch(r(2)),
+            Ins::Return(r(1)),
+        ];
+        let live = live(&code, 3);
+        assert_eq!(live[2], vec![1]);
+        assert_eq!(live[3], vec![0, 1]);
+        assert_eq!(live[4], vec![1]);
+    }
+}
@@ -0,0 +1,176 @@
+//! Inline caches of `LoadId` and `StoreId` sites. A cache lives in the `OpcodeMetadata` of
+//! its instruction so the interpreter fills it and the baseline JIT reuses what it learned,
+//! monomorphic sites get their structure check and slot access compiled inline.
+//!
+//! JIT sites call the runtime through a repatchable call. Once the cache changes the call is
+//! repatched to a stub with one structure check per entry that accesses the slot without
+//! leaving machine code, misses go on to the runtime which records the new structure.
+//!
+//! Entries are keyed on the `Structure` of the receiver, which they keep alive, and remember
+//! the slot of the property. Only own properties of objects outside of dictionary mode are
+//! cached, other accesses take the generic `lookup_fn`/`set_fn` path.
+use crate::object::*;
+use crate::table::Structure;
+use crate::value::Value;
+
+/// Number of structures a site remembers before it stops caching.
+pub const MAX_POLYMORPHIC_ENTRIES: usize = 4;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CacheState {
+    /// Site was not executed with an object receiver yet.
+    Uninitialized,
+    Monomorphic,
+    Polymorphic,
+    /// Site saw more than `MAX_POLYMORPHIC_ENTRIES` structures, always takes the generic path.
+    Megamorphic,
+}
+
+#[derive(Copy, Clone)]
+pub struct PropertyCacheEntry {
+    pub structure: *const Structure,
+    pub slot: u32,
+}
+
+pub struct PropertyCache {
+    pub state: CacheState,
+    entries: [PropertyCacheEntry; MAX_POLYMORPHIC_ENTRIES],
+    count: usize,
+    pub hits: u32,
+    pub misses: u32,
+    /// Address right after the repatchable call of the JIT site, 0 for interpreted code.
+    pub call_location: usize,
+    /// The JIT site does not call the stub for the current entries yet.
+    pub needs_repatch: bool,
+    /// Stub the call of the JIT site points to.
+    pub stub: Option<crate::jit::thunk_generator::StubCode>,
+}
+
+impl PropertyCache {
+    pub fn new() -> Self {
+        Self {
+            state: CacheState::Uninitialized,
+            entries: [PropertyCacheEntry {
+                structure: std::ptr::null(),
+                slot: 0,
+            }; MAX_POLYMORPHIC_ENTRIES],
+            count: 0,
+            hits: 0,
+            misses: 0,
+            call_location: 0,
+            needs_repatch: false,
+            stub: None,
+        }
+    }
+
+    /// Cached structures, most recently added last.
+    pub fn entries(&self) -> &[PropertyCacheEntry] {
+        &self.entries[..self.count]
+    }
+
+    fn find(&self, obj: Ref<RegularObj>) -> Option<u32> {
+        let structure = obj.table.structure()? as *const Structure;
+        self.entries()
+            .iter()
+            .find(|entry| entry.structure == structure)
+            .map(|entry| entry.slot)
+    }
+
+    /// Value of the cached property of `object` if its structure is cached.
+    pub fn get(&mut self, object: Value) -> Option<Value> {
+        let obj = receiver(object)?;
+        let slot = self.find(obj)?;
+        self.hits += 1;
+        Some(*obj.table.slot(slot))
+    }
+
+    /// Overwrite the cached property of `object` if its structure is cached. Stores that add
+    /// properties change the structure and always miss.
+    pub fn put(&mut self, object: Value, value: Value) -> bool {
+        let mut obj = match receiver(object) {
+            Some(obj) => obj,
+            None => return false,
+        };
+        let slot = match self.find(obj) {
+            Some(slot) => slot,
+            None => return false,
+        };
+        let heap = &mut crate::get_vm().heap;
+        let old = obj.table.slot_mut(slot);
+        heap.satb_barrier(*old);
+        *old = value;
+        heap.write_barrier(obj.cast(), value);
+        self.hits += 1;
+        true
+    }
+
+    /// Remember the structure of `object` after the generic path accessed property `key`.
+    pub fn record(&mut self, object: Value, key: &str) {
+        self.misses += 1;
+        let state = self.state;
+        let count = self.count;
+        self.record_structure(object, key);
+        if self.state != state || self.count != count {
+            self.needs_repatch = true;
+        }
+    }
+
+    fn record_structure(&mut self, object: Value, key: &str) {
+        if self.state == CacheState::Megamorphic {
+            return;
+        }
+        let obj = match receiver(object) {
+            Some(obj) => obj,
+            None => return,
+        };
+        let structure = match obj.table.structure() {
+            Some(structure) => structure,
+            None => return,
+        };
+        let slot = match structure.slot(key) {
+            Some(slot) => slot,
+            None => return,
+        };
+        if self.find(obj).is_some() {
+            return;
+        }
+        if self.count == MAX_POLYMORPHIC_ENTRIES {
+            self.state = CacheState::Megamorphic;
+            self.clear();
+            return;
+        }
+        // a freed structure could be reallocated at the same address and match the entry.
+        Structure::retain(structure);
+        self.entries[self.count] = PropertyCacheEntry { structure, slot };
+        self.count += 1;
+        self.state = if self.count == 1 {
+            CacheState::Monomorphic
+        } else {
+            CacheState::Polymorphic
+        };
+    }
+
+    fn clear(&mut self) {
+        for entry in self.entries() {
+            Structure::release(entry.structure);
+        }
+        self.count = 0;
+    }
+}
+
+impl Drop for PropertyCache {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+fn receiver(object: Value) -> Option<Ref<RegularObj>> {
+    if object.is_cell()
+        && !object.is_empty()
+        && object.as_cell().vtable as *const _ == &OBJECT_VTBL as *const _
+    {
+        Some(object.as_cell().cast())
+    } else {
+        None
+    }
+}
@@ -1,3 +1,4 @@
+pub mod captures;
 pub mod register_id;
 use crate::bytecode::virtual_register::*;
 use crate::bytecode::*;
@@ -28,6 +29,8 @@ impl Scope {
 pub struct ByteCompiler {
     pub parent: Option<std::ptr::NonNull<Self>>,
     pub used_upvars: indexmap::IndexMap<String, i32>,
+    /// Names of locals captured by nested functions, such locals live in upvalue cells.
+    pub captured: HashSet<String>,
     label_true: u32,
     label_false: u32,
     label_check: u32,
@@ -75,6 +78,18 @@ impl ByteCompiler {
         r
     }
 
+    /// Declare local `name` initialized with `val`, boxing it into a cell if closures capture it.
+    pub fn define_local(&mut self, name: impl AsRef<str>, val: VirtualRegister) -> VirtualRegister {
+        let boxed = self.captured.contains(name.as_ref());
+        let r = self.new_local(name);
+        if boxed {
+            self.code.push(Ins::NewCell(r, val));
+        } else {
+            self.code.push(Ins::Move(r, val));
+        }
+        r
+    }
+
     pub fn get_local(&mut self, name: impl AsRef<str>) -> Option<VirtualRegister> {
         self.scope.try_get(name.as_ref())
     }
@@ -235,14 +250,7 @@ impl ByteCompiler {
             }
         }*/
         // simple peephole opt
-        self.code.retain(|ins| {
-            if let Ins::Move(x, y) = ins {
-                if x == y {
-                    return false;
-                }
-            }
-            true
-        });
+        remove_self_moves(&mut self.code);
         let mut cb = CodeBlock::new();
         cb.constants = constants;
         cb.instructions = self.code.clone();
@@ -250,8 +258,58 @@ impl ByteCompiler {
         for _ in 0..cb.instructions.len() {
             cb.metadata.push(OpcodeMetadata::new());
         }
-        vm.allocate(cb)
+        let cb = vm.allocate(cb);
+        // constants may be young while code block itself is never collected.
+        vm.heap.remember(cb.cast());
+        cb
+    }
+}
+
+fn is_self_move(ins: &Ins) -> bool {
+    if let Ins::Move(x, y) = ins {
+        x == y
+    } else {
+        false
+    }
+}
+
+/// Drop `move x, x`, offsets of jumps over removed instructions are fixed up.
+fn remove_self_moves(code: &mut Vec<Ins>) {
+    // index every instruction gets, removed ones get the index of the next kept instruction.
+    let mut new_ix = Vec::with_capacity(code.len() + 1);
+    let mut kept = 0i32;
+    for ins in code.iter() {
+        new_ix.push(kept);
+        if !is_self_move(ins) {
+            kept += 1;
+        }
     }
+    new_ix.push(kept);
+    let fix = |at: usize, rel: i32| new_ix[(at as i32 + rel) as usize] - new_ix[at];
+    let mut out = Vec::with_capacity(kept as usize);
+    for (i, ins) in code.iter().enumerate() {
+        if is_self_move(ins) {
+            continue;
+        }
+        out.push(match *ins {
+            Ins::Jmp(rel) => Ins::Jmp(fix(i, rel)),
+            Ins::JmpIfZero(r, rel) => Ins::JmpIfZero(r, fix(i, rel)),
+            Ins::JmpIfNotZero(r, rel) => Ins::JmpIfNotZero(r, fix(i, rel)),
+            Ins::JEq(x, y, rel) => Ins::JEq(x, y, fix(i, rel)),
+            Ins::JNEq(x, y, rel) => Ins::JNEq(x, y, fix(i, rel)),
+            Ins::JLess(x, y, rel) => Ins::JLess(x, y, fix(i, rel)),
+            Ins::JLessEq(x, y, rel) => Ins::JLessEq(x, y, fix(i, rel)),
+            Ins::JGreater(x, y, rel) => Ins::JGreater(x, y, fix(i, rel)),
+            Ins::JGreaterEq(x, y, rel) => Ins::JGreaterEq(x, y, fix(i, rel)),
+            Ins::JNLess(x, y, rel) => Ins::JNLess(x, y, fix(i, rel)),
+            Ins::JNLessEq(x, y, rel) => Ins::JNLessEq(x, y, fix(i, rel)),
+            Ins::JNGreater(x, y, rel) => Ins::JNGreater(x, y, fix(i, rel)),
+            Ins::JNGreaterEq(x, y, rel) => Ins::JNGreaterEq(x, y, fix(i, rel)),
+            Ins::Try(rel) => Ins::Try(fix(i, rel as i32) as u32),
+            ins => ins,
+        });
+    }
+    *code = out;
 }
 use crate::frontend;
 use frontend::ast::*;
@@ -269,12 +327,26 @@ pub enum Access {
 use std::cell::RefCell;
 use std::rc::Rc;
 
+/// Loop that is being compiled, `break` and `continue` jumps are patched when loop body is done.
+pub struct LoopInfo {
+    pub label: Option<String>,
+    /// Number of `try` blocks around the loop, jumps out of the loop leave inner ones.
+    pub try_depth: usize,
+    pub breaks: Vec<Box<dyn FnOnce(&mut ByteCompiler)>>,
+    pub continues: Vec<Box<dyn FnOnce(&mut ByteCompiler)>>,
+}
+
 pub struct Context {
     pub parent: Option<NonNull<Self>>,
     pub builder: ByteCompiler,
     pub module: Ref<Module>,
     pub fmap: Rc<RefCell<HashMap<String, VirtualRegister>>>,
     pub functions: Rc<RefCell<Vec<(Ref<CodeBlock>, VirtualRegister, String)>>>,
+    pub loops: Vec<LoopInfo>,
+    /// Label of the loop that is compiled next.
+    pub label: Option<String>,
+    /// Number of `try` blocks around the instruction that is compiled.
+    pub try_depth: usize,
 }
 
 impl Context {
@@ -289,6 +361,9 @@ impl Context {
             module,
             functions: funcs,
             builder: ByteCompiler::new(),
+            loops: vec![],
+            label: None,
+            try_depth: 0,
         }
     }
     pub fn global(&self, name: &str) -> Option<VirtualRegister> {
@@ -300,6 +375,52 @@ impl Context {
         self.builder.pop_scope();
         res
     }
+    fn enter_loop(&mut self) {
+        let label = self.label.take();
+        self.loops.push(LoopInfo {
+            label,
+            try_depth: self.try_depth,
+            breaks: vec![],
+            continues: vec![],
+        });
+    }
+
+    /// Patch `continue` jumps of the innermost loop to the current position.
+    fn patch_continues(&mut self) {
+        let continues = std::mem::replace(&mut self.loops.last_mut().unwrap().continues, vec![]);
+        for j in continues {
+            j(&mut self.builder);
+        }
+    }
+
+    fn leave_loop(&mut self) {
+        let info = self.loops.pop().unwrap();
+        for j in info.breaks {
+            j(&mut self.builder);
+        }
+        let undef = self.builder.new_const(Value::undefined());
+        self.builder
+            .register_push(VirtualRegister::new_constant_index(undef as _));
+    }
+
+    fn find_loop(&mut self, label: &Option<String>, pos: Position) -> Result<usize, MsgWithPos> {
+        let ix = match label {
+            None => self.loops.len().checked_sub(1),
+            Some(name) => self
+                .loops
+                .iter()
+                .rposition(|l| l.label.as_ref() == Some(name)),
+        };
+        match (ix, label) {
+            (Some(ix), _) => Ok(ix),
+            (None, Some(name)) if !self.loops.is_empty() => Err(MsgWithPos::new(
+                pos,
+                Msg::Custom(format!("use of undeclared label '{}'", name)),
+            )),
+            _ => Err(MsgWithPos::new(pos, Msg::OutsideLoop)),
+        }
+    }
+
     fn access_env(&mut self, name: &str) -> Option<Access> {
         unsafe {
             let mut current = self.parent;
@@ -340,6 +461,7 @@ impl Context {
             Rc::new(RefCell::new(Default::default())),
         );
         ctx.builder.new_const(Value::undefined());
+        ctx.builder.captured = captures::captured_names(e);
         ctx.parent = Some(std::ptr::NonNull::new(self as *mut _).unwrap());
         let mut i = 0;
         for p in params.iter() {
@@ -375,18 +497,20 @@ impl Context {
             if self.builder.is_temp(dst) {
                 self.builder.protect(dst);
             }
-            let mut dest = vec![];
-            for _ in 0..ctx.builder.used_upvars.len() {
-                dest.push(self.builder.register_new());
+            let cells = self.builder.allocate_regs(ctx.builder.used_upvars.len());
+            for r in cells.iter() {
+                self.builder.protect(*r);
             }
             for (i, (var, _)) in ctx.builder.used_upvars.iter().enumerate() {
-                self.ident(var);
-                let reg = self.builder.register_pop(false);
-                if reg != dest[i] {
-                    self.builder.code.push(Ins::Move(dest[i], reg));
-                }
+                self.capture(var, cells[i]);
+            }
+            self.builder.code.push(Ins::Move(dst, c));
+            self.builder
+                .code
+                .push(Ins::Closure(dst, cells[0], cells.len() as _));
+            for r in cells {
+                self.builder.unprotect(r);
             }
-            self.builder.code.push(Ins::Closure(dst, dest.len() as _));
             if self.builder.is_temp(dst) {
                 self.builder.unprotect(dst);
             }
@@ -401,12 +525,25 @@ impl Context {
             cb,
             vname.as_ref().unwrap_or(&anon),
         );
+        crate::get_vm()
+            .heap
+            .satb_barrier(cb.constants[c2.to_constant_index() as usize]);
         cb.constants[c2.to_constant_index() as usize] = Value::from(f.cast());
+        crate::get_vm()
+            .heap
+            .write_barrier(cb.cast(), Value::from(f.cast()));
         self.builder.constants[c.to_constant_index() as usize] = Value::from(f.cast());
         if let Some(name) = vname.as_ref() {
-            self.module
+            if let Some(old) = self
+                .module
                 .scope
-                .insert(name.to_owned(), Value::from(f.cast()));
+                .insert(name.to_owned(), Value::from(f.cast()))
+            {
+                crate::get_vm().heap.satb_barrier(old);
+            }
+            crate::get_vm()
+                .heap
+                .write_barrier(self.module.cast(), Value::from(f.cast()));
         }
         f.module = Some(self.module);
         let mut b = String::new();
@@ -435,17 +572,266 @@ impl Context {
                         Msg::Custom(format!("argument '{}' already defined", name)),
                     ));
                 }
-                let r = self.builder.new_local(name);
-                let arg = VirtualRegister::new_argument(i);
-                self.builder.code.push(Ins::Move(r, arg));
+                self.builder
+                    .define_local(name, VirtualRegister::new_argument(i));
                 Ok(())
             }
-            _ => todo!("NYI"),
+            Arg::Record(names) => {
+                let fields = names.iter().map(|name| (name.clone(), None)).collect();
+                self.compile_arg_pattern(PatternDecl::Record(fields), i, p)
+            }
+            Arg::Array(names, rest) => {
+                let mut pats: Vec<Box<Pattern>> = names
+                    .iter()
+                    .map(|name| {
+                        Box::new(Pattern {
+                            decl: PatternDecl::Ident(name.clone()),
+                            pos: p,
+                        })
+                    })
+                    .collect();
+                if let Some(rest) = rest {
+                    pats.push(Box::new(Pattern {
+                        decl: PatternDecl::Rest(Some(rest.clone())),
+                        pos: p,
+                    }));
+                }
+                self.compile_arg_pattern(PatternDecl::Array(pats), i, p)
+            }
+        }
+    }
+
+    fn compile_arg_pattern(
+        &mut self,
+        decl: PatternDecl,
+        i: i32,
+        p: Position,
+    ) -> Result<(), MsgWithPos> {
+        let pat = Pattern { decl, pos: p };
+        let mut names = vec![];
+        pattern_names(&pat, &mut names);
+        for (ix, name) in names.iter().enumerate() {
+            if self.builder.get_local(name).is_some() || names[..ix].contains(name) {
+                return Err(MsgWithPos::new(
+                    p,
+                    Msg::Custom(format!("argument '{}' already defined", name)),
+                ));
+            }
         }
+        self.compile_destructure(&pat, VirtualRegister::new_argument(i))
+    }
+
+    /// Emit a test of `v` against `pat`, binding pattern variables as locals in the current scope.
+    /// Every emitted jump to the next match arm is pushed to `fails` and patched by the caller.
+    fn compile_pattern(
+        &mut self,
+        pat: &Pattern,
+        v: VirtualRegister,
+        fails: &mut Vec<Box<dyn FnOnce(&mut ByteCompiler)>>,
+    ) -> Result<(), MsgWithPos> {
+        match &pat.decl {
+            PatternDecl::Pass | PatternDecl::Rest(_) => Ok(()),
+            PatternDecl::Ident(name) => {
+                self.builder.define_local(name, v);
+                Ok(())
+            }
+            PatternDecl::ConstInt(i) => {
+                let i = *i;
+                let c = if i as i32 as i64 == i {
+                    self.builder.new_const(Value::new_int(i as i32))
+                } else {
+                    self.builder.new_const(Value::new_double(i as f64))
+                };
+                self.pattern_test(
+                    fails,
+                    Ins::Equal,
+                    v,
+                    VirtualRegister::new_constant_index(c as _),
+                );
+                Ok(())
+            }
+            PatternDecl::ConstFloat(x) => {
+                let c = self.builder.new_const(Value::new_double(*x));
+                self.pattern_test(
+                    fails,
+                    Ins::Equal,
+                    v,
+                    VirtualRegister::new_constant_index(c as _),
+                );
+                Ok(())
+            }
+            PatternDecl::ConstChar(c) => {
+                let c = self.builder.new_string(c.to_string());
+                self.pattern_test(
+                    fails,
+                    Ins::Equal,
+                    v,
+                    VirtualRegister::new_constant_index(c as _),
+                );
+                Ok(())
+            }
+            PatternDecl::ConstStr(s) => {
+                let c = self.builder.new_string(s);
+                self.pattern_test(
+                    fails,
+                    Ins::Equal,
+                    v,
+                    VirtualRegister::new_constant_index(c as _),
+                );
+                Ok(())
+            }
+            PatternDecl::EmptyList => {
+                let c = self.builder.new_const(Value::null());
+                self.pattern_test(
+                    fails,
+                    Ins::Equal,
+                    v,
+                    VirtualRegister::new_constant_index(c as _),
+                );
+                Ok(())
+            }
+            // cons cells are plain objects with `head` and `tail` fields, list ends with `nil`.
+            PatternDecl::Cons(head, tail) => {
+                let undef = VirtualRegister::new_constant_index(
+                    self.builder.new_const(Value::undefined()) as _,
+                );
+                let t = self.builder.register_new();
+                let key = self.builder.new_string("tail");
+                self.builder.code.push(Ins::LoadId(t, v, key));
+                self.pattern_test(fails, Ins::NotEqual, t, undef);
+                let h = self.builder.register_new();
+                let key = self.builder.new_string("head");
+                self.builder.code.push(Ins::LoadId(h, v, key));
+                self.compile_pattern(head, h, fails)?;
+                self.builder.unprotect(h);
+                self.compile_pattern(tail, t, fails)?;
+                self.builder.unprotect(t);
+                Ok(())
+            }
+            PatternDecl::Tuple(pats) | PatternDecl::Array(pats) => {
+                let is_rest = |p: &Box<Pattern>| match p.decl {
+                    PatternDecl::Rest(_) => true,
+                    _ => false,
+                };
+                let rest = pats.iter().position(is_rest).unwrap_or(pats.len());
+                if let Some(p) = pats.iter().skip(rest + 1).find(|p| is_rest(p)) {
+                    return Err(MsgWithPos::new(
+                        p.pos,
+                        Msg::Custom("only one rest pattern is allowed".to_owned()),
+                    ));
+                }
+                let fixed = if rest == pats.len() {
+                    pats.len()
+                } else {
+                    pats.len() - 1
+                };
+                let len = self.builder.register_new();
+                let key = self.builder.new_string("length");
+                self.builder.code.push(Ins::LoadId(len, v, key));
+                let n = VirtualRegister::new_constant_index(
+                    self.builder.new_const(Value::new_int(fixed as _)) as _,
+                );
+                if rest == pats.len() {
+                    self.pattern_test(fails, Ins::Equal, len, n);
+                } else {
+                    self.pattern_test(fails, Ins::GreaterOrEqual, len, n);
+                }
+                for (i, p) in pats.iter().enumerate() {
+                    if i == rest {
+                        if let PatternDecl::Rest(Some(name)) = &p.decl {
+                            let r = self.builder.register_new();
+                            self.builder.code.push(Ins::Slice(
+                                r,
+                                v,
+                                rest as _,
+                                (pats.len() - rest - 1) as _,
+                            ));
+                            self.builder.define_local(name, r);
+                            self.builder.unprotect(r);
+                        }
+                        continue;
+                    }
+                    let elem = self.builder.register_new();
+                    if i < rest {
+                        let ix = self.builder.new_const(Value::new_int(i as _));
+                        self.builder.code.push(Ins::LoadId(elem, v, ix));
+                    } else {
+                        // elements after `..` are indexed from the end.
+                        let off = VirtualRegister::new_constant_index(
+                            self.builder.new_const(Value::new_int((pats.len() - i) as _)) as _,
+                        );
+                        self.builder.code.push(Ins::Sub(elem, len, off));
+                        self.builder.code.push(Ins::Load(elem, v, elem));
+                    }
+                    self.compile_pattern(p, elem, fails)?;
+                    self.builder.unprotect(elem);
+                }
+                self.builder.unprotect(len);
+                Ok(())
+            }
+            PatternDecl::Record(fields) => {
+                let undef = VirtualRegister::new_constant_index(
+                    self.builder.new_const(Value::undefined()) as _,
+                );
+                for (name, p) in fields.iter() {
+                    let field = self.builder.register_new();
+                    let key = self.builder.new_string(name);
+                    self.builder.code.push(Ins::LoadId(field, v, key));
+                    self.pattern_test(fails, Ins::NotEqual, field, undef);
+                    if let Some(p) = p {
+                        self.compile_pattern(p, field, fails)?;
+                    } else {
+                        self.builder.define_local(name, field);
+                    }
+                    self.builder.unprotect(field);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Bind pattern variables of `pat` to parts of `v`, throwing if the value has another shape.
+    fn compile_destructure(&mut self, pat: &Pattern, v: VirtualRegister) -> Result<(), MsgWithPos> {
+        let mut fails: Vec<Box<dyn FnOnce(&mut ByteCompiler)>> = vec![];
+        self.compile_pattern(pat, v, &mut fails)?;
+        if fails.is_empty() {
+            return Ok(());
+        }
+        let jend = self.builder.jmp();
+        for fail in fails {
+            fail(&mut self.builder);
+        }
+        let msg = self
+            .builder
+            .new_string(format!("value does not match pattern at {}", pat.pos));
+        self.builder
+            .code
+            .push(Ins::Throw(VirtualRegister::new_constant_index(msg as _)));
+        jend(&mut self.builder);
+        Ok(())
+    }
+
+    fn pattern_test(
+        &mut self,
+        fails: &mut Vec<Box<dyn FnOnce(&mut ByteCompiler)>>,
+        op: fn(VirtualRegister, VirtualRegister, VirtualRegister) -> Ins,
+        lhs: VirtualRegister,
+        rhs: VirtualRegister,
+    ) {
+        let dst = self.builder.register_new();
+        self.builder.code.push(op(dst, lhs, rhs));
+        fails.push(Box::new(self.builder.cjmp(true, dst)));
+        self.builder.unprotect(dst);
     }
     fn ident(&mut self, name: &str) {
         if let Some(loc) = self.builder.get_local(name) {
-            self.builder.register_push(loc);
+            if self.builder.captured.contains(name) {
+                let dst = self.builder.register_new();
+                self.builder.code.push(Ins::LoadCell(dst, loc));
+                self.builder.register_push(dst);
+            } else {
+                self.builder.register_push(loc);
+            }
         } else {
             if let Some(Access::Env(x)) = self.access_env(name) {
                 let dst = self.builder.register_new();
@@ -464,11 +850,34 @@ impl Context {
         }
     }
 
+    /// Move upvalue cell of `name` to `dst`, used when creating closures.
+    fn capture(&mut self, name: &str, dst: VirtualRegister) {
+        if let Some(r) = self.builder.get_local(name) {
+            debug_assert!(self.builder.captured.contains(name));
+            self.builder.code.push(Ins::Move(dst, r));
+        } else if let Some(Access::Env(x)) = self.access_env(name) {
+            self.builder.code.push(Ins::LoadUCell(dst, x as _));
+        } else {
+            unreachable!()
+        }
+    }
+
     pub fn access_get(&mut self, acc: Access) -> Result<(), MsgWithPos> {
         match acc {
-            Access::Env { .. } => unreachable!(),
-            Access::Stack(_, r) => {
-                self.builder.register_push(r);
+            Access::Env(x) => {
+                let dst = self.builder.register_new();
+                self.builder.code.push(Ins::LoadU(dst, x as u32));
+                self.builder.register_push(dst);
+                Ok(())
+            }
+            Access::Stack(name, r) => {
+                if self.builder.captured.contains(&name) {
+                    let dst = self.builder.register_new();
+                    self.builder.code.push(Ins::LoadCell(dst, r));
+                    self.builder.register_push(dst);
+                } else {
+                    self.builder.register_push(r);
+                }
                 Ok(())
             }
             Access::Global(x, n, name) => {
@@ -528,20 +937,26 @@ impl Context {
     }
     fn access_set(&mut self, acc: Access) -> Result<(), MsgWithPos> {
         match acc {
-            Access::Stack(_, x) => {
+            Access::Stack(name, x) => {
                 let v = self.builder.register_pop(false);
-                self.builder.code.push(Ins::Move(x, v));
-                Ok(())
-            }
-            Access::Global(_x, n, name) => {
-                if !n {
-                    panic!();
+                if self.builder.captured.contains(&name) {
+                    self.builder.code.push(Ins::StoreCell(x, v));
                 } else {
-                    let key = self.builder.new_string(name);
-                    let val = self.builder.register_pop(false);
-                    self.builder.code.push(Ins::StoreGlobal(val, key));
-                    Ok(())
+                    self.builder.code.push(Ins::Move(x, v));
                 }
+                Ok(())
+            }
+            Access::Env(x) => {
+                let v = self.builder.register_pop(false);
+                self.builder.code.push(Ins::StoreU(v, x as u32));
+                Ok(())
+            }
+            // unknown names are stored by name too, runtime reports undeclared globals.
+            Access::Global(_x, _, name) => {
+                let key = self.builder.new_string(name);
+                let val = self.builder.register_pop(false);
+                self.builder.code.push(Ins::StoreGlobal(val, key));
+                Ok(())
             }
             Access::This => {
                 let val = self.builder.register_pop(false);
@@ -558,6 +973,20 @@ impl Context {
                 self.builder.unprotect(val);
                 Ok(())
             }
+            Access::Array(object, ix) => {
+                let val = self.builder.register_pop(true);
+                self.compile(&ix)?;
+                let key = self.builder.register_pop(true);
+                self.compile(&object)?;
+                let obj = self.builder.register_pop(false);
+                self.builder.code.push(Ins::Store(obj, key, val));
+                for r in [key, val].iter() {
+                    if r.is_local() && self.builder.is_temp(*r) {
+                        self.builder.unprotect(*r);
+                    }
+                }
+                Ok(())
+            }
             _ => unreachable!(),
         }
     }
@@ -688,18 +1117,37 @@ impl Context {
                 Ok(())
             }
             ExprKind::Var(_, name, init) => {
-                let dst = self.builder.new_local(name);
-                if let Some(i) = init {
+                let r = if let Some(i) = init {
                     self.compile(&i)?;
-                    let r = self.builder.register_pop(false);
-                    self.builder.code.push(Ins::Move(dst, r));
+                    self.builder.register_pop(true)
                 } else {
                     let c = self.builder.new_const(Value::undefined());
-                    self.builder
-                        .code
-                        .push(Ins::Move(dst, VirtualRegister::new_constant_index(c as _)));
+                    VirtualRegister::new_constant_index(c as _)
+                };
+                let dst = self.builder.define_local(name, r);
+                if r.is_local() && self.builder.is_temp(r) {
+                    self.builder.unprotect(r);
+                }
+                if self.builder.captured.contains(name) {
+                    self.builder.register_push(r);
+                } else {
+                    self.builder.register_push(dst);
                 }
-                self.builder.register_push(dst);
+                Ok(())
+            }
+            ExprKind::BinOp(lhs, op, rhs) if op == "&&" || op == "||" => {
+                // short-circuit: the result is the last evaluated operand.
+                self.compile(lhs)?;
+                let l = self.builder.register_pop(false);
+                let phi = self.builder.register_new();
+                self.builder.code.push(Ins::Move(phi, l));
+                let jend = self.builder.cjmp(op == "&&", phi);
+                self.compile(rhs)?;
+                let r = self.builder.register_pop(false);
+                self.builder.code.push(Ins::Move(phi, r));
+                jend(&mut self.builder);
+                self.builder.register_push(phi);
+                self.builder.unprotect(phi);
                 Ok(())
             }
             ExprKind::BinOp(lhs, op, rhs) => {
@@ -723,8 +1171,11 @@ impl Context {
                     "*" => b!(Mul),
                     "%" => b!(Mod),
                     ">>" => b!(RShift),
-                    ">>>" => b!(RShift),
+                    ">>>" => b!(URShift),
                     "<<" => b!(LShift),
+                    "&" => b!(BitAnd),
+                    "|" => b!(BitOr),
+                    "^" => b!(BitXor),
                     ">" => b!(Greater),
                     ">=" => b!(GreaterOrEqual),
                     "<" => b!(Less),
@@ -817,6 +1268,7 @@ impl Context {
                 Ok(())
             }
             ExprKind::While(cond, body) => {
+                self.enter_loop();
                 let start = self.builder.code.len();
                 self.compile(cond)?;
                 let cond = self.builder.register_pop(false);
@@ -825,12 +1277,117 @@ impl Context {
                 let jend = self.builder.cjmp(true, cond);
                 self.builder.code.push(Ins::LoopHint);
                 self.compile(body)?;
-                //let r = self.builder.register_pop(false);
+                self.builder.register_pop(false);
+                self.patch_continues();
                 self.builder.code.push(Ins::Safepoint);
                 self.builder.goto(start);
                 jend(&mut self.builder);
                 //self.builder.code.push(Ins::Move(phi, r));
                 //self.builder.unprotect(phi);
+                self.leave_loop();
+                Ok(())
+            }
+            ExprKind::Loop(body) => {
+                self.enter_loop();
+                let start = self.builder.code.len();
+                self.builder.code.push(Ins::LoopHint);
+                self.compile(body)?;
+                self.builder.register_pop(false);
+                self.patch_continues();
+                self.builder.code.push(Ins::Safepoint);
+                self.builder.goto(start);
+                self.leave_loop();
+                Ok(())
+            }
+            ExprKind::DoWhile(body, cond) => {
+                self.enter_loop();
+                let start = self.builder.code.len();
+                self.builder.code.push(Ins::LoopHint);
+                self.compile(body)?;
+                self.builder.register_pop(false);
+                self.patch_continues();
+                self.compile(cond)?;
+                let cond = self.builder.register_pop(false);
+                self.builder.code.push(Ins::Safepoint);
+                let off = start as i32 - self.builder.code.len() as i32;
+                self.builder.code.push(Ins::JmpIfNotZero(cond, off));
+                self.leave_loop();
+                Ok(())
+            }
+            // `for pat in xs` walks any value that has `length` and integer keys.
+            ExprKind::For(pat, iterable, body) => {
+                self.enter_loop();
+                self.compile(iterable)?;
+                let it = self.builder.register_pop(true);
+                let i = self.builder.register_new();
+                let zero = self.builder.new_const(Value::new_int(0));
+                self.builder
+                    .code
+                    .push(Ins::Move(i, VirtualRegister::new_constant_index(zero as _)));
+                let start = self.builder.code.len();
+                let len = self.builder.register_new();
+                let key = self.builder.new_string("length");
+                self.builder.code.push(Ins::LoadId(len, it, key));
+                let cond = self.builder.register_new();
+                self.builder.code.push(Ins::Less(cond, i, len));
+                let jend = self.builder.cjmp(true, cond);
+                self.builder.unprotect(cond);
+                self.builder.unprotect(len);
+                self.builder.code.push(Ins::LoopHint);
+                self.builder.push_scope();
+                let elem = self.builder.register_new();
+                self.builder.code.push(Ins::Load(elem, it, i));
+                if let PatternDecl::Ident(name) = &pat.decl {
+                    self.builder.define_local(name, elem);
+                } else {
+                    self.compile_destructure(pat, elem)?;
+                }
+                self.builder.unprotect(elem);
+                self.compile(body)?;
+                self.builder.register_pop(false);
+                self.builder.pop_scope();
+                self.patch_continues();
+                let one = self.builder.new_const(Value::new_int(1));
+                self.builder.code.push(Ins::Add(
+                    i,
+                    i,
+                    VirtualRegister::new_constant_index(one as _),
+                ));
+                self.builder.code.push(Ins::Safepoint);
+                self.builder.goto(start);
+                jend(&mut self.builder);
+                self.builder.unprotect(i);
+                if it.is_local() && self.builder.is_temp(it) {
+                    self.builder.unprotect(it);
+                }
+                self.leave_loop();
+                Ok(())
+            }
+            ExprKind::Labeled(label, body) => {
+                if self.loops.iter().any(|l| l.label.as_ref() == Some(label)) {
+                    return Err(MsgWithPos::new(
+                        e.pos,
+                        Msg::Custom(format!("label '{}' shadows outer loop label", label)),
+                    ));
+                }
+                self.label = Some(label.clone());
+                self.compile(body)
+            }
+            ExprKind::Break(label) | ExprKind::Continue(label) => {
+                let ix = self.find_loop(label, e.pos)?;
+                // handlers of `try` blocks inside of the loop are popped like by `TryEnd`.
+                for _ in self.loops[ix].try_depth..self.try_depth {
+                    self.builder.code.push(Ins::TryEnd);
+                }
+                let j = self.builder.jmp();
+                if let ExprKind::Break(_) = &e.expr {
+                    self.loops[ix].breaks.push(Box::new(j));
+                } else {
+                    self.loops[ix].continues.push(Box::new(j));
+                }
+                let undef = self.builder.new_const(Value::undefined());
+                self.builder
+                    .register_push(VirtualRegister::new_constant_index(undef as _));
                 Ok(())
             }
             ExprKind::Block(e) => {
@@ -854,14 +1411,29 @@ impl Context {
                 Ok(())
             }
             ExprKind::Let(_, p, init) => {
-                if let PatternDecl::Ident(x) = &p.decl {
-                    self.compile(init)?;
-                    let v = self.builder.register_pop(false);
-                    let x = self.builder.new_local(x);
-                    self.builder.code.push(Ins::Move(x, v));
-                    self.builder.register_push(x);
-                } else {
-                    panic!("NYI");
+                match &p.decl {
+                    PatternDecl::Ident(x) => {
+                        self.compile(init)?;
+                        let v = self.builder.register_pop(true);
+                        let r = self.builder.define_local(x, v);
+                        if v.is_local() && self.builder.is_temp(v) {
+                            self.builder.unprotect(v);
+                        }
+                        if self.builder.captured.contains(x) {
+                            self.builder.register_push(v);
+                        } else {
+                            self.builder.register_push(r);
+                        }
+                    }
+                    _ => {
+                        self.compile(init)?;
+                        let v = self.builder.register_pop(true);
+                        self.compile_destructure(p, v)?;
+                        if v.is_local() && self.builder.is_temp(v) {
+                            self.builder.unprotect(v);
+                        }
+                        self.builder.register_push(v);
+                    }
                 }
                 Ok(())
             }
@@ -883,7 +1455,7 @@ impl Context {
             }
 
             ExprKind::Assign(e, val) => {
-                let acc = self.compile_access(&e.expr)?;
+                let acc = self.compile_access(e)?;
                 self.compile(val)?;
                 let val = self.builder.register_pop(false);
                 self.builder.register_push(val);
@@ -905,11 +1477,11 @@ impl Context {
                 Ok(())
             }
             ExprKind::Access(_, _) => {
-                let acc = self.compile_access(&e.expr)?;
+                let acc = self.compile_access(e)?;
                 self.access_get(acc)
             }
             ExprKind::ArrayIndex(_, _) => {
-                let acc = self.compile_access(&e.expr)?;
+                let acc = self.compile_access(e)?;
                 self.access_get(acc)
             }
             ExprKind::Nil => {
@@ -918,12 +1490,223 @@ impl Context {
                     .register_push(VirtualRegister::new_constant_index(c as _));
                 Ok(())
             }
+            ExprKind::Array(elems) | ExprKind::Tuple(elems) => {
+                let dst = self.builder.register_new();
+                let regs = self.builder.allocate_regs(elems.len());
+                regs.iter().for_each(|x| {
+                    self.builder.protect(*x);
+                });
+                for (i, elem) in elems.iter().enumerate() {
+                    self.compile(elem)?;
+                    let reg = self.builder.register_pop(false);
+                    if reg != regs[i] {
+                        self.builder.code.push(Ins::Move(regs[i], reg));
+                    }
+                }
+                let first = regs.first().copied().unwrap_or(dst);
+                if let ExprKind::Tuple(_) = e.expr {
+                    self.builder
+                        .code
+                        .push(Ins::NewTuple(dst, first, elems.len() as _));
+                } else {
+                    self.builder
+                        .code
+                        .push(Ins::NewArray(dst, first, elems.len() as _));
+                }
+                for r in regs {
+                    self.builder.unprotect(r);
+                }
+                self.builder.register_push(dst);
+                Ok(())
+            }
+            ExprKind::Class(name, parent, body) => {
+                let mut ctor = None;
+                let mut methods = vec![];
+                for m in body.iter() {
+                    match &m.expr {
+                        ExprKind::Function(Some(mname), args, fbody) if mname == "constructor" => {
+                            if ctor.is_some() {
+                                return Err(MsgWithPos::new(
+                                    m.pos,
+                                    Msg::Custom(format!(
+                                        "class '{}' has more than one constructor",
+                                        name
+                                    )),
+                                ));
+                            }
+                            ctor = Some((m.pos, args, fbody));
+                        }
+                        ExprKind::Function(Some(mname), args, fbody) => {
+                            methods.push((m.pos, mname, args, fbody))
+                        }
+                        _ => {
+                            return Err(MsgWithPos::new(
+                                m.pos,
+                                Msg::Custom("class method must have a name".to_owned()),
+                            ))
+                        }
+                    }
+                }
+                // without a constructor derived classes forward their arguments to the parent.
+                let default = match parent {
+                    Some(_) => ExprKind::Call(
+                        Box::new(Expr {
+                            pos: e.pos,
+                            expr: ExprKind::Ident(crate::runtime::PARENT_CONSTRUCTOR.to_owned()),
+                        }),
+                        vec![],
+                    ),
+                    None => ExprKind::Block(vec![]),
+                };
+                let default = Box::new(Expr {
+                    pos: e.pos,
+                    expr: default,
+                });
+                match ctor {
+                    Some((pos, args, fbody)) => {
+                        self.compile_function(pos, args, fbody, Some(name.clone()))?
+                    }
+                    None => self.compile_function(e.pos, &[], &default, Some(name.clone()))?,
+                }
+                let class = self.builder.register_pop(true);
+                if let Some(parent) = parent {
+                    self.compile(parent)?;
+                    let p = self.builder.register_pop(false);
+                    self.builder.code.push(Ins::Inherit(class, p));
+                }
+                let proto = self.builder.register_new();
+                let key = self.builder.new_string("prototype");
+                self.builder.code.push(Ins::LoadId(proto, class, key));
+                for (pos, mname, args, fbody) in methods {
+                    self.compile_function(pos, args, fbody, None)?;
+                    let f = self.builder.register_pop(false);
+                    let key = self.builder.new_string(mname);
+                    self.builder.code.push(Ins::StoreId(proto, key, f));
+                }
+                self.builder.unprotect(proto);
+                self.builder.register_push(class);
+                self.builder.unprotect(class);
+                Ok(())
+            }
+            ExprKind::Match(value, arms) => {
+                self.compile(value)?;
+                let v = self.builder.register_pop(true);
+                let phi = self.builder.register_new();
+                let co = self.builder.new_const(Value::undefined());
+                self.builder
+                    .code
+                    .push(Ins::Move(phi, VirtualRegister::new_constant_index(co as _)));
+                let mut ends = vec![];
+                for (pat, guard, body) in arms.iter() {
+                    let mut fails: Vec<Box<dyn FnOnce(&mut ByteCompiler)>> = vec![];
+                    self.builder.push_scope();
+                    self.compile_pattern(pat, v, &mut fails)?;
+                    if let Some(guard) = guard {
+                        self.compile(guard)?;
+                        let g = self.builder.register_pop(false);
+                        fails.push(Box::new(self.builder.cjmp(true, g)));
+                    }
+                    self.compile(body)?;
+                    let r = self.builder.register_pop(false);
+                    self.builder.code.push(Ins::Move(phi, r));
+                    ends.push(self.builder.jmp());
+                    self.builder.pop_scope();
+                    for fail in fails {
+                        fail(&mut self.builder);
+                    }
+                }
+                let msg = self.builder.new_string(format!("no match at {}", e.pos));
+                self.builder
+                    .code
+                    .push(Ins::Throw(VirtualRegister::new_constant_index(msg as _)));
+                for end in ends {
+                    end(&mut self.builder);
+                }
+                if v.is_local() && self.builder.is_temp(v) {
+                    self.builder.unprotect(v);
+                }
+                self.builder.register_push(phi);
+                self.builder.unprotect(phi);
+                Ok(())
+            }
+            ExprKind::Try(body, name, catch) => {
+                let phi = self.builder.register_new();
+                let try_ix = self.builder.code.len();
+                self.builder.code.push(Ins::Try(0));
+                self.try_depth += 1;
+                self.compile(body)?;
+                self.try_depth -= 1;
+                let r = self.builder.register_pop(false);
+                self.builder.code.push(Ins::Move(phi, r));
+                self.builder.code.push(Ins::TryEnd);
+                let jend = self.builder.jmp();
+                self.builder.code[try_ix] = Ins::Try((self.builder.code.len() - try_ix) as _);
+                self.builder.push_scope();
+                let exc = self.builder.register_new();
+                self.builder.code.push(Ins::Catch(exc));
+                self.builder.define_local(name, exc);
+                self.builder.unprotect(exc);
+                self.compile(catch)?;
+                let r = self.builder.register_pop(false);
+                self.builder.code.push(Ins::Move(phi, r));
+                self.builder.pop_scope();
+                jend(&mut self.builder);
+                self.builder.register_push(phi);
+                self.builder.unprotect(phi);
+                Ok(())
+            }
+            ExprKind::Throw(val) => {
+                self.compile(val)?;
+                let r = self.builder.register_pop(false);
+                self.builder.code.push(Ins::Throw(r));
+                self.builder.register_push(r);
+                Ok(())
+            }
+            ExprKind::Return(None) => {
+                let undef = self.builder.new_const(Value::undefined());
+                let r = VirtualRegister::new_constant_index(undef as _);
+                self.builder.code.push(Ins::Return(r));
+                self.builder.register_push(r);
+                Ok(())
+            }
+            ExprKind::Lambda(args, body) => self.compile_function(e.pos, args, body, None),
+            ExprKind::ConstBool(b) => {
+                let c = self.builder.new_const(Value::new_bool(*b));
+                self.builder
+                    .register_push(VirtualRegister::new_constant_index(c as _));
+                Ok(())
+            }
+            // characters are one character strings, like in patterns.
+            ExprKind::ConstChar(c) => {
+                let k = self.builder.new_string(c.to_string());
+                self.builder
+                    .register_push(VirtualRegister::new_constant_index(k as _));
+                Ok(())
+            }
+            // object literal with computed keys, `{ key: value }` with plain names is `NewObject`.
+            ExprKind::Object(fields) => {
+                let dest = self.builder.register_new();
+                self.builder.code.push(Ins::NewObject(dest));
+                for (key, val) in fields.iter() {
+                    self.compile(key)?;
+                    let k = self.builder.register_pop(true);
+                    self.compile(val)?;
+                    let v = self.builder.register_pop(false);
+                    self.builder.code.push(Ins::Store(dest, k, v));
+                    if k.is_local() && self.builder.is_temp(k) {
+                        self.builder.unprotect(k);
+                    }
+                }
+                self.builder.unprotect(dest);
+                self.builder.register_push(dest);
+                Ok(())
+            }
             _ => todo!("{:?}", e),
         }
     }
 
-    fn compile_access(&mut self, e: &ExprKind) -> Result<Access, MsgWithPos> {
-        match e {
+    fn compile_access(&mut self, e: &Expr) -> Result<Access, MsgWithPos> {
+        match &e.expr {
             ExprKind::Ident(i) => {
                 if let Some(x) = self.builder.get_local(i) {
                     return Ok(Access::Stack(i.to_owned(), x));
@@ -945,11 +1728,36 @@ impl Context {
             }
             ExprKind::Access(object, field) => Ok(Access::Field(object.clone(), field.clone())),
             ExprKind::ArrayIndex(object, ix) => Ok(Access::Array(object.clone(), ix.clone())),
-            _ => unimplemented!(),
+            _ => Err(MsgWithPos::new(e.pos, Msg::LvalueExpected)),
         }
     }
 }
 use frontend::token::*;
+/// Collect names bound by `pat`.
+fn pattern_names<'a>(pat: &'a Pattern, names: &mut Vec<&'a String>) {
+    match &pat.decl {
+        PatternDecl::Ident(name) | PatternDecl::Rest(Some(name)) => names.push(name),
+        PatternDecl::Cons(head, tail) => {
+            pattern_names(head, names);
+            pattern_names(tail, names);
+        }
+        PatternDecl::Tuple(pats) | PatternDecl::Array(pats) => {
+            for p in pats.iter() {
+                pattern_names(p, names);
+            }
+        }
+        PatternDecl::Record(fields) => {
+            for (name, p) in fields.iter() {
+                match p {
+                    Some(p) => pattern_names(p, names),
+                    None => names.push(name),
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
 pub fn compile(ast: &[Box<Expr>]) -> Result<(Ref<Module>, Ref<CodeBlock>), MsgWithPos> {
     //let vm = crate::get_vm();
     let ast = Box::new(Expr {
@@ -963,6 +1771,7 @@ pub fn compile(ast: &[Box<Expr>]) -> Result<(Ref<Module>, Ref<CodeBlock>), MsgWi
         Rc::new(RefCell::new(Default::default())),
     );
     ctx.builder.new_const(Value::undefined());
+    ctx.builder.captured = captures::captured_names(&ast);
     let _ = ctx.compile(&ast)?;
     ctx.builder.code.push(Ins::Safepoint);
     let r = ctx.builder.register_pop(false);
@@ -971,3 +1780,112 @@ pub fn compile(ast: &[Box<Expr>]) -> Result<(Ref<Module>, Ref<CodeBlock>), MsgWi
 
     Ok((module, cb))
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::*;
+
+    #[test]
+    fn test_unsigned_shift() {
+        let _vm = lock();
+        assert_eq!(
+            run_both("(-1 >>> 0, -8 >>> 1, 7 >>> 1, -1 >>> 32)"),
+            "(4294967295,2147483644,3,4294967295)"
+        );
+        assert_eq!(run_both("let x = -1\nlet y = 28\nx >>> y"), "15");
+    }
+
+    #[test]
+    fn test_array_growth() {
+        let _vm = lock();
+        let src = "let a = []\na[3] = 1\n(a.length, a[0], a[3])";
+        assert_eq!(run_both(src), "(4,undefined,1)");
+        assert_eq!(
+            run_both("let a = [1]\ntry { a[1000000000] = 1 } catch e { (e, a.length) }"),
+            "(RangeError: array index 1000000000 is out of bounds,1)"
+        );
+    }
+
+    #[test]
+    fn test_one_element_tuples() {
+        let _vm = lock();
+        assert_eq!(run_both("((1,), (1), ())"), "((1,),1,())");
+        assert_eq!(run_both("let (a) = 5\na"), "5");
+        assert_eq!(run_both("match (1,) { (x,) => x + 1, _ => 0 }"), "2");
+        assert_eq!(run_both("match 3 { (x) => x, _ => 0 }"), "3");
+    }
+
+    #[test]
+    fn test_match_patterns() {
+        let _vm = lock();
+        let src = "function f(v) { return match v { 0 => 1, 1.5 => 2, \"s\" => 3, _ => 4 } }\n\
+                   f(0) * 1000 + f(1.5) * 100 + f(\"s\") * 10 + f(7)";
+        assert_eq!(run_both(src), "1234");
+        let src = "function f(v) { return match v { { x, y: 3 } => x,\n\
+                   { x } => x * 10, _ => 0 } }\n\
+                   f(new { x: 4, y: 3 }) + f(new { x: 4, y: 2 }) * 100 + f(new { z: 1 })";
+        assert_eq!(run_both(src), "4004");
+        let src = "function sign(n) { return match n { x when x < 0 => -1,\n0 => 0, _ => 1 } }\n\
+                   sign(-5) * 100 + sign(0) * 10 + sign(9)";
+        assert_eq!(run_both(src), "-99");
+        assert!(run_both("match 3 { 1 => 1, 2 => 2 }").starts_with("error: no match at "));
+    }
+
+    #[test]
+    fn test_destructuring() {
+        let _vm = lock();
+        let src = "function len({x, y}) { return x * x + y * y }\n\
+                   function sum([a, ..rest]) { return a + rest.length }\n\
+                   (len(new { x: 3, y: 4 }), sum([10, 1, 1]), sum([5]))";
+        assert_eq!(run_both(src), "(25,12,5)");
+        let src = "let { x, y: (a, b) } = new { x: 1, y: (2, 3) }\n\
+                   let [first, ..rest] = [4, 5, 6]\n\
+                   (x + a + b, first, rest.length, rest[1])";
+        assert_eq!(run_both(src), "(6,4,2,6)");
+        let mismatch = "error: value does not match pattern at ";
+        let src = "function sum([a, b]) { return a + b }\nsum([1, 2, 3])";
+        assert!(run_both(src).starts_with(mismatch));
+        assert!(run_both("let (a, b) = 1").starts_with(mismatch));
+        assert_eq!(
+            compile_error("function f({x}, x) { return x }"),
+            "argument 'x' already defined"
+        );
+    }
+
+    #[test]
+    fn test_shared_upvalues() {
+        let _vm = lock();
+        let src = "function pair() { let n = 0\nlet inc = || n = n + 1\nlet get = || n\n\
+                   return (inc, get) }\nlet (inc, get) = pair()\ninc()\ninc()\nget()";
+        assert_eq!(run_both(src), "2");
+        let src = "function f() { let x = 1\nlet g = || x\nx = 5\nreturn g() }\nf()";
+        assert_eq!(run_both(src), "5");
+        let src = "function outer() { let n = 1\nlet mid = function() { return || n = n * 10 }\n\
+                   mid()()\nmid()()\nreturn n }\nouter()";
+        assert_eq!(run_both(src), "100");
+    }
+
+    #[test]
+    fn test_break_out_of_try() {
+        let _vm = lock();
+        let src = "let i = 0\nwhile true { try { i = i + 1\nif i > 2 { break } } catch e { 0 } }\n\
+                   try { throw i } catch e { e }";
+        assert_eq!(run_both(src), "3");
+        let src = "let s = 0\nfor x in [1, 2, 3] { try { try { if x == 2 { continue }\n\
+                   s = s + x } catch e { 0 } } catch e { 0 } }\n\
+                   try { throw s } catch e { e }";
+        assert_eq!(run_both(src), "4");
+        let src = "outer: loop { try { loop { try { break outer } catch e { 0 } } } catch e { 0 } }\n\
+                   try { throw 1 } catch e { e + 1 }";
+        assert_eq!(run_both(src), "2");
+    }
+
+    #[test]
+    fn test_default_constructor_calls_parent() {
+        let _vm = lock();
+        let src = "class A { function constructor(x, y) { this.s = x + y } }\n\
+                   class B(A) { }\nclass C(B) { function get() { return this.s } }\n\
+                   let c = new C(1, 2)\nc.get()";
+        assert_eq!(run_both(src), "3");
+    }
+}
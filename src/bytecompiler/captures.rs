@@ -0,0 +1,90 @@
+//! Finds locals that have to live in upvalue cells because nested functions refer to them.
+use crate::frontend::ast::*;
+use std::collections::HashSet;
+
+/// Collect names referenced from functions nested in `body`. Shadowing is not tracked,
+/// so this may box more locals than needed which is still correct, just slower.
+pub fn captured_names(body: &Expr) -> HashSet<String> {
+    let mut names = HashSet::new();
+    visit(body, false, &mut names);
+    names
+}
+
+fn visit(e: &Expr, nested: bool, names: &mut HashSet<String>) {
+    match &e.expr {
+        ExprKind::Ident(name) => {
+            if nested {
+                names.insert(name.clone());
+            }
+        }
+        ExprKind::Function(_, _, body) | ExprKind::Lambda(_, body) => visit(body, true, names),
+        ExprKind::Assign(a, b)
+        | ExprKind::BinOp(a, _, b)
+        | ExprKind::While(a, b)
+        | ExprKind::DoWhile(a, b)
+        | ExprKind::For(_, a, b)
+        | ExprKind::ArrayIndex(a, b)
+        | ExprKind::Try(a, _, b) => {
+            visit(a, nested, names);
+            visit(b, nested, names);
+        }
+        ExprKind::Unop(_, a)
+        | ExprKind::Access(a, _)
+        | ExprKind::New(a)
+        | ExprKind::Let(_, _, a)
+        | ExprKind::Loop(a)
+        | ExprKind::Labeled(_, a)
+        | ExprKind::Throw(a)
+        | ExprKind::Return(Some(a))
+        | ExprKind::Var(_, _, Some(a)) => visit(a, nested, names),
+        ExprKind::Match(a, arms) => {
+            visit(a, nested, names);
+            for (_, guard, body) in arms.iter() {
+                if let Some(guard) = guard {
+                    visit(guard, nested, names);
+                }
+                visit(body, nested, names);
+            }
+        }
+        ExprKind::If(cond, then, otherwise) => {
+            visit(cond, nested, names);
+            visit(then, nested, names);
+            if let Some(otherwise) = otherwise {
+                visit(otherwise, nested, names);
+            }
+        }
+        ExprKind::Object(fields) => {
+            for (key, value) in fields.iter() {
+                visit(key, nested, names);
+                visit(value, nested, names);
+            }
+        }
+        ExprKind::NewObject(fields) => {
+            for (_, value) in fields.iter() {
+                if let Some(value) = value {
+                    visit(value, nested, names);
+                }
+            }
+        }
+        ExprKind::Call(callee, args) => {
+            visit(callee, nested, names);
+            for arg in args.iter() {
+                visit(arg, nested, names);
+            }
+        }
+        ExprKind::Class(_, parent, methods) => {
+            if let Some(parent) = parent {
+                visit(parent, nested, names);
+            }
+            for method in methods.iter() {
+                visit(method, nested, names);
+            }
+        }
+        ExprKind::Block(exprs) | ExprKind::Array(exprs) | ExprKind::Tuple(exprs) => {
+            for e in exprs.iter() {
+                visit(e, nested, names);
+            }
+        }
+        _ => (),
+    }
+}
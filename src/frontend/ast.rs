@@ -13,7 +13,8 @@ pub enum PatternDecl {
     Record(Vec<(String, Option<Box<Pattern>>)>),
     Array(Vec<Box<Pattern>>),
     Pass,
-    Rest,
+    /// `..` or `..name`, the latter binds remaining elements as a new array.
+    Rest(Option<String>),
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -22,8 +23,8 @@ pub enum Arg {
     Ident(bool, String),
     /// `function foo ( {x,y} /* Record */ )`
     Record(Vec<String>),
-    /// `function foo ( [x,y] /* Array */ )`
-    Array(Vec<String>),
+    /// `function foo ( [x,y,..rest] /* Array */ )`
+    Array(Vec<String>, Option<String>),
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -59,6 +60,15 @@ pub enum ExprKind {
     Var(bool, String, Option<Box<Expr>>),
     Let(bool, Box<Pattern>, Box<Expr>),
     While(Box<Expr>, Box<Expr>),
+    /// `for pattern in iterable { ... }`
+    For(Box<Pattern>, Box<Expr>, Box<Expr>),
+    Loop(Box<Expr>),
+    /// `do { ... } while cond`
+    DoWhile(Box<Expr>, Box<Expr>),
+    /// `label: <loop>`
+    Labeled(String, Box<Expr>),
+    Break(Option<String>),
+    Continue(Option<String>),
     Block(Vec<Box<Expr>>),
     Return(Option<Box<Expr>>),
     Call(Box<Expr>, Vec<Box<Expr>>),
@@ -46,6 +46,8 @@ impl Lexer {
             "else" => TokenKind::Else,
             "in" => TokenKind::In,
             "loop" => TokenKind::Loop,
+            "break" => TokenKind::Break,
+            "continue" => TokenKind::Continue,
             "match" => TokenKind::Match,
             "when" => TokenKind::When,
             "const" => TokenKind::Const,
@@ -72,6 +74,10 @@ impl Lexer {
         self.reader.filename()
     }
 
+    pub fn source(&self) -> &str {
+        self.reader.src()
+    }
+
     fn read_multi_comment(&mut self) -> Result<(), MsgWithPos> {
         let pos = self.reader.pos();
 
@@ -119,6 +125,8 @@ impl Lexer {
                 return self.read_operator();
             } else {
                 let ch = ch.unwrap();
+                // skip it, so the parser can carry on after reporting the error.
+                self.read_char();
 
                 return Err(MsgWithPos::new(pos, Msg::UnknownChar(ch)));
             }
@@ -328,7 +336,12 @@ impl Lexer {
                 '>' => {
                     self.read_char();
 
-                    TokenKind::GtGt
+                    if self.cur() == Some('>') {
+                        self.read_char();
+                        TokenKind::GtGtGt
+                    } else {
+                        TokenKind::GtGt
+                    }
                 }
 
                 _ => TokenKind::Gt,
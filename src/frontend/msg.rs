@@ -67,6 +67,7 @@ pub enum Msg {
     NumberOverflow(String),
     ExpectedClass(String),
     ExpectedFactor(String),
+    ExpectedPattern(String),
     ExpectedToken(String, String),
     ExpectedTopLevelElement(String),
     ExpectedTrait(String),
@@ -241,6 +242,7 @@ impl Msg {
             NumberOverflow(ref ty) => format!("number does not fit into type {}.", ty),
             ExpectedClass(ref cls) => format!("expected class name but got {}.", cls),
             ExpectedFactor(ref got) => format!("factor expected but got {}.", got),
+            ExpectedPattern(ref got) => format!("pattern expected but got {}.", got),
             ExpectedTrait(ref trt) => format!("expected trait name but got {}.", trt),
             ExpectedType(ref got) => format!("type expected but got {}.", got),
             ExpectedIdentifier(ref tok) => format!("identifier expected but got {}.", tok),
@@ -367,6 +369,39 @@ impl MsgWithPos {
     pub fn message(&self) -> String {
         format!("error at {}: {}", self.pos, self.msg.message())
     }
+
+    /// Render error with file name and the offending source line, with a caret under the column.
+    pub fn render(&self, filename: &str, src: &str) -> String {
+        let mut out = format!(
+            "error: {}\n --> {}:{}:{}\n",
+            self.msg.message(),
+            filename,
+            self.pos.line,
+            self.pos.column
+        );
+        if let Some(line) = src.lines().nth((self.pos.line as usize).wrapping_sub(1)) {
+            // the reader counts columns with tabs expanded to 4 spaces.
+            let mut text = String::new();
+            for ch in line.chars() {
+                if ch == '\t' {
+                    let n = 4 - text.chars().count() % 4;
+                    text.push_str(&" ".repeat(n));
+                } else {
+                    text.push(ch);
+                }
+            }
+            let lineno = self.pos.line.to_string();
+            let pad = " ".repeat(lineno.len());
+            out.push_str(&format!("{} |\n", pad));
+            out.push_str(&format!("{} | {}\n", lineno, text));
+            out.push_str(&format!(
+                "{} | {}^\n",
+                pad,
+                " ".repeat(self.pos.column.saturating_sub(1) as usize)
+            ));
+        }
+        out
+    }
 }
 
 impl fmt::Display for MsgWithPos {
@@ -26,6 +26,7 @@ pub struct Parser<'a> {
     lexer: Lexer,
     token: Token,
     ast: &'a mut Vec<Box<Expr>>,
+    errors: Vec<MsgWithPos>,
 }
 
 macro_rules! expr {
@@ -45,21 +46,90 @@ impl<'a> Parser<'a> {
             lexer: Lexer::new(reader),
             token: Token::new(TokenKind::End, Position::new(1, 1)),
             ast,
+            errors: vec![],
         }
     }
 
-    fn init(&mut self) -> Result<(), MsgWithPos> {
-        self.advance_token()?;
+    pub fn filename(&self) -> &str {
+        self.lexer.filename()
+    }
 
-        Ok(())
+    pub fn source(&self) -> &str {
+        self.lexer.source()
     }
 
-    pub fn parse(&mut self) -> Result<(), MsgWithPos> {
-        self.init()?;
+    fn init(&mut self) {
+        self.skip_token();
+    }
+
+    /// Parse the whole input. Parsing continues after an error at the next statement,
+    /// so every diagnostic found is returned.
+    pub fn parse(&mut self) -> Result<(), Vec<MsgWithPos>> {
+        self.init();
         while !self.token.is_eof() {
-            self.parse_top_level()?;
+            if let Err(e) = self.parse_top_level() {
+                self.errors.push(e);
+                self.synchronize();
+                // stray `}` can't start a statement, skip it.
+                if self.token.is(TokenKind::RBrace) {
+                    self.skip_token();
+                }
+            }
+        }
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(mem::replace(&mut self.errors, vec![]))
+        }
+    }
+
+    /// Advance to the next token, lexer errors are recorded and the bad input is skipped.
+    fn skip_token(&mut self) {
+        loop {
+            match self.advance_token() {
+                Ok(_) => return,
+                Err(e) => self.errors.push(e),
+            }
+        }
+    }
+
+    /// Skip tokens until one that starts a statement. Nested braces are skipped as a whole
+    /// and the `}` closing the current block is left for the caller.
+    fn synchronize(&mut self) {
+        let mut depth = 0;
+        loop {
+            match self.token.kind {
+                TokenKind::End => return,
+                TokenKind::LBrace => depth += 1,
+                TokenKind::RBrace => {
+                    if depth == 0 {
+                        return;
+                    }
+                    depth -= 1;
+                }
+                TokenKind::Fun
+                | TokenKind::Class
+                | TokenKind::Let
+                | TokenKind::Var
+                | TokenKind::If
+                | TokenKind::While
+                | TokenKind::For
+                | TokenKind::Loop
+                | TokenKind::Do
+                | TokenKind::Match
+                | TokenKind::Return
+                | TokenKind::Throw
+                | TokenKind::Try
+                | TokenKind::Break
+                | TokenKind::Continue
+                    if depth == 0 =>
+                {
+                    return
+                }
+                _ => (),
+            }
+            self.skip_token();
         }
-        Ok(())
     }
 
     fn expect_token(&mut self, kind: TokenKind) -> Result<Token, MsgWithPos> {
@@ -125,9 +195,30 @@ impl<'a> Parser<'a> {
             }
             TokenKind::LBracket => {
                 self.expect_token(TokenKind::LBracket)?;
-                let list: Vec<String> = self
-                    .parse_comma_list(TokenKind::RBracket, |parser| parser.expect_identifier())?;
-                Ok(Arg::Array(list))
+                let list: Vec<(bool, String)> =
+                    self.parse_comma_list(TokenKind::RBracket, |parser| {
+                        let rest = parser.token.is(TokenKind::DotDot);
+                        if rest {
+                            parser.advance_token()?;
+                        }
+                        Ok((rest, parser.expect_identifier()?))
+                    })?;
+                let mut names = vec![];
+                let mut rest = None;
+                for (is_rest, name) in list {
+                    if rest.is_some() {
+                        return Err(MsgWithPos::new(
+                            pos,
+                            Msg::Custom("rest parameter must be last".to_owned()),
+                        ));
+                    }
+                    if is_rest {
+                        rest = Some(name);
+                    } else {
+                        names.push(name);
+                    }
+                }
+                Ok(Arg::Array(names, rest))
             }
             _ => Err(MsgWithPos::new(
                 pos,
@@ -148,6 +239,13 @@ impl<'a> Parser<'a> {
 
     fn parse_return(&mut self) -> EResult {
         let pos = self.expect_token(TokenKind::Return)?.position;
+        // bare `return` ends the block or its line.
+        if self.token.is(TokenKind::RBrace)
+            || self.token.is_eof()
+            || self.token.position.line != pos.line
+        {
+            return Ok(expr!(ExprKind::Return(None), pos));
+        }
         let expr = self.parse_expression()?;
         Ok(expr!(ExprKind::Return(Some(expr)), pos))
     }
@@ -166,10 +264,56 @@ impl<'a> Parser<'a> {
             TokenKind::LBrace => self.parse_block(),
             TokenKind::If => self.parse_if(),
             TokenKind::While => self.parse_while(),
+            TokenKind::For => self.parse_for(),
+            TokenKind::Loop => self.parse_loop(),
+            TokenKind::Do => self.parse_do_while(),
+            TokenKind::Break | TokenKind::Continue => self.parse_break_continue(),
             TokenKind::Return => self.parse_return(),
             TokenKind::Throw => self.parse_throw(),
             TokenKind::Try => self.parse_try(),
-            _ => self.parse_binary(0),
+            _ => {
+                let expr = self.parse_binary(0)?;
+                if let ExprKind::Ident(name) = &expr.expr {
+                    if self.token.is(TokenKind::Colon) {
+                        return self.parse_labeled(name.clone(), expr.pos);
+                    }
+                }
+                Ok(expr)
+            }
+        }
+    }
+
+    fn parse_labeled(&mut self, label: String, pos: Position) -> EResult {
+        self.expect_token(TokenKind::Colon)?;
+        let body = match self.token.kind {
+            TokenKind::While => self.parse_while()?,
+            TokenKind::For => self.parse_for()?,
+            TokenKind::Loop => self.parse_loop()?,
+            TokenKind::Do => self.parse_do_while()?,
+            _ => {
+                return Err(MsgWithPos::new(
+                    self.token.position,
+                    Msg::Custom(format!("label '{}' must be followed by a loop", label)),
+                ))
+            }
+        };
+        Ok(expr!(ExprKind::Labeled(label, body), pos))
+    }
+
+    fn parse_break_continue(&mut self) -> EResult {
+        let tok = self.advance_token()?;
+        // label has to be on the same line, otherwise `break` followed by an expression
+        // statement on the next line would swallow it.
+        let label = match self.token.kind {
+            TokenKind::Identifier(_) if self.token.position.line == tok.position.line => {
+                Some(self.expect_identifier()?)
+            }
+            _ => None,
+        };
+        if tok.is(TokenKind::Break) {
+            Ok(expr!(ExprKind::Break(label), tok.position))
+        } else {
+            Ok(expr!(ExprKind::Continue(label), tok.position))
         }
     }
 
@@ -201,6 +345,29 @@ impl<'a> Parser<'a> {
         Ok(expr!(ExprKind::While(cond, block), pos))
     }
 
+    fn parse_for(&mut self) -> EResult {
+        let pos = self.expect_token(TokenKind::For)?.position;
+        let pat = self.parse_pattern()?;
+        self.expect_token(TokenKind::In)?;
+        let iterable = self.parse_expression()?;
+        let block = self.parse_block()?;
+        Ok(expr!(ExprKind::For(pat, iterable, block), pos))
+    }
+
+    fn parse_loop(&mut self) -> EResult {
+        let pos = self.expect_token(TokenKind::Loop)?.position;
+        let block = self.parse_block()?;
+        Ok(expr!(ExprKind::Loop(block), pos))
+    }
+
+    fn parse_do_while(&mut self) -> EResult {
+        let pos = self.expect_token(TokenKind::Do)?.position;
+        let block = self.parse_block()?;
+        self.expect_token(TokenKind::While)?;
+        let cond = self.parse_expression()?;
+        Ok(expr!(ExprKind::DoWhile(block, cond), pos))
+    }
+
     fn parse_if(&mut self) -> EResult {
         let pos = self.expect_token(TokenKind::If)?.position;
         let cond = self.parse_expression()?;
@@ -227,8 +394,13 @@ impl<'a> Parser<'a> {
         let pos = self.expect_token(TokenKind::LBrace)?.position;
         let mut exprs = vec![];
         while !self.token.is(TokenKind::RBrace) && !self.token.is_eof() {
-            let expr = self.parse_expression()?;
-            exprs.push(expr);
+            match self.parse_expression() {
+                Ok(expr) => exprs.push(expr),
+                Err(e) => {
+                    self.errors.push(e);
+                    self.synchronize();
+                }
+            }
         }
         self.expect_token(TokenKind::RBrace)?;
         Ok(expr!(ExprKind::Block(exprs), pos))
@@ -254,6 +426,7 @@ impl<'a> Parser<'a> {
             TokenKind::Div => "/",
             TokenKind::LtLt => "<<",
             TokenKind::GtGt => ">>",
+            TokenKind::GtGtGt => ">>>",
             TokenKind::Mod => "%",
             _ => unimplemented!(),
         };
@@ -275,7 +448,11 @@ impl<'a> Parser<'a> {
                 | TokenKind::Gt
                 | TokenKind::Ge => 4,
                 TokenKind::BitOr | TokenKind::BitAnd | TokenKind::Caret => 6,
-                TokenKind::LtLt | TokenKind::GtGt | TokenKind::Add | TokenKind::Sub => 8,
+                TokenKind::LtLt
+                | TokenKind::GtGt
+                | TokenKind::GtGtGt
+                | TokenKind::Add
+                | TokenKind::Sub => 8,
                 TokenKind::Mul | TokenKind::Div | TokenKind::Mod => 9,
                 _ => {
                     return Ok(left);
@@ -495,17 +672,26 @@ impl<'a> Parser<'a> {
             TokenKind::String(_) => self.plit_str(),
             TokenKind::Identifier(_) => self.pident(),
             TokenKind::LBracket => self.parray(),
+            TokenKind::LParen => self.ptuple(),
             TokenKind::LBrace => self.precord(),
 
             TokenKind::DotDot => {
                 let pos = self.advance_token()?.position;
+                let name = if let TokenKind::Identifier(_) = self.token.kind {
+                    Some(self.expect_identifier()?)
+                } else {
+                    None
+                };
                 Ok(Pattern {
-                    decl: PatternDecl::Rest,
+                    decl: PatternDecl::Rest(name),
                     pos: pos,
                 })
                 .map(|x| Box::new(x))
             }
-            _ => unimplemented!(),
+            _ => Err(MsgWithPos::new(
+                pos,
+                Msg::ExpectedPattern(self.token.name().clone()),
+            )),
         }
     }
 
@@ -513,6 +699,7 @@ impl<'a> Parser<'a> {
         let expr = match self.token.kind {
             TokenKind::Fun => self.parse_function(),
             TokenKind::LParen => self.parse_parentheses(),
+            TokenKind::LBracket => self.parse_array_literal(),
             TokenKind::LitChar(_) => self.lit_char(),
             TokenKind::LitInt(_, _, _) => self.lit_int(),
             TokenKind::LitFloat(_) => self.lit_float(),
@@ -561,11 +748,28 @@ impl<'a> Parser<'a> {
         expr
     }
 
+    /// Parses `(expr)`, or a tuple: `()`, `(a,)`, `(a, b, ...)`.
     fn parse_parentheses(&mut self) -> EResult {
-        self.advance_token()?;
-        let expr = self.parse_expression();
+        let pos = self.advance_token()?.position;
+        if self.token.is(TokenKind::RParen) {
+            self.advance_token()?;
+            return Ok(expr!(ExprKind::Tuple(vec![]), pos));
+        }
+        let expr = self.parse_expression()?;
+        if self.token.is(TokenKind::Comma) {
+            self.advance_token()?;
+            let mut list = self.parse_comma_list(TokenKind::RParen, |p| p.parse_expression())?;
+            list.insert(0, expr);
+            return Ok(expr!(ExprKind::Tuple(list), pos));
+        }
         self.expect_token(TokenKind::RParen)?;
-        expr
+        Ok(expr)
+    }
+
+    fn parse_array_literal(&mut self) -> EResult {
+        let pos = self.expect_token(TokenKind::LBracket)?.position;
+        let list = self.parse_comma_list(TokenKind::RBracket, |p| p.parse_expression())?;
+        Ok(expr!(ExprKind::Array(list), pos))
     }
 
     fn parse_null(&mut self) -> EResult {
@@ -691,6 +895,31 @@ impl<'a> Parser<'a> {
         .map(|x| Box::new(x))
     }
 
+    /// `(p)` groups like in expressions, tuples of one element are written as `(p,)`.
+    fn ptuple(&mut self) -> Result<Box<Pattern>, MsgWithPos> {
+        let pos = self.token.position;
+        self.expect_token(TokenKind::LParen)?;
+        let mut list = vec![];
+        if !self.token.is(TokenKind::RParen) {
+            let first = self.parse_pattern()?;
+            if !self.token.is(TokenKind::Comma) {
+                self.expect_token(TokenKind::RParen)?;
+                return Ok(first);
+            }
+            self.advance_token()?;
+            list = self.parse_comma_list(TokenKind::RParen, |parser| parser.parse_pattern())?;
+            list.insert(0, first);
+        } else {
+            self.advance_token()?;
+        }
+
+        Ok(Pattern {
+            decl: PatternDecl::Tuple(list),
+            pos,
+        })
+        .map(|x| Box::new(x))
+    }
+
     fn precord(&mut self) -> Result<Box<Pattern>, MsgWithPos> {
         let pos = self.expect_token(TokenKind::LBrace)?.position;
         let record = self.parse_comma_list(TokenKind::RBrace, |parser| {
@@ -711,3 +940,38 @@ impl<'a> Parser<'a> {
         .map(|x| Box::new(x))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_errors(src: &str) -> Vec<MsgWithPos> {
+        let mut ast = vec![];
+        match Parser::new(Reader::from_string(src), &mut ast).parse() {
+            Ok(()) => vec![],
+            Err(errors) => errors,
+        }
+    }
+
+    #[test]
+    fn recover_at_statement_boundaries() {
+        let errors = parse_errors("let = 1\nlet x = 2\nlet y = )\nx");
+        let positions = errors.iter().map(|e| e.pos).collect::<Vec<_>>();
+        assert_eq!(positions, vec![Position::new(1, 5), Position::new(3, 9)]);
+
+        let src = "function f() { let = 1\nreturn 2 }\nlet z = )\nfunction g() { return 1 }";
+        let positions = parse_errors(src).iter().map(|e| e.pos).collect::<Vec<_>>();
+        assert_eq!(positions, vec![Position::new(1, 20), Position::new(3, 9)]);
+        assert!(parse_errors("let x = (1, 2)\nx").is_empty());
+    }
+
+    #[test]
+    fn render_with_source_line() {
+        let src = "let x = 1\nlet = 1";
+        let errors = parse_errors(src);
+        assert_eq!(errors.len(), 1);
+        let rendered = errors[0].render("test.waffle", src);
+        assert!(rendered.starts_with("error: "));
+        assert!(rendered.ends_with(" --> test.waffle:2:5\n  |\n2 | let = 1\n  |     ^\n"));
+    }
+}
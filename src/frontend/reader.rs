@@ -45,6 +45,10 @@ impl Reader {
         &self.filename
     }
 
+    pub fn src(&self) -> &str {
+        &self.src
+    }
+
     pub fn advance(&mut self) -> Option<char> {
         match self.cur {
             Some('\n') => {
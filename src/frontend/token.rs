@@ -60,6 +60,8 @@ pub enum TokenKind {
     Loop,
     For,
     In,
+    Break,
+    Continue,
     Return,
     True,
     False,
@@ -111,6 +113,7 @@ pub enum TokenKind {
     Ge,
 
     GtGt,
+    GtGtGt,
     LtLt,
 }
 
@@ -149,6 +152,8 @@ impl TokenKind {
             TokenKind::Loop => "loop",
             TokenKind::For => "for",
             TokenKind::In => "in",
+            TokenKind::Break => "break",
+            TokenKind::Continue => "continue",
             TokenKind::When => "when",
             TokenKind::Return => "return",
             TokenKind::True => "true",
@@ -198,6 +203,7 @@ impl TokenKind {
             TokenKind::Ge => ">=",
 
             TokenKind::GtGt => ">>",
+            TokenKind::GtGtGt => ">>>",
             TokenKind::LtLt => "<<",
         }
     }
@@ -14,6 +14,8 @@ pub struct Function {
     pub name: Ref<WaffleString>,
     pub prototype: value::Value,
     pub module: Option<Ref<Module>>,
+    /// Class set by `Inherit`, called by the default constructor of derived classes.
+    pub parent: value::Value,
 }
 
 fn lookup_fn(vm: &VM, this: Ref<Obj>, key: value::Value) -> WaffleResult {
@@ -42,6 +44,7 @@ impl Function {
                 env: None,
                 native: true,
                 prototype: value::Value::undefined(),
+                parent: value::Value::undefined(),
                 name: WaffleString::new(heap, name),
                 native_code: fptr as _,
             });
@@ -65,6 +68,7 @@ impl Function {
                 prototype: value::Value::from(
                     RegularObj::new(heap, value::Value::undefined()).cast(),
                 ),
+                parent: value::Value::undefined(),
                 name: WaffleString::new(heap, name),
             });
         }
@@ -73,6 +77,14 @@ impl Function {
         }
     }
 
+    /// Instantiate closure of this function that captures `env` upvalue cells.
+    pub fn new_closure(&self, heap: &mut Heap, env: Ref<Array>) -> Ref<Self> {
+        let mut closure = Self::new(heap, self.code_block.unwrap(), self.name.str());
+        closure.module = self.module;
+        closure.env = Some(env);
+        closure
+    }
+
     pub fn execute(&self, this: value::Value, args: &[value::Value]) -> WaffleResult {
         let regc = if let Some(cb) = self.code_block {
             cb.num_vars
@@ -102,7 +114,7 @@ impl Function {
                 jit::operations::get_executable_address_for(callee)
             {
                 //vm.call_stack.push(cf);
-                let result = fun(cf);
+                let result = crate::interpreter::run_frame(fun, cf);
                 vm.pop_frame();
                 return result;
             } else {
@@ -113,6 +125,7 @@ impl Function {
 }
 
 pub static FUNCTION_VTBL: VTable = VTable {
+    name: "Function",
     element_size: 0,
     instance_size: std::mem::size_of::<Function>(),
     parent: None,
@@ -121,7 +134,7 @@ pub static FUNCTION_VTBL: VTable = VTable {
     calc_size_fn: None,
     apply_fn: None,
     destroy_fn: None,
-    trace_fn: None,
+    trace_fn: Some(trace),
     set_fn: None,
     set_index_fn: None,
 };
@@ -141,4 +154,7 @@ fn trace(this: Ref<Obj>, trace: &mut dyn FnMut(*const Ref<Obj>)) {
     if let Some(m) = &this.module {
         trace(unsafe { std::mem::transmute(m) });
     }
+    if this.parent.is_cell() {
+        trace(this.parent.as_cell_ref());
+    }
 }
@@ -1,6 +1,15 @@
 use crate::gc::*;
+use crate::interpreter::callframe::CallFrame;
 use crate::object::*;
+use crate::value::Value;
 pub mod block;
+pub mod large;
+pub mod mem;
+pub mod snapshot;
+pub mod space;
+pub mod stats;
+pub mod verify;
+pub mod weak;
 
 pub const SIZE_CLASS_1: usize = 32;
 pub const SIZE_CLASS_2: usize = 48;
@@ -10,18 +19,64 @@ pub const SIZE_CLASS_5: usize = 512;
 pub const SIZE_CLASS_6: usize = 1024;
 pub const LARGE_SIZE: usize = 7;
 pub const SIZE_CLASSES: usize = 6;
+/// Index of memory owned by objects outside of the heap in `HeapStats::allocated`/`freed`.
+pub const EXTERNAL: usize = SIZE_CLASSES + 1;
+/// Size of the young generation, reaching it requests a minor collection.
+pub const NURSERY_SIZE: usize = 512 * 1024;
+/// Default number of objects traced by one incremental marking step.
+pub const MARK_STEP_BUDGET: usize = 1024;
 
 pub struct Heap {
+    /// Young generation. Every cell is prefixed with its size so survivors can be copied out
+    /// and dead cells with destructors can be found.
+    pub young: space::Space,
     pub size_classes: [Vec<*mut block::HeapBlock>; SIZE_CLASSES],
-    pub start: *mut u8,
+    /// Index of the first block in each size class that may still have free cells.
+    current: [usize; SIZE_CLASSES],
+    /// Objects bigger than the largest size class.
+    pub large: large::LargeObjectSpace,
     pub allocated: usize,
     pub threshold: usize,
+    /// Old objects and code blocks that may point into the nursery. Minor collection scans
+    /// them instead of the whole old generation.
+    remembered: Vec<Address>,
+    /// Check before every minor collection that no old-to-young reference bypassed
+    /// `write_barrier`.
+    pub verify_barriers: bool,
+    /// Run a minor collection and an old generation step at every safepoint, makes missing
+    /// roots and trace edges crash early. Allocation only requests a collection like it
+    /// always does, runtime code keeps unrooted references between allocations.
+    pub gc_stress: bool,
+    /// Check every reachable reference before and after each collection.
+    pub verify_heap: bool,
+    /// Nursery ran out of its current page.
+    needs_minor: bool,
+    /// True while the old generation is being marked incrementally. Read by JIT code to
+    /// skip `satb_barrier`.
+    pub marking: bool,
+    /// Marked objects whose fields are not traced yet.
+    gray: Vec<Ref<Obj>>,
+    /// Code blocks are never swept, their marks are cleared by hand when marking finishes.
+    marked_code_blocks: Vec<Ref<Obj>>,
+    /// Large objects allocated while marking. Their headers are written after allocation
+    /// so they are marked only when the cycle finishes.
+    allocated_black: Vec<Address>,
+    /// Number of objects traced at each safepoint while marking.
+    pub mark_budget: usize,
+    pub stats: stats::HeapStats,
+    /// Weak containers found by the running minor collection.
+    young_weak: weak::Discovered,
+    /// Weak containers found by the running marking cycle.
+    old_weak: weak::Discovered,
+    finalizers: Vec<weak::Finalizer>,
+    /// Held values and callbacks of finalizers whose targets died.
+    pending_finalizers: Vec<(Value, weak::FinalizerCallback)>,
 }
 
 impl Heap {
-    pub fn new(stack_start: *const bool) -> Self {
+    pub fn new() -> Self {
         Self {
-            start: stack_start as *mut u8,
+            young: space::Space::new(NURSERY_SIZE),
             size_classes: [
                 Vec::new(),
                 Vec::new(),
@@ -30,8 +85,25 @@ impl Heap {
                 Vec::new(),
                 Vec::new(),
             ],
+            current: [0; SIZE_CLASSES],
+            large: large::LargeObjectSpace::new(),
             allocated: 0,
             threshold: 8 * 1024,
+            remembered: vec![],
+            verify_barriers: false,
+            gc_stress: false,
+            verify_heap: false,
+            needs_minor: false,
+            marking: false,
+            gray: vec![],
+            marked_code_blocks: vec![],
+            allocated_black: vec![],
+            mark_budget: MARK_STEP_BUDGET,
+            stats: stats::HeapStats::default(),
+            young_weak: Default::default(),
+            old_weak: Default::default(),
+            finalizers: vec![],
+            pending_finalizers: vec![],
         }
     }
     pub fn size_class_for(size: usize) -> usize {
@@ -56,229 +128,806 @@ impl Heap {
             _ => unreachable!(),
         }
     }
+    /// Allocate `size` bytes of GC memory. Collection never happens here, when the nursery
+    /// is full or old space reaches the threshold `stop_world` is set and the next `Safepoint`
+    /// collects. Objects that do not fit into a size class go straight to the large space.
     pub fn allocate(&mut self, size: usize) -> Address {
-        /*let sc = Self::size_class_for(size);
+        let size = mem::align_usize(size, 8);
+        if Self::size_class_for(size) == LARGE_SIZE {
+            return self.allocate_large(size);
+        }
+        let mut needs_gc = false;
+        let mem = self
+            .young
+            .fast_allocate(size + std::mem::size_of::<usize>(), &mut needs_gc);
+        if needs_gc || self.gc_stress {
+            self.needs_minor = true;
+            crate::get_vm().stop_world = true;
+        }
+        unsafe {
+            *mem.to_mut_ptr::<usize>() = size;
+        }
+        self.stats.young_allocated += size;
+        self.update_peak();
+        mem.offset(std::mem::size_of::<usize>())
+    }
+
+    /// Allocate `size` bytes in the old generation, used for promotion.
+    pub fn allocate_old(&mut self, size: usize) -> Address {
+        let sc = Self::size_class_for(size);
         if sc == LARGE_SIZE {
-            println!("{}", size);
-            todo!("Large classes is not yet supported")
+            return self.allocate_large(size);
         }
         self.allocated += Self::size_class_size_for(sc);
+        self.stats.allocated[sc] += Self::size_class_size_for(sc);
+        self.update_peak();
         if self.allocated >= self.threshold {
             crate::get_vm().stop_world = true;
         }
-        /*clog!(
+        clog!(
             crate::get_vm().verbose_alloc;
             "Allocate {} bytes in size class #{} (total {} allocated bytes)",
             size,
             sc,
             self.allocated
-        );*/
+        );
         unsafe {
-            for block in self.size_classes[sc].iter_mut() {
-                let mem = (&mut **block).allocate();
+            while self.current[sc] < self.size_classes[sc].len() {
+                let block = self.size_classes[sc][self.current[sc]];
+                let mem = (&mut *block).allocate();
                 if mem.is_non_null() {
                     return mem;
                 }
+                self.current[sc] += 1;
             }
             self.size_classes[sc].push(block::HeapBlock::new(Self::size_class_size_for(sc)));
             (&mut **self.size_classes[sc].last_mut().unwrap()).allocate()
-        }*/
-        unsafe { Address::from_ptr(libc::malloc(size)) }
-    }
-
-    fn get_heap_block(object: Address) -> *mut block::HeapBlock {
-        let off = object.to_usize() % block::HeapBlock::BLOCK_SIZE;
-        (object.to_usize() as isize + (-(off as isize))) as *mut _
-    }
-    #[inline(never)]
-    fn collect_roots(&mut self, sp: Address) -> Vec<Ref<Obj>> {
-        //clog!(crate::get_vm().verbose_alloc;"GC Started");
-        let sp = Address::from_ptr(&sp);
-        let filter = |frame_addr: Address| unsafe {
-            if frame_addr.is_non_null() {
-                let value_pointer = *frame_addr.to_mut_ptr::<*mut u8>();
-                if value_pointer.is_null() {
-                    return false;
-                }
-                let block = Self::get_heap_block(Address::from_ptr(value_pointer));
-                for sc in self.size_classes.iter() {
-                    if sc.iter().any(|b| {
-                        let bptr = &**b as *const block::HeapBlock;
-                        bptr == block
-                    }) {
-                        return true;
+        }
+    }
+
+    fn allocate_large(&mut self, size: usize) -> Address {
+        let mem = self.large.allocate(size);
+        if mem.is_null() {
+            panic!("out of memory: cannot allocate large object of {} bytes", size);
+        }
+        self.allocated += self.large.size_of(mem).unwrap();
+        self.stats.allocated[SIZE_CLASSES] += self.large.size_of(mem).unwrap();
+        self.update_peak();
+        // large objects are born old and are initialized without barriers.
+        self.remembered.push(mem);
+        if self.marking {
+            self.allocated_black.push(mem);
+        }
+        if self.allocated >= self.threshold {
+            crate::get_vm().stop_world = true;
+        }
+        mem
+    }
+
+    /// Count `bytes` of memory an object allocated outside of the heap, e.g. the backing
+    /// store of an array. It is part of `allocated` and moves the threshold like old cells.
+    pub fn report_external_allocation(&mut self, bytes: usize) {
+        self.allocated += bytes;
+        self.stats.allocated[EXTERNAL] += bytes;
+        self.update_peak();
+        if self.allocated >= self.threshold {
+            crate::get_vm().stop_world = true;
+        }
+    }
+
+    /// Counterpart of `report_external_allocation` for memory that was released.
+    pub fn report_external_free(&mut self, bytes: usize) {
+        self.allocated -= bytes;
+        self.stats.freed[EXTERNAL] += bytes;
+    }
+
+    fn update_peak(&mut self) {
+        let size = self.allocated + self.young.allocated_size;
+        if size > self.stats.peak {
+            self.stats.peak = size;
+        }
+    }
+
+    /// Call `callback` with every nursery cell and its size. Cells that died since the last
+    /// collection are visited too.
+    pub fn for_each_young_cell(&self, mut callback: impl FnMut(Ref<Obj>, usize)) {
+        for page in self.young.pages.iter() {
+            let mut cursor = page.data;
+            while cursor < page.top {
+                let size = unsafe { *cursor.to_ptr::<usize>() };
+                callback(cursor.offset(std::mem::size_of::<usize>()).into(), size);
+                cursor = cursor.offset(std::mem::size_of::<usize>() + size);
+            }
+        }
+    }
+
+    /// Call `callback` with every old generation cell and its size.
+    pub fn for_each_old_cell(&self, mut callback: impl FnMut(Ref<Obj>, usize)) {
+        for (sc, blocks) in self.size_classes.iter().enumerate() {
+            let size = Self::size_class_size_for(sc);
+            for block in blocks.iter() {
+                let block = unsafe { &**block };
+                block.for_each_cell(|cell| {
+                    if block.is_marked(cell) {
+                        callback(cell.into(), size);
                     }
-                }
-                false
-            } else {
-                false
+                });
             }
+        }
+        self.large
+            .for_each_object(|addr, size| callback(addr.into(), size));
+    }
+
+    /// Count cells of every type in both generations.
+    pub fn live_objects(&self) -> std::collections::BTreeMap<&'static str, stats::TypeStats> {
+        let mut live = std::collections::BTreeMap::new();
+        let mut count = |cell: Ref<Obj>, size: usize| {
+            let entry: &mut stats::TypeStats = live.entry(cell.vtable.name).or_default();
+            entry.count += 1;
+            entry.bytes += size;
         };
-        let vm = crate::get_vm();
-        let mut mark_stack: Vec<Ref<Obj>> = vec![];
-        mark_stack.push(vm.constructor.as_cell());
-        mark_stack.push(vm.length.as_cell());
-        mark_stack.push(vm.prototype.as_cell());
-        for (_, g) in vm.globals.map.iter() {
-            if g.is_cell() {
-                if g.as_cell().header().is_marked_non_atomic() == false {
-                    g.as_cell().header_mut().mark_non_atomic();
-                    mark_stack.push(g.as_cell());
-                }
-            }
+        self.for_each_young_cell(&mut count);
+        self.for_each_old_cell(&mut count);
+        live
+    }
+
+    /// Size of memory occupied by `cell`, including the backing store of arrays.
+    pub fn cell_size(&self, cell: Ref<Obj>) -> usize {
+        let external = if cell.vtable as *const _ == &crate::builtins::ARRAY_VTBL as *const _ {
+            cell.cast::<Array>().capacity() * std::mem::size_of::<Value>()
+        } else {
+            0
+        };
+        let addr = cell.address();
+        if self.young.contains(addr) {
+            return external + unsafe { *addr.sub(std::mem::size_of::<usize>()).to_ptr::<usize>() };
+        }
+        if let Some(size) = self.large.size_of(addr) {
+            return external + size;
+        }
+        if cell.vtable as *const _ == &crate::bytecode::CB_VTBL as *const _ {
+            return std::mem::size_of::<crate::bytecode::CodeBlock>();
         }
-        // conservative roots
+        let block =
+            (addr.to_usize() & !(block::HeapBlock::BLOCK_SIZE - 1)) as *const block::HeapBlock;
+        external + unsafe { (*block).cell_size() }
+    }
+
+    /// Write heap snapshot of all reachable cells to `path`, see `snapshot` for the format.
+    pub fn write_snapshot(&self, path: &str) -> std::io::Result<()> {
+        let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+        snapshot::write_snapshot(self, &mut out)
+    }
+
+    pub fn dump_stats(&self, f: &mut dyn std::fmt::Write) -> std::fmt::Result {
+        self.stats.dump(&self.live_objects(), f)
+    }
+
+    /// Must be called after storing `value` into a field of `object`. Records old objects
+    /// that start pointing into the nursery.
+    #[inline]
+    pub fn write_barrier(&mut self, object: Ref<Obj>, value: Value) {
+        if !value.is_cell() || value.is_empty() || !self.young.contains(value.as_cell().address())
         {
-            let mut start = sp;
-            let mut end = Address::from_ptr(self.start);
-            if start > end {
-                // if stack grows from bottom to top then swap
-                std::mem::swap(&mut start, &mut end);
+            return;
+        }
+        self.remember(object);
+    }
+
+    /// Add `object` to the remembered set unconditionally, used when many fields are
+    /// written at once (e.g. constants of a code block).
+    pub fn remember(&mut self, mut object: Ref<Obj>) {
+        if self.young.contains(object.address()) {
+            return;
+        }
+        if object.header_mut().try_remember() {
+            self.remembered.push(object.address());
+        }
+    }
+
+    /// Must be called before a field holding `old` is overwritten. While marking, values
+    /// removed from the heap are shaded so everything reachable when marking started
+    /// survives the cycle (snapshot-at-the-beginning).
+    #[inline]
+    pub fn satb_barrier(&mut self, old: Value) {
+        if self.marking && old.is_cell() && !old.is_empty() {
+            self.shade(old.as_cell());
+        }
+    }
+
+    /// Entered from `Safepoint` when `stop_world` is set. Evacuates the nursery if it is
+    /// full and does a bounded amount of old generation marking, the cycle starts once old
+    /// space reaches the threshold and sweeps when nothing gray is left.
+    pub fn collect(&mut self) {
+        let start = std::time::Instant::now();
+        if self.verify_heap {
+            self.verify("before collection");
+        }
+        if self.needs_minor || self.gc_stress {
+            self.minor_collect();
+        }
+        if self.marking {
+            self.mark_step();
+        } else if self.allocated >= self.threshold || self.gc_stress {
+            // the snapshot is taken with an empty nursery so it contains old objects only.
+            if self.young.allocated_size != 0 {
+                self.minor_collect();
             }
-            if start.to_usize() % 8 != 0 {
-                start = Address::from(start.to_usize() + 8 - start.to_usize() % 8);
+            self.start_marking();
+        }
+        if self.verify_heap {
+            self.verify("after collection");
+        }
+        // keep entering safepoints until marking is done.
+        crate::get_vm().stop_world = self.marking || self.gc_stress;
+        self.stats.pauses.record(start.elapsed());
+        crate::jit::free_jettisoned_code();
+        self.run_finalizers();
+    }
+
+    /// Copy live young objects into the old generation. Old objects that point into the
+    /// nursery are found in the remembered set.
+    fn minor_collect(&mut self) {
+        log!(
+            "start minor gc after {} young bytes",
+            self.young.allocated_size
+        );
+        self.needs_minor = false;
+        self.stats.minor_collections += 1;
+        if self.verify_barriers {
+            self.verify_remembered();
+        }
+        let mut gray: Vec<Ref<Obj>> = vec![];
+        let mut code_blocks = std::collections::HashSet::new();
+        visit_roots(&mut |_, slot| unsafe {
+            if slot.is_cell() && !slot.is_empty() {
+                self.evacuate(
+                    slot as *mut Value as *mut Ref<Obj>,
+                    &mut gray,
+                    &mut code_blocks,
+                );
             }
-            if end.to_usize() % 8 != 0 {
-                end = Address::from(end.to_usize() + 8 - end.to_usize() % 8);
+        });
+        unsafe {
+            for cell in std::mem::take(&mut self.remembered) {
+                let mut cell: Ref<Obj> = cell.into();
+                cell.header_mut().forget();
+                self.trace_young(cell, &mut gray, &mut code_blocks);
             }
-            /*clog!(
-                crate::get_vm().verbose_alloc;
-                "Scan for conservative roots in range {:p}..{:p}",
-                start.to_ptr::<u8>(),
-                end.to_ptr::<u8>()
-            );*/
-            while start < end {
-                unsafe {
-                    let frame = start;
-                    if filter(frame) {
-                        let block =
-                            Self::get_heap_block(Address::from_ptr(*frame.to_mut_ptr::<*mut u8>()));
-                        if (&*block).is_marked(*start.to_mut_ptr::<Address>()) {
-                            let mut cell: Ref<Obj> = Ref {
-                                ptr: std::ptr::NonNull::new_unchecked(
-                                    (*start.to_mut_ptr::<Address>()).to_mut_ptr(),
-                                ),
-                            };
-                            if cell.header().is_marked_non_atomic() {
-                                start = start.add_ptr(1);
-                                continue;
-                            }
-                            log!(
-                                //  crate::get_vm().verbose_alloc;
-                                "Found GC pointer {:p} at {:p}",
-                                (*start.to_mut_ptr::<Address>()).to_ptr::<u8>(),
-                                start.to_ptr::<u8>(),
-                            );
-                            cell.header_mut().mark_non_atomic();
-                            mark_stack.push(*start.to_mut_ptr::<Ref<Obj>>());
-                            start = start.add_ptr(1);
-                            continue;
-                        }
-                    }
-                }
-                start = start.add_ptr(1);
+            while let Some(cell) = gray.pop() {
+                self.trace_young(cell, &mut gray, &mut code_blocks);
             }
+            self.evacuate_ephemerons(&mut gray, &mut code_blocks);
         }
-        // precise roots
-        {
-            let vm = crate::get_vm();
-            let mut frame = vm.top_call_frame;
-            while !frame.is_null() {
-                let f = unsafe { &mut *frame };
-                for reg in f.regs.iter() {
-                    if reg.is_cell() {
-                        if !reg.as_cell().header().is_marked_non_atomic() {
-                            mark_stack.push(reg.as_cell());
-                        }
-                    }
+        self.sweep_weak_young();
+        // run destructors of dead young objects.
+        self.for_each_young_cell(|cell, _| {
+            if cell.header().fwdptr_non_atomic().is_null() {
+                if let Some(destroy_fn) = cell.vtable.destroy_fn {
+                    destroy_fn(cell);
                 }
+            }
+        });
+        self.young.reset();
+        // everything reachable from the nursery was promoted, nothing old points into it.
+        for cell in std::mem::take(&mut self.remembered) {
+            let mut cell: Ref<Obj> = cell.into();
+            cell.header_mut().forget();
+        }
+        log!("minor gc done, {} bytes in old space", self.allocated);
+    }
 
-                for i in 0..f.argc {
-                    let value = f.args.offset(i as _);
-                    if value.is_cell() {
-                        if !value.as_cell().header().is_marked_non_atomic() {
-                            value.as_cell().header_mut().mark_non_atomic();
-                            mark_stack.push(value.as_cell());
-                        }
-                    }
-                }
-                if f.this.is_cell() {
-                    if !f.this.as_cell().header().is_marked_non_atomic() {
-                        f.this.as_cell().header_mut().mark_non_atomic();
-                        mark_stack.push(f.this.as_cell());
+    /// Evacuate young objects referenced by `cell`.
+    unsafe fn trace_young(
+        &mut self,
+        cell: Ref<Obj>,
+        gray: &mut Vec<Ref<Obj>>,
+        code_blocks: &mut std::collections::HashSet<Address>,
+    ) {
+        self.young_weak.discover(cell);
+        if let Some(trace) = cell.vtable.trace_fn {
+            trace(cell, &mut |slot| {
+                self.evacuate(slot as *mut Ref<Obj>, gray, code_blocks);
+            });
+        }
+    }
+
+    /// Move young object referenced from `slot` into the old generation and update the slot.
+    /// Code blocks are not in the heap but may refer to young constants, they are scanned
+    /// once per cycle.
+    unsafe fn evacuate(
+        &mut self,
+        slot: *mut Ref<Obj>,
+        gray: &mut Vec<Ref<Obj>>,
+        code_blocks: &mut std::collections::HashSet<Address>,
+    ) {
+        if *(slot as *const usize) == 0 {
+            // empty value
+            return;
+        }
+        let mut cell = *slot;
+        let addr = cell.address();
+        if !self.young.contains(addr) {
+            if cell.vtable as *const _ == &crate::bytecode::CB_VTBL as *const _
+                && code_blocks.insert(addr)
+            {
+                gray.push(cell);
+            }
+            return;
+        }
+        let forwarded = cell.header().fwdptr_non_atomic();
+        if forwarded.is_non_null() {
+            *slot = forwarded.into();
+            return;
+        }
+        let size = *addr.sub(std::mem::size_of::<usize>()).to_ptr::<usize>();
+        let new_addr = self.allocate_old(size);
+        std::ptr::copy_nonoverlapping(addr.to_ptr::<u8>(), new_addr.to_mut_ptr::<u8>(), size);
+        cell.header_mut().set_fwdptr_non_atomic(new_addr);
+        let mut new_cell: Ref<Obj> = new_addr.into();
+        if self.marking {
+            // promoted objects are allocated black.
+            new_cell.header_mut().mark_non_atomic();
+        }
+        *slot = new_cell;
+        gray.push(new_cell);
+    }
+
+    /// Panic if an old object points into the nursery without being remembered. Code blocks
+    /// are not in the heap and cannot be enumerated, they are not checked.
+    fn verify_remembered(&self) {
+        let remembered = self
+            .remembered
+            .iter()
+            .copied()
+            .collect::<std::collections::HashSet<_>>();
+        self.for_each_old_cell(|cell, _| {
+            let addr = cell.address();
+            if remembered.contains(&addr) {
+                return;
+            }
+            if let Some(trace) = cell.vtable.trace_fn {
+                trace(cell, &mut |slot| unsafe {
+                    let value = *(slot as *const usize);
+                    if value != 0 && self.young.contains(Address::from(value)) {
+                        panic!(
+                            "missed write barrier: old object {:p} points to young object {:p}",
+                            addr.to_ptr::<u8>(),
+                            value as *const u8
+                        );
                     }
-                }
-                if f.callee.is_cell() {
-                    if !f.callee.as_cell().header().is_marked_non_atomic() {
-                        f.callee.as_cell().header_mut().mark_non_atomic();
-                        mark_stack.push(f.callee.as_cell());
+                });
+            }
+        });
+    }
+
+    /// Mark `cell` and queue it for tracing. Young objects are not part of the snapshot.
+    fn shade(&mut self, mut cell: Ref<Obj>) {
+        if self.young.contains(cell.address()) || cell.header().is_marked_non_atomic() {
+            return;
+        }
+        cell.header_mut().mark_non_atomic();
+        if cell.vtable as *const _ == &crate::bytecode::CB_VTBL as *const _ {
+            self.marked_code_blocks.push(cell);
+        }
+        self.gray.push(cell);
+    }
+
+    fn start_marking(&mut self) {
+        log!(
+            "start incremental marking after {} allocated bytes",
+            self.allocated
+        );
+        self.marking = true;
+        visit_roots(&mut |_, slot| {
+            if slot.is_cell() && !slot.is_empty() {
+                self.shade(slot.as_cell());
+            }
+        });
+    }
+
+    /// Trace at most `mark_budget` gray objects, sweep once marking is complete.
+    fn mark_step(&mut self) {
+        for _ in 0..self.mark_budget {
+            let cell = match self.gray.pop() {
+                Some(cell) => cell,
+                None => break,
+            };
+            self.old_weak.discover(cell);
+            if let Some(trace) = cell.vtable.trace_fn {
+                trace(cell, &mut |slot| unsafe {
+                    if *(slot as *const usize) != 0 {
+                        self.shade(*slot);
                     }
-                }
-                if let Some(mut cb) = f.code_block {
-                    cb.header.mark_non_atomic();
-                    mark_stack.push(cb.cast());
-                }
-                frame = f.caller;
+                });
             }
         }
-        mark_stack
+        if self.gray.is_empty() {
+            self.mark_ephemerons();
+        }
+        if self.gray.is_empty() {
+            self.finish_marking();
+        }
     }
-    pub fn collect(&mut self, sp: Address) {
-        return;
-        log!("start gc after {} allocated bytes ", self.allocated);
-        let mut mark_stack = self.collect_roots(sp);
-        // marking
-        {
-            while let Some(cell_addr) = mark_stack.pop() {
-                let mut cell: Ref<Obj> = cell_addr;
-                /*log!(
-                    "Trace '{}' at {:p}",
-                    crate::runtime::val_str(crate::value::Value::from(cell)),
-                    cell.ptr.as_ptr()
-                );*/
-
-                if let Some(trace) = cell.vtable.trace_fn {
-                    trace(cell, &mut |mut object| unsafe {
-                        let object = object as *mut Ref<Obj>;
-                        if (*object).header().is_marked_non_atomic() {
-                            return;
-                        }
-                        (*object).header_mut().mark_non_atomic();
-                        mark_stack.push(*object);
-                    });
+
+    /// Sweep the old generation. Objects unreachable at the start of marking and not
+    /// allocated since then are unmarked.
+    fn finish_marking(&mut self) {
+        for addr in std::mem::take(&mut self.allocated_black) {
+            let mut cell: Ref<Obj> = addr.into();
+            cell.header_mut().mark_non_atomic();
+        }
+        self.sweep_weak_old();
+        // the nursery may be empty when marking starts, so objects remembered since the last
+        // minor collection can die here. Code blocks are not swept.
+        self.remembered.retain(|addr| {
+            let cell: Ref<Obj> = (*addr).into();
+            cell.header().is_marked_non_atomic()
+                || cell.vtable as *const _ == &crate::bytecode::CB_VTBL as *const _
+        });
+        let mut freed = 0;
+        unsafe {
+            for (class, sc) in self.size_classes.iter_mut().enumerate() {
+                let before = freed;
+                let mut i = 0;
+                while i < sc.len() {
+                    // keep at least one block per size class around.
+                    if (&mut *sc[i]).sweep(&mut freed) && sc.len() > 1 {
+                        let block = sc.swap_remove(i);
+                        std::ptr::drop_in_place(block);
+                        std::alloc::dealloc(
+                            block.cast(),
+                            std::alloc::Layout::from_size_align_unchecked(
+                                block::HeapBlock::BLOCK_SIZE,
+                                block::HeapBlock::BLOCK_SIZE,
+                            ),
+                        );
+                        continue;
+                    }
+                    i += 1;
                 }
+                self.stats.freed[class] += freed - before;
             }
+            let before = freed;
+            self.large.sweep(&mut freed);
+            self.stats.freed[SIZE_CLASSES] += freed - before;
+        }
+        for mut cb in std::mem::take(&mut self.marked_code_blocks) {
+            cb.header_mut().unmark_non_atomic();
         }
+        self.marking = false;
+        self.stats.major_collections += 1;
+        self.current = [0; SIZE_CLASSES];
+        self.allocated -= freed;
+        log!(
+            "gc freed {} bytes, {} bytes still allocated",
+            freed,
+            self.allocated
+        );
+        if self.allocated >= (self.threshold as f64 * 0.7) as usize {
+            self.threshold = (self.allocated as f64 / 0.7) as usize;
+        }
+    }
+}
 
-        // sweeping
-        unsafe {
-            for sc in self.size_classes.iter_mut() {
-                let len = sc.len();
-                let mut del = 0;
-                {
-                    for i in 0..len {
-                        /*if (&mut *sc[i]).sweep() {
-                            del += 1;
-                            std::ptr::drop_in_place(sc[i]);
-                            std::alloc::dealloc(
-                                sc[i].cast(),
-                                std::alloc::Layout::from_size_align_unchecked(16 * 1024, 16 * 1024),
-                            );
-                            log!("RIP {:p}", sc[i]);
-                        } else if del > 0 {
-                            sc.swap(i - del, i);
-                        }*/
-                        //(&mut *sc[i]).sweep();
+/// Where a root slot lives, used to name roots in heap snapshots.
+#[derive(Clone, Copy)]
+enum Root<'a> {
+    Vm(&'static str),
+    Global(&'a str),
+    /// Held value or callback of a finalizer.
+    Finalizer,
+    /// Slot of the n-th call frame counting from the top.
+    Frame(usize),
+}
+
+impl std::fmt::Display for Root<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Root::Vm(name) => write!(f, "vm.{}", name),
+            Root::Global(name) => write!(f, "global {}", name),
+            Root::Finalizer => write!(f, "finalizer"),
+            Root::Frame(depth) => write!(f, "frame #{}", depth),
+        }
+    }
+}
+
+/// Visit every root slot: well-known VM values, globals and registers of all call frames.
+fn visit_roots(visit: &mut dyn FnMut(Root, &mut Value)) {
+    let vm = crate::get_vm();
+    visit(Root::Vm("constructor"), &mut vm.constructor);
+    visit(Root::Vm("length"), &mut vm.length);
+    visit(Root::Vm("prototype"), &mut vm.prototype);
+    visit(Root::Vm("empty_string"), &mut vm.empty_string);
+    visit(Root::Vm("not_a_func_exc"), &mut vm.not_a_func_exc);
+    visit(Root::Vm("exception"), &mut vm.exception);
+    visit(Root::Vm("array_prototype"), &mut vm.array_prototype);
+    for (name, g) in vm.globals.map.iter_mut() {
+        visit(Root::Global(name), &mut g.value);
+    }
+    let finalizers = vm.heap.finalizers.iter_mut().map(|f| (&mut f.held, &mut f.callback));
+    let pending = vm
+        .heap
+        .pending_finalizers
+        .iter_mut()
+        .map(|(held, callback)| (held, callback));
+    for (held, callback) in finalizers.chain(pending) {
+        visit(Root::Finalizer, held);
+        if let weak::FinalizerCallback::Script(callback) = callback {
+            visit(Root::Finalizer, callback);
+        }
+    }
+    let mut frame = vm.top_call_frame;
+    let mut depth = 0;
+    while !frame.is_null() {
+        let root = Root::Frame(depth);
+        let f = unsafe { &mut *frame };
+        let live = if f.jit_pc != CallFrame::NO_JIT_PC {
+            f.code_block
+                .and_then(|cb| cb.jit_data().stack_map.live_at(f.jit_pc as u32).cloned())
+        } else {
+            None
+        };
+        match live {
+            // JIT frame, only registers from the stack map are live. Dead ones are
+            // cleared so that stale pointers never become visible to later cycles.
+            Some(live) => {
+                for (i, reg) in f.regs.iter_mut().enumerate() {
+                    if live.contains(i) {
+                        visit(root, reg);
+                    } else {
+                        *reg = Value::undefined();
                     }
                 }
-                if del > 0 {
-                    sc.truncate(len - del);
+            }
+            None => {
+                for reg in f.regs.iter_mut() {
+                    visit(root, reg);
                 }
             }
         }
-        if self.allocated >= self.threshold {
-            self.threshold = (self.allocated as f64 / 0.7) as usize;
+        for i in 0..f.argc {
+            visit(root, unsafe { &mut *f.args.offset(i as _).ptr.as_ptr() });
+        }
+        visit(root, &mut f.this);
+        visit(root, &mut f.callee);
+        if let Some(cb) = f.code_block {
+            visit(root, &mut Value::from(cb.cast::<Obj>()));
+        }
+        frame = f.caller;
+        depth += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::object::*;
+    use crate::testing::*;
+    use crate::value::Value;
+    use crate::*;
+
+    /// Run a minor collection and a whole marking cycle.
+    fn full_collection() {
+        let heap = &mut get_vm().heap;
+        heap.gc_stress = true;
+        heap.collect();
+        while heap.marking {
+            heap.collect();
+        }
+        heap.gc_stress = false;
+    }
+
+    #[test]
+    fn test_gc_stress() {
+        let _vm = lock();
+        let vm = get_vm();
+        vm.heap.gc_stress = true;
+        vm.heap.verify_heap = true;
+        vm.stop_world = true;
+        let src = "let xs = []\nlet i = 0\n\
+                   while i < 50 { xs.push(new { i: i, a: [i, (i, i)] })\ni = i + 1 }\n\
+                   let s = 0\nfor x in xs { s = s + x.i + x.a[0] }\ns";
+        assert_eq!(run_both(src), "2450");
+    }
+
+    #[test]
+    fn test_new_object_moved_by_constructor_safepoint() {
+        let _vm = lock();
+        let vm = get_vm();
+        vm.heap.gc_stress = true;
+        vm.stop_world = true;
+        // the safepoint at the end of `P` evacuates the new object from the nursery.
+        let src = "function P(x) { let junk = [x, [x], (x, x)]\nthis.x = x\nthis.junk = junk }\n\
+                   let s = 0\nlet i = 0\n\
+                   while i < 20 { let p = new P(i)\ns = s + p.x + p.junk[0]\ni = i + 1 }\ns";
+        assert_eq!(run_both(src), "380");
+    }
+
+    #[test]
+    fn test_large_objects_are_collected() {
+        let _vm = lock();
+        let vm = get_vm();
+        let young = Value::from(WaffleString::new(&mut vm.heap, "largeTestElement").cast());
+        let kept = Tuple::new(&mut vm.heap, &[young; 200]);
+        vm.globals.insert("largeTestKept", Value::from(kept.cast()));
+        let dropped = Tuple::new(&mut vm.heap, &[Value::new_int(1); 200]).address();
+        assert!(vm.heap.large.contains(kept.address()));
+        assert!(vm.heap.large.contains(dropped));
+        full_collection();
+        assert!(vm.heap.large.contains(kept.address()));
+        assert!(!vm.heap.large.contains(dropped));
+        // the element was promoted through the large object that is remembered at birth.
+        let element = kept.get_at(199);
+        assert!(!vm.heap.young.contains(element.as_cell().address()));
+        assert_eq!(runtime::val_str(element), "largeTestElement");
+        vm.globals.insert("largeTestKept", Value::undefined());
+    }
+
+    #[test]
+    fn test_dead_remembered_objects_are_forgotten() {
+        let _vm = lock();
+        let vm = get_vm();
+        while vm.heap.marking {
+            vm.heap.collect();
+        }
+        vm.heap.minor_collect();
+        // born remembered, dead before the next minor collection.
+        let dropped = Tuple::new(&mut vm.heap, &[Value::new_int(1); 200]).address();
+        assert!(vm.heap.remembered.contains(&dropped));
+        // marking starts with an empty nursery, so no minor collection runs first.
+        vm.heap.threshold = 0;
+        vm.heap.collect();
+        while vm.heap.marking {
+            vm.heap.collect();
+        }
+        assert!(!vm.heap.large.contains(dropped));
+        assert!(!vm.heap.remembered.contains(&dropped));
+        let young = WaffleString::new(&mut vm.heap, "rememberedTestElement");
+        vm.globals
+            .insert("rememberedTestKept", Value::from(young.cast()));
+        vm.heap.minor_collect();
+        assert_eq!(run("rememberedTestKept", false), "rememberedTestElement");
+        vm.globals.insert("rememberedTestKept", Value::undefined());
+    }
+
+    #[test]
+    fn test_write_barriers() {
+        let _vm = lock();
+        let vm = get_vm();
+        // every safepoint promotes the nursery, so the stores below write young values
+        // into old objects.
+        vm.heap.verify_barriers = true;
+        vm.heap.gc_stress = true;
+        vm.stop_world = true;
+        let src = "let o = new { v: nil }\nlet a = [nil]\nlet c = nil\nlet set = |x| c = x\n\
+                   function store(x) { barrierTestVar = x }\nlet i = 0\n\
+                   while i < 20 { o.v = (i, i)\na[0] = [i]\na.push(new { i: i })\n\
+                   set([i, i])\nstore((i, i))\ni = i + 1 }\n\
+                   o.v[0] + a[0][0] + a[20].i + c[1] + barrierTestVar[0]";
+        for jit in [false, true].iter() {
+            let result = run_in_module(src, *jit, |m| {
+                m.scope
+                    .insert("barrierTestVar".to_owned(), Value::undefined());
+            });
+            assert_eq!(result, "95");
+        }
+        vm.heap.verify_barriers = false;
+    }
+
+    #[test]
+    fn test_incremental_marking_keeps_snapshot() {
+        let _vm = lock();
+        let vm = get_vm();
+        vm.globals.insert("satbHolder", Value::undefined());
+        vm.globals.insert("satbKept", Value::undefined());
+        let src = "satbHolder = new { child: new { x: 42 } }\n0";
+        assert_eq!(run(src, false), "0");
+        full_collection();
+        let major = vm.heap.stats.major_collections;
+        let pauses = vm.heap.stats.pauses.count;
+        vm.heap.mark_budget = 1;
+        vm.heap.gc_stress = true;
+        vm.heap.collect();
+        vm.heap.gc_stress = false;
+        assert!(vm.heap.marking);
+        // move the only reference to the child into a root that was already scanned.
+        let holder = vm.globals.lookup("satbHolder").unwrap();
+        let mut holder = holder.as_cell().cast::<RegularObj>();
+        let child = holder.table.get("child").unwrap();
+        vm.heap.satb_barrier(child);
+        holder.table.set("child", Value::null());
+        vm.globals.insert("satbKept", child);
+        vm.heap.verify_heap = true;
+        while vm.heap.marking {
+            vm.heap.collect();
+        }
+        assert_eq!(vm.heap.stats.major_collections, major + 1);
+        assert!(vm.heap.stats.pauses.count > pauses + 1);
+        vm.heap.mark_budget = heap::MARK_STEP_BUDGET;
+        assert_eq!(run("satbKept.x", false), "42");
+        vm.globals.insert("satbHolder", Value::undefined());
+        vm.globals.insert("satbKept", Value::undefined());
+    }
+
+    #[test]
+    fn test_gc_stats() {
+        let _vm = lock();
+        let vm = get_vm();
+        let minor = vm.heap.stats.minor_collections;
+        let major = vm.heap.stats.major_collections;
+        full_collection();
+        assert!(vm.heap.stats.minor_collections > minor);
+        assert!(vm.heap.stats.major_collections > major);
+        let src = "let s = gcStats()\n(s.minorCollections > 0, s.majorCollections > 0, \
+                   s.pauseCount > 0, s.peak >= s.allocated, s.objects.Function > 0)";
+        assert_eq!(run_both(src), "(true,true,true,true,true)");
+        let mut report = String::new();
+        vm.heap.dump_stats(&mut report).unwrap();
+        assert!(report.starts_with("GC statistics:"));
+    }
+
+    #[test]
+    fn test_array_storage_is_accounted() {
+        let _vm = lock();
+        let vm = get_vm();
+        full_collection();
+        let allocated = vm.heap.allocated;
+        let freed = vm.heap.stats.freed[heap::EXTERNAL];
+        let storage = 1000 * std::mem::size_of::<Value>();
+        let array = Array::new(&mut vm.heap, 1000, Value::new_int(0));
+        assert_eq!(vm.heap.allocated, allocated + storage);
+        assert_eq!(
+            vm.heap.cell_size(array.cast()),
+            std::mem::size_of::<Array>() + storage
+        );
+        // the array is not rooted, the minor collection frees its backing store.
+        full_collection();
+        assert_eq!(vm.heap.stats.freed[heap::EXTERNAL], freed + storage);
+    }
+
+    #[test]
+    fn test_weak_references_and_finalizers() {
+        let _vm = lock();
+        let vm = get_vm();
+        let names = [
+            "weakTestKept",
+            "weakTestTemp",
+            "weakTestKeptRef",
+            "weakTestDeadRef",
+            "weakTestValueRef",
+            "weakTestMap",
+            "weakTestFinalized",
+        ];
+        for name in names.iter() {
+            vm.globals.insert(name, Value::undefined());
+        }
+        let src = "function setup() { let dead = new { d: 2 }\nlet value = new { v: 4 }\n\
+                   weakTestKept = new { k: 1 }\nweakTestTemp = dead\n\
+                   weakTestDeadRef = weakRef(dead)\nweakTestKeptRef = weakRef(weakTestKept)\n\
+                   weakTestValueRef = weakRef(value)\nweakTestMap = weakMap()\n\
+                   weakTestMap[weakTestKept] = new { v: 3 }\nweakTestMap[dead] = value\n\
+                   registerFinalizer(dead, |h| weakTestFinalized = h, 7)\n\
+                   registerFinalizer(weakTestKept, |h| weakTestFinalized = h, 8) }\nsetup()\n0";
+        assert_eq!(run(src, false), "0");
+        let finalized = std::rc::Rc::new(std::cell::Cell::new(0));
+        let target = WaffleString::new(&mut vm.heap, "weakTestTarget");
+        let cell = finalized.clone();
+        vm.heap.register_finalizer(
+            target.cast(),
+            Value::new_int(5),
+            heap::weak::FinalizerCallback::Native(Box::new(move |held| cell.set(held.to_int32()))),
+        );
+        // promote everything, then let `dead` die in the old generation.
+        full_collection();
+        assert_eq!(finalized.get(), 5);
+        assert_eq!(run("weakTestDeadRef.target.d", false), "2");
+        vm.globals.insert("weakTestTemp", Value::undefined());
+        full_collection();
+        let src = "(weakTestDeadRef.target, weakTestKeptRef.target == weakTestKept, \
+                   weakTestMap[weakTestKept].v, weakTestValueRef.target, weakTestFinalized)";
+        assert_eq!(run(src, false), "(undefined,true,3,undefined,7)");
+        for name in names.iter() {
+            vm.globals.insert(name, Value::undefined());
         }
-        crate::get_vm().stop_world = false;
     }
 }
@@ -1,6 +1,10 @@
 use super::*;
 use crate::gc::Address;
 use std::collections::HashSet;
+/// Block of `BLOCK_SIZE` bytes aligned to its size, split into cells of one size class.
+/// Cells are bump allocated until the block is swept for the first time, after that
+/// allocation goes through the free list.
+#[repr(C)]
 pub struct HeapBlock {
     cell_size: usize,
     free_list: *mut FreeListEntry,
@@ -12,54 +16,37 @@ pub struct HeapBlock {
 }
 
 impl HeapBlock {
-    pub fn sweep(&mut self) -> bool {
+    /// Release cells whose header is not marked and rebuild the free list, size of released
+    /// cells is added to `freed`. Returns true when no live cell is left in the block.
+    pub fn sweep(&mut self, freed: &mut usize) -> bool {
         let mut all_free = true;
         let mut free_list: *mut FreeListEntry = std::ptr::null_mut();
-        let mut seen = HashSet::new();
         self.for_each_cell_mut(|this, cell_addr| unsafe {
             if this.is_marked(cell_addr) {
                 let mut cell = Ref {
                     ptr: std::ptr::NonNull::new_unchecked(cell_addr.to_mut_ptr::<Obj>()),
                 };
-                if seen.contains(&(cell.ptr.as_ptr() as usize)) {
-                    panic!();
-                }
-                seen.insert(cell.ptr.as_ptr() as usize);
-                if cell.header().is_marked_non_atomic()
-                    || cell.vtable as *const _ == &crate::bytecode::CB_VTBL as *const _
-                {
-                    //let c = crate::runtime::val_str(crate::value::Value::from(cell));
-                    //log!("Keep '{}' at {:p}", c, cell.ptr);
+                if cell.header().is_marked_non_atomic() {
                     cell.header_mut().unmark_non_atomic();
                     all_free = false;
-                } else {
-                    cell.header_mut().unmark_non_atomic();
-                    this.unmark(cell_addr);
-                    let c = crate::runtime::val_str(crate::value::Value::from(cell));
-                    if let Some(destroy_fn) = cell.vtable.destroy_fn {
-                        destroy_fn(cell);
-                    }
-                    std::ptr::write_bytes(cell_addr.to_mut_ptr::<u8>(), 0, this.cell_size);
-                    if free_list.is_null() {
-                        log!("Initialize free list with {:p} ('{}')", cell.raw(), c);
-                        free_list = cell.raw() as *mut _;
-                        (&mut *free_list).next = std::ptr::null_mut();
-                    } else {
-                        let next = free_list;
-                        free_list = cell.raw() as *mut _;
-                        (&mut *free_list).next = next as *mut _;
-                        log!("Sweep {:p} to free list {:p} ('{}')", cell.raw(), next, c);
-                    }
-                    crate::get_vm().heap.allocated -= this.cell_size;
+                    return;
                 }
-            } else {
-                let next = free_list;
-                free_list = cell_addr.to_mut_ptr();
-                (&mut *free_list).next = next as *mut _;
-                //log!("Add {:p} to free list {:p}", cell_addr.to_ptr::<()>(), next);
+                this.unmark(cell_addr);
+                if let Some(destroy_fn) = cell.vtable.destroy_fn {
+                    destroy_fn(cell);
+                }
+                std::ptr::write_bytes(cell_addr.to_mut_ptr::<u8>(), 0, this.cell_size);
+                *freed += this.cell_size;
             }
+            let entry = cell_addr.to_mut_ptr::<FreeListEntry>();
+            (&mut *entry).next = free_list.cast();
+            free_list = entry;
         });
         self.free_list = free_list;
+        // every unused cell is in the free list now, so bump allocation is over.
+        self.cursor = self
+            .storage()
+            .offset(Self::BLOCK_SIZE - std::mem::size_of::<Self>());
         all_free
     }
     pub fn allocate(&mut self) -> Address {
@@ -83,11 +70,6 @@ impl HeapBlock {
                 unsafe {
                     let x = self.free_list;
                     self.free_list = (&*x).next.cast();
-                    log!(
-                        "Allocate {:p} from free list,next free list cell: {:p} ",
-                        x,
-                        self.free_list
-                    );
                     Address::from_ptr(x)
                 }
             }
@@ -0,0 +1,115 @@
+use super::mem::*;
+use crate::gc::*;
+use crate::object::*;
+use std::collections::BTreeMap;
+
+/// Size of virtual memory reserved for large objects.
+pub const LARGE_SPACE_SIZE: usize = 1024 * 1024 * 1024;
+
+/// Space for objects that do not fit into any size class. Memory for the whole space is
+/// reserved up front and every object gets its own page aligned range that is committed on
+/// allocation and uncommitted when the object dies.
+pub struct LargeObjectSpace {
+    start: Address,
+    end: Address,
+    /// Live objects, start address to committed size.
+    objects: BTreeMap<Address, usize>,
+    /// Free ranges of the reserved memory, start address to size.
+    free: BTreeMap<Address, usize>,
+    pub allocated: usize,
+}
+
+impl LargeObjectSpace {
+    pub fn new() -> Self {
+        let start = reserve(LARGE_SPACE_SIZE);
+        let mut free = BTreeMap::new();
+        free.insert(start, LARGE_SPACE_SIZE);
+        Self {
+            start,
+            end: start.offset(LARGE_SPACE_SIZE),
+            objects: BTreeMap::new(),
+            free,
+            allocated: 0,
+        }
+    }
+
+    /// Allocate zeroed memory for an object of `size` bytes, returns null address if
+    /// reserved space is exhausted.
+    pub fn allocate(&mut self, size: usize) -> Address {
+        let size = page_align(size);
+        let range = self
+            .free
+            .iter()
+            .find(|(_, free_size)| **free_size >= size)
+            .map(|(start, free_size)| (*start, *free_size));
+        let (start, free_size) = match range {
+            Some(range) => range,
+            None => return Address::null(),
+        };
+        self.free.remove(&start);
+        if free_size > size {
+            self.free.insert(start.offset(size), free_size - size);
+        }
+        commit_at(start, size, false);
+        self.objects.insert(start, size);
+        self.allocated += size;
+        start
+    }
+
+    /// Committed size of the object starting at `addr`.
+    pub fn size_of(&self, addr: Address) -> Option<usize> {
+        self.objects.get(&addr).copied()
+    }
+
+    /// Returns true if `addr` is the start of a live large object.
+    pub fn contains(&self, addr: Address) -> bool {
+        addr >= self.start && addr < self.end && self.objects.contains_key(&addr)
+    }
+
+    pub fn for_each_object(&self, mut callback: impl FnMut(Address, usize)) {
+        for (addr, size) in self.objects.iter() {
+            callback(*addr, *size);
+        }
+    }
+
+    /// Release objects with unmarked header and clear marks of the survivors. Size of
+    /// released memory is added to `freed`.
+    pub fn sweep(&mut self, freed: &mut usize) {
+        let mut dead = vec![];
+        for (addr, size) in self.objects.iter() {
+            let mut cell = Ref::<Obj> {
+                ptr: unsafe { std::ptr::NonNull::new_unchecked(addr.to_mut_ptr()) },
+            };
+            if cell.header().is_marked_non_atomic() {
+                cell.header_mut().unmark_non_atomic();
+                continue;
+            }
+            if let Some(destroy_fn) = cell.vtable.destroy_fn {
+                destroy_fn(cell);
+            }
+            dead.push((*addr, *size));
+        }
+        for (addr, size) in dead {
+            self.objects.remove(&addr);
+            uncommit(addr, size);
+            self.release(addr, size);
+            self.allocated -= size;
+            *freed += size;
+        }
+    }
+
+    /// Return range to the free map merging it with its neighbours.
+    fn release(&mut self, mut addr: Address, mut size: usize) {
+        if let Some((&prev, &prev_size)) = self.free.range(..addr).next_back() {
+            if prev.offset(prev_size) == addr {
+                self.free.remove(&prev);
+                addr = prev;
+                size += prev_size;
+            }
+        }
+        if let Some(next_size) = self.free.remove(&addr.offset(size)) {
+            size += next_size;
+        }
+        self.free.insert(addr, size);
+    }
+}
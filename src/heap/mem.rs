@@ -397,7 +397,7 @@ pub fn uncommit(ptr: Address, size: usize) {
             ptr.to_mut_ptr(),
             size,
             libc::PROT_NONE,
-            libc::MAP_PRIVATE | libc::MAP_ANON | libc::MAP_NORESERVE,
+            libc::MAP_PRIVATE | libc::MAP_ANON | libc::MAP_NORESERVE | libc::MAP_FIXED,
             -1,
             0,
         )
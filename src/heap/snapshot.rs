@@ -0,0 +1,340 @@
+//! Heap snapshots in the `.heapsnapshot` format of Chrome DevTools, load the file in the
+//! Memory tab to browse retainers of leaking objects.
+//!
+//! Nodes are cells reachable from the roots and a synthetic `(GC roots)` node whose edges
+//! are named after the roots (`vm.<field>`, `global <name>`, `frame #<depth>`). Nodes are
+//! named by their `VTable::name`, except strings which are named by their contents and
+//! functions which are named by the function name. Properties of objects and module
+//! scopes are `property` edges, fields of functions and upvalues are `internal` edges,
+//! everything else (array and tuple elements, code block constants) is an `element` edge
+//! indexed in trace order.
+use super::*;
+use crate::function::Function;
+use std::collections::HashMap;
+use std::io::Write;
+
+const NODE_TYPES: &str = r#"["hidden","array","string","object","code","closure","regexp","number","native","synthetic"]"#;
+const EDGE_TYPES: &str =
+    r#"["context","element","property","internal","hidden","shortcut","weak"]"#;
+
+const NODE_HIDDEN: usize = 0;
+const NODE_ARRAY: usize = 1;
+const NODE_STRING: usize = 2;
+const NODE_OBJECT: usize = 3;
+const NODE_CODE: usize = 4;
+const NODE_CLOSURE: usize = 5;
+const NODE_NUMBER: usize = 7;
+const NODE_SYNTHETIC: usize = 9;
+
+const EDGE_ELEMENT: usize = 1;
+const EDGE_PROPERTY: usize = 2;
+const EDGE_INTERNAL: usize = 3;
+
+const NODE_FIELD_COUNT: usize = 6;
+/// Longer strings are cut when used as node names.
+const MAX_NAME_LEN: usize = 100;
+
+struct Edge {
+    kind: usize,
+    /// String index for named edges, element index otherwise.
+    name: usize,
+    to: usize,
+}
+
+struct Node {
+    kind: usize,
+    name: usize,
+    size: usize,
+    edges: Vec<Edge>,
+}
+
+#[derive(Default)]
+struct Snapshot {
+    nodes: Vec<Node>,
+    ids: HashMap<Address, usize>,
+    strings: Vec<String>,
+    string_ids: HashMap<String, usize>,
+}
+
+impl Snapshot {
+    fn string(&mut self, s: &str) -> usize {
+        if let Some(id) = self.string_ids.get(s) {
+            return *id;
+        }
+        self.strings.push(s.to_owned());
+        self.string_ids.insert(s.to_owned(), self.strings.len() - 1);
+        self.strings.len() - 1
+    }
+
+    /// Index of the node for `cell`, new nodes are pushed to `queue` to get their edges.
+    fn node(&mut self, heap: &Heap, cell: Ref<Obj>, queue: &mut Vec<Ref<Obj>>) -> usize {
+        if let Some(ix) = self.ids.get(&cell.address()) {
+            return *ix;
+        }
+        let (kind, name) = match cell.vtable.name {
+            "String" => {
+                let s = cell.cast::<WaffleString>();
+                let name = s.str().chars().take(MAX_NAME_LEN).collect::<String>();
+                (NODE_STRING, name)
+            }
+            "Function" => (
+                NODE_CLOSURE,
+                cell.cast::<Function>().name.str().to_owned(),
+            ),
+            "Array" | "Tuple" => (NODE_ARRAY, cell.vtable.name.to_owned()),
+            "CodeBlock" => (NODE_CODE, cell.vtable.name.to_owned()),
+            "BigInt" => (NODE_NUMBER, cell.vtable.name.to_owned()),
+            "Upvalue" => (NODE_HIDDEN, cell.vtable.name.to_owned()),
+            _ => (NODE_OBJECT, cell.vtable.name.to_owned()),
+        };
+        let node = Node {
+            kind,
+            name: self.string(&name),
+            size: heap.cell_size(cell),
+            edges: vec![],
+        };
+        self.nodes.push(node);
+        self.ids.insert(cell.address(), self.nodes.len() - 1);
+        queue.push(cell);
+        self.nodes.len() - 1
+    }
+}
+
+/// Names of slots of `cell` that are known fields or properties.
+fn edge_names(cell: Ref<Obj>) -> HashMap<usize, (usize, String)> {
+    fn addr<T>(x: &T) -> usize {
+        x as *const T as usize
+    }
+    let mut names = HashMap::new();
+    match cell.vtable.name {
+        "Object" => {
+            let obj = cell.cast::<RegularObj>();
+            names.insert(addr(&obj.prototype), (EDGE_INTERNAL, "__proto__".to_owned()));
+            for (key, value) in obj.table.iter() {
+                names.insert(addr(value), (EDGE_PROPERTY, key.to_owned()));
+            }
+        }
+        "Module" => {
+            let module = cell.cast::<Module>();
+            names.insert(addr(&module.name), (EDGE_INTERNAL, "name".to_owned()));
+            for (key, value) in module.scope.iter() {
+                names.insert(addr(value), (EDGE_PROPERTY, key.clone()));
+            }
+        }
+        "Function" => {
+            let function = cell.cast::<Function>();
+            names.insert(addr(&function.name), (EDGE_INTERNAL, "name".to_owned()));
+            names.insert(
+                addr(&function.prototype),
+                (EDGE_INTERNAL, "prototype".to_owned()),
+            );
+            if let Some(env) = &function.env {
+                names.insert(addr(env), (EDGE_INTERNAL, "env".to_owned()));
+            }
+            if let Some(code_block) = &function.code_block {
+                names.insert(addr(code_block), (EDGE_INTERNAL, "code".to_owned()));
+            }
+            if let Some(module) = &function.module {
+                names.insert(addr(module), (EDGE_INTERNAL, "module".to_owned()));
+            }
+        }
+        "Upvalue" => {
+            let upvalue = cell.cast::<Upvalue>();
+            names.insert(addr(&upvalue.value), (EDGE_INTERNAL, "value".to_owned()));
+        }
+        _ => (),
+    }
+    names
+}
+
+fn write_json_string(out: &mut dyn Write, s: &str) -> std::io::Result<()> {
+    write!(out, "\"")?;
+    for ch in s.chars() {
+        match ch {
+            '"' => write!(out, "\\\"")?,
+            '\\' => write!(out, "\\\\")?,
+            '\n' => write!(out, "\\n")?,
+            '\r' => write!(out, "\\r")?,
+            '\t' => write!(out, "\\t")?,
+            ch if (ch as u32) < 0x20 => write!(out, "\\u{:04x}", ch as u32)?,
+            ch => write!(out, "{}", ch)?,
+        }
+    }
+    write!(out, "\"")
+}
+
+/// Walk every cell reachable from the roots and write the snapshot to `out`.
+pub fn write_snapshot(heap: &Heap, out: &mut dyn Write) -> std::io::Result<()> {
+    let mut snapshot = Snapshot::default();
+    let mut queue = vec![];
+    let root_name = snapshot.string("(GC roots)");
+    snapshot.nodes.push(Node {
+        kind: NODE_SYNTHETIC,
+        name: root_name,
+        size: 0,
+        edges: vec![],
+    });
+    visit_roots(&mut |root, slot| {
+        if slot.is_cell() && !slot.is_empty() {
+            let to = snapshot.node(heap, slot.as_cell(), &mut queue);
+            let name = snapshot.string(&root.to_string());
+            snapshot.nodes[0].edges.push(Edge {
+                kind: EDGE_PROPERTY,
+                name,
+                to,
+            });
+        }
+    });
+    while let Some(cell) = queue.pop() {
+        let from = snapshot.ids[&cell.address()];
+        let names = edge_names(cell);
+        let mut targets = vec![];
+        if let Some(trace) = cell.vtable.trace_fn {
+            trace(cell, &mut |slot| unsafe {
+                if *(slot as *const usize) != 0 {
+                    targets.push((slot as usize, *slot));
+                }
+            });
+        }
+        let mut index = 0;
+        for (slot, target) in targets {
+            let to = snapshot.node(heap, target, &mut queue);
+            let edge = match names.get(&slot) {
+                Some((kind, name)) => Edge {
+                    kind: *kind,
+                    name: snapshot.string(name),
+                    to,
+                },
+                None => {
+                    index += 1;
+                    Edge {
+                        kind: EDGE_ELEMENT,
+                        name: index - 1,
+                        to,
+                    }
+                }
+            };
+            snapshot.nodes[from].edges.push(edge);
+        }
+    }
+
+    let edge_count = snapshot.nodes.iter().map(|n| n.edges.len()).sum::<usize>();
+    writeln!(
+        out,
+        "{{\"snapshot\":{{\"meta\":{{\
+         \"node_fields\":[\"type\",\"name\",\"id\",\"self_size\",\"edge_count\",\"trace_node_id\"],\
+         \"node_types\":[{},\"string\",\"number\",\"number\",\"number\",\"number\"],\
+         \"edge_fields\":[\"type\",\"name_or_index\",\"to_node\"],\
+         \"edge_types\":[{},\"string_or_number\",\"node\"]}},\
+         \"node_count\":{},\"edge_count\":{}}},",
+        NODE_TYPES,
+        EDGE_TYPES,
+        snapshot.nodes.len(),
+        edge_count
+    )?;
+    write!(out, "\"nodes\":[")?;
+    for (i, node) in snapshot.nodes.iter().enumerate() {
+        if i != 0 {
+            writeln!(out, ",")?;
+        }
+        // ids of objects are odd in snapshots made by V8, keep it that way.
+        write!(
+            out,
+            "{},{},{},{},{},0",
+            node.kind,
+            node.name,
+            i * 2 + 1,
+            node.size,
+            node.edges.len()
+        )?;
+    }
+    write!(out, "],\n\"edges\":[")?;
+    let mut first = true;
+    for node in snapshot.nodes.iter() {
+        for edge in node.edges.iter() {
+            if !first {
+                writeln!(out, ",")?;
+            }
+            first = false;
+            write!(
+                out,
+                "{},{},{}",
+                edge.kind,
+                edge.name,
+                edge.to * NODE_FIELD_COUNT
+            )?;
+        }
+    }
+    write!(out, "],\n\"strings\":[")?;
+    for (i, s) in snapshot.strings.iter().enumerate() {
+        if i != 0 {
+            writeln!(out, ",")?;
+        }
+        write_json_string(out, s)?;
+    }
+    writeln!(out, "]}}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn numbers(json: &str, section: &str) -> Vec<usize> {
+        let start = json.find(section).unwrap() + section.len();
+        let end = start + json[start..].find(']').unwrap();
+        json[start..end]
+            .split(',')
+            .map(|n| n.trim().parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_retention_path() {
+        let _vm = lock();
+        let vm = crate::get_vm();
+        vm.globals.insert("snapshotTestObj", Value::undefined());
+        let src = "snapshotTestObj = new { leaked: \"snapshotTestString\" }\n0";
+        assert_eq!(run(src, false), "0");
+        let mut out = vec![];
+        write_snapshot(&vm.heap, &mut out).unwrap();
+        let json = String::from_utf8(out).unwrap();
+        let nodes = numbers(&json, "\"nodes\":[");
+        let edges = numbers(&json, "\"edges\":[");
+        let start = json.find("\"strings\":[").unwrap() + "\"strings\":[".len();
+        let strings = json[start..json.len() - 3]
+            .split(",\n")
+            .map(|s| s.trim_matches('"'))
+            .collect::<Vec<_>>();
+        let string = |s: &str| strings.iter().position(|x| *x == s).unwrap();
+        assert_eq!(nodes.len() % NODE_FIELD_COUNT, 0);
+        assert_eq!(nodes[1], string("(GC roots)"));
+        // edges of a node follow the edges of all nodes before it.
+        let edges_of = |node: usize| {
+            let first = (0..node)
+                .map(|n| nodes[n * NODE_FIELD_COUNT + 4])
+                .sum::<usize>();
+            let count = nodes[node * NODE_FIELD_COUNT + 4];
+            edges[first * 3..(first + count) * 3]
+                .chunks(3)
+                .map(|e| (e[0], e[1], e[2] / NODE_FIELD_COUNT))
+                .collect::<Vec<_>>()
+        };
+        let find = |node: usize, kind: usize, name: &str| {
+            edges_of(node)
+                .into_iter()
+                .find(|e| e.0 == kind && e.1 == string(name))
+                .unwrap()
+                .2
+        };
+        let obj = find(0, EDGE_PROPERTY, "global snapshotTestObj");
+        assert_eq!(nodes[obj * NODE_FIELD_COUNT + 1], string("Object"));
+        let leaked = find(obj, EDGE_PROPERTY, "leaked");
+        assert_eq!(nodes[leaked * NODE_FIELD_COUNT], NODE_STRING);
+        assert_eq!(
+            nodes[leaked * NODE_FIELD_COUNT + 1],
+            string("snapshotTestString")
+        );
+        vm.globals.insert("snapshotTestObj", Value::undefined());
+    }
+}
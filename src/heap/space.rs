@@ -137,6 +137,23 @@ impl Space {
 
         false
     }
+    /// Rewind allocation to the start of the first page and release all other pages.
+    pub fn reset(&mut self) {
+        while self.pages.len() > 1 {
+            let page = self.pages.pop().unwrap();
+            self.size -= page.size;
+            page.uncommit();
+        }
+        self.pages_count = self.pages.len();
+        self.allocated_size = 0;
+        let page = &mut self.pages[0];
+        unsafe {
+            std::ptr::write_bytes(page.data.to_mut_ptr::<u8>(), 0, page.top.offset_from(page.data));
+        }
+        page.top = page.data;
+        self.top = Address::from_ptr(&page.top);
+        self.limit = Address::from_ptr(&page.limit);
+    }
     pub fn clear(&mut self) {
         self.size = 0;
         while let Some(page) = self.pages.pop() {
@@ -0,0 +1,102 @@
+use super::*;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Time spent in `Heap::collect`, every call is one pause of the mutator.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct PauseStats {
+    pub count: usize,
+    pub total: Duration,
+    pub max: Duration,
+    pub last: Duration,
+}
+
+impl PauseStats {
+    pub fn record(&mut self, pause: Duration) {
+        self.count += 1;
+        self.total += pause;
+        self.last = pause;
+        if pause > self.max {
+            self.max = pause;
+        }
+    }
+
+    pub fn average(&self) -> Duration {
+        if self.count == 0 {
+            return Duration::default();
+        }
+        self.total / self.count as u32
+    }
+}
+
+/// Counters kept by the heap for the whole run.
+#[derive(Default, Clone, Debug)]
+pub struct HeapStats {
+    pub minor_collections: usize,
+    pub major_collections: usize,
+    pub pauses: PauseStats,
+    /// Bytes allocated in the nursery.
+    pub young_allocated: usize,
+    /// Bytes allocated in the old generation per size class, then the large space and at
+    /// `EXTERNAL` array backing stores.
+    pub allocated: [usize; EXTERNAL + 1],
+    /// Bytes released by sweeping or freeing backing stores, indexed like `allocated`.
+    pub freed: [usize; EXTERNAL + 1],
+    /// Largest size of nursery and old generation together.
+    pub peak: usize,
+}
+
+/// Number and total size of cells of one type.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct TypeStats {
+    pub count: usize,
+    pub bytes: usize,
+}
+
+impl HeapStats {
+    /// Write human readable report, `live` is the result of `Heap::live_objects`.
+    pub fn dump(
+        &self,
+        live: &BTreeMap<&'static str, TypeStats>,
+        f: &mut dyn std::fmt::Write,
+    ) -> std::fmt::Result {
+        writeln!(f, "GC statistics:")?;
+        writeln!(
+            f,
+            "  collections: {} minor, {} major",
+            self.minor_collections, self.major_collections
+        )?;
+        writeln!(
+            f,
+            "  pauses: {} total {:?}, avg {:?}, max {:?}",
+            self.pauses.count,
+            self.pauses.total,
+            self.pauses.average(),
+            self.pauses.max
+        )?;
+        writeln!(f, "  nursery allocated: {} bytes", self.young_allocated)?;
+        writeln!(f, "  peak heap size: {} bytes", self.peak)?;
+        writeln!(f, "  old generation by size class:")?;
+        for i in 0..=EXTERNAL {
+            let class = match i {
+                SIZE_CLASSES => "large".to_owned(),
+                EXTERNAL => "arrays".to_owned(),
+                _ => format!("{}", Heap::size_class_size_for(i)),
+            };
+            writeln!(
+                f,
+                "    {:>6}: {} bytes allocated, {} bytes freed",
+                class, self.allocated[i], self.freed[i]
+            )?;
+        }
+        writeln!(f, "  objects in heap:")?;
+        for (name, stats) in live.iter() {
+            writeln!(
+                f,
+                "    {:>10}: {} objects, {} bytes",
+                name, stats.count, stats.bytes
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+//! Heap verification for `--verifyHeap`. Every reference reachable from the roots has to
+//! point to an allocated cell with a known vtable: a nursery cell that was not evacuated, a
+//! live cell of a `HeapBlock`, a large object or a code block.
+use super::*;
+use crate::vtable::is_valid_vtable;
+use std::collections::HashSet;
+
+impl Heap {
+    /// Walk the object graph and panic at the first invalid reference. `phase` is included
+    /// in the panic message.
+    pub fn verify(&self, phase: &str) {
+        let blocks = self
+            .size_classes
+            .iter()
+            .flat_map(|blocks| blocks.iter().map(|block| *block as usize))
+            .collect::<HashSet<_>>();
+        let mut visited = HashSet::new();
+        let mut stack = vec![];
+        visit_roots(&mut |root, slot| {
+            if slot.is_cell() && !slot.is_empty() {
+                self.verify_ref(phase, &root.to_string(), slot.as_cell(), &blocks);
+                if visited.insert(slot.as_cell().address()) {
+                    stack.push(slot.as_cell());
+                }
+            }
+        });
+        while let Some(cell) = stack.pop() {
+            let mut children = vec![];
+            if let Some(trace) = cell.vtable.trace_fn {
+                trace(cell, &mut |slot| unsafe {
+                    if *(slot as *const usize) != 0 {
+                        children.push(*slot);
+                    }
+                });
+            }
+            // weak slots are not traced but must not dangle either.
+            if cell.vtable as *const _ == &crate::builtins::WEAK_REF_VTBL as *const _ {
+                let target = cell.cast::<WeakRef>().target;
+                if target.is_cell() && !target.is_empty() {
+                    children.push(target.as_cell());
+                }
+            } else if cell.vtable as *const _ == &crate::builtins::WEAK_MAP_VTBL as *const _ {
+                for (key, value) in cell.cast::<WeakMap>().entries() {
+                    children.push(key.as_cell());
+                    if value.is_cell() && !value.is_empty() {
+                        children.push(value.as_cell());
+                    }
+                }
+            }
+            let from = format!("{} {:p}", cell.vtable.name, cell.address().to_ptr::<u8>());
+            for child in children {
+                self.verify_ref(phase, &from, child, &blocks);
+                if visited.insert(child.address()) {
+                    stack.push(child);
+                }
+            }
+        }
+    }
+
+    fn verify_ref(&self, phase: &str, from: &str, cell: Ref<Obj>, blocks: &HashSet<usize>) {
+        let addr = cell.address();
+        let fail = |reason: &str| -> ! {
+            panic!(
+                "heap verification failed {}: {} refers to {:p}, {}",
+                phase,
+                from,
+                addr.to_ptr::<u8>(),
+                reason
+            )
+        };
+        if addr.to_usize() % std::mem::size_of::<usize>() != 0 {
+            fail("misaligned pointer");
+        }
+        // check the vtable before dereferencing anything through it.
+        let vtable = unsafe { *addr.offset(offset_of!(Obj, vtable)).to_ptr::<*const VTable>() };
+        let valid_location = if self.young.contains(addr) {
+            if !self.is_allocated_young(addr) {
+                fail("pointer into unused nursery memory");
+            }
+            if cell.header().fwdptr_non_atomic().is_non_null() {
+                fail("stale pointer to evacuated nursery cell");
+            }
+            true
+        } else if self.large.contains(addr) {
+            true
+        } else if vtable == &crate::bytecode::CB_VTBL as *const _ {
+            // code blocks live in malloc'd memory.
+            true
+        } else {
+            let block = addr.to_usize() & !(block::HeapBlock::BLOCK_SIZE - 1);
+            blocks.contains(&block)
+                && unsafe { (*(block as *const block::HeapBlock)).is_marked(addr) }
+        };
+        if !valid_location {
+            fail("pointer to freed or foreign memory");
+        }
+        if !is_valid_vtable(vtable) {
+            fail("unknown vtable");
+        }
+    }
+
+    /// Returns true if `addr` is the start of a cell allocated in the nursery.
+    fn is_allocated_young(&self, addr: Address) -> bool {
+        let mut found = false;
+        self.for_each_young_cell(|cell, _| found |= cell.address() == addr);
+        found
+    }
+}
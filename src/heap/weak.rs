@@ -0,0 +1,191 @@
+//! Weak references, ephemeron tables and finalizers. `WeakRef` and `WeakMap` cells have no
+//! `trace_fn`, the collector records them while tracing and fixes them up once everything
+//! strongly reachable is known: after evacuation for minor collections and before sweeping
+//! for major ones.
+use super::*;
+use crate::builtins::{WEAK_MAP_VTBL, WEAK_REF_VTBL};
+use crate::function::Function;
+
+/// Called with the held value of a finalizer after its target was collected.
+pub enum FinalizerCallback {
+    /// Native code, lets embedders release resources attached to script objects.
+    Native(Box<dyn FnOnce(Value)>),
+    /// Script function.
+    Script(Value),
+}
+
+pub(super) struct Finalizer {
+    /// Weak, never visited as a root.
+    pub(super) target: Value,
+    pub(super) held: Value,
+    pub(super) callback: FinalizerCallback,
+}
+
+/// Weak containers reached by tracing.
+#[derive(Default)]
+pub(super) struct Discovered {
+    refs: Vec<Ref<Obj>>,
+    maps: Vec<Ref<Obj>>,
+}
+
+impl Discovered {
+    pub(super) fn discover(&mut self, cell: Ref<Obj>) {
+        if cell.vtable as *const _ == &WEAK_REF_VTBL as *const _ {
+            self.refs.push(cell);
+        } else if cell.vtable as *const _ == &WEAK_MAP_VTBL as *const _ {
+            self.maps.push(cell);
+        }
+    }
+}
+
+impl Heap {
+    /// Run `callback` with `held` once `target` is collected. `held` and script callbacks
+    /// are roots until the callback runs, so `held` must not refer to `target`.
+    pub fn register_finalizer(
+        &mut self,
+        target: Ref<Obj>,
+        held: Value,
+        callback: FinalizerCallback,
+    ) {
+        self.finalizers.push(Finalizer {
+            target: Value::from(target),
+            held,
+            callback,
+        });
+    }
+
+    /// Run callbacks of finalizers whose targets died. Called at the end of `collect`, outside
+    /// of the pause.
+    pub fn run_finalizers(&mut self) {
+        while let Some((held, callback)) = self.pending_finalizers.pop() {
+            match callback {
+                FinalizerCallback::Native(callback) => callback(held),
+                FinalizerCallback::Script(callback) => {
+                    let res = callback
+                        .as_cell()
+                        .cast::<Function>()
+                        .execute(Value::undefined(), &[held]);
+                    if res.is_error() {
+                        eprintln!(
+                            "finalizer threw: {}",
+                            crate::runtime::val_str(res.value())
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Address `value` has after evacuation, `None` if it is a young object that died.
+    fn forwarded(&self, value: Value) -> Option<Value> {
+        if !value.is_cell() || value.is_empty() || !self.young.contains(value.as_cell().address())
+        {
+            return Some(value);
+        }
+        let forwarded = value.as_cell().header().fwdptr_non_atomic();
+        if forwarded.is_null() {
+            None
+        } else {
+            Some(Value::from(Into::<Ref<Obj>>::into(forwarded)))
+        }
+    }
+
+    /// Evacuate values of discovered ephemerons whose keys survived, until no more objects
+    /// are copied.
+    pub(super) unsafe fn evacuate_ephemerons(
+        &mut self,
+        gray: &mut Vec<Ref<Obj>>,
+        code_blocks: &mut std::collections::HashSet<Address>,
+    ) {
+        loop {
+            for map in self.young_weak.maps.clone() {
+                let mut map = map.cast::<WeakMap>();
+                for (key, value) in map.entries_mut() {
+                    if self.forwarded(*key).is_some() && value.is_cell() && !value.is_empty() {
+                        self.evacuate(value as *mut Value as *mut Ref<Obj>, gray, code_blocks);
+                    }
+                }
+            }
+            if gray.is_empty() {
+                break;
+            }
+            while let Some(cell) = gray.pop() {
+                self.trace_young(cell, gray, code_blocks);
+            }
+        }
+    }
+
+    /// Update weak slots after evacuation, slots pointing to dead young objects are cleared
+    /// and their finalizers become pending.
+    pub(super) fn sweep_weak_young(&mut self) {
+        let discovered = std::mem::take(&mut self.young_weak);
+        for weak in discovered.refs {
+            let mut weak = weak.cast::<WeakRef>();
+            weak.target = self.forwarded(weak.target).unwrap_or(Value::undefined());
+        }
+        for map in discovered.maps {
+            map.cast::<WeakMap>().update_keys(|key| self.forwarded(key));
+        }
+        for mut finalizer in std::mem::take(&mut self.finalizers) {
+            match self.forwarded(finalizer.target) {
+                Some(target) => {
+                    finalizer.target = target;
+                    self.finalizers.push(finalizer);
+                }
+                None => self
+                    .pending_finalizers
+                    .push((finalizer.held, finalizer.callback)),
+            }
+        }
+    }
+
+    /// Returns false for old objects left unmarked by the current marking cycle. The nursery
+    /// is not collected by major cycles so young objects always survive.
+    fn survives_marking(&self, value: Value) -> bool {
+        if !value.is_cell() || value.is_empty() {
+            return true;
+        }
+        let cell = value.as_cell();
+        self.young.contains(cell.address()) || cell.header().is_marked_non_atomic()
+    }
+
+    /// Shade values of discovered ephemerons whose keys are live.
+    pub(super) fn mark_ephemerons(&mut self) {
+        for map in self.old_weak.maps.clone() {
+            let map = map.cast::<WeakMap>();
+            for (key, value) in map.entries() {
+                if self.survives_marking(*key) && value.is_cell() && !value.is_empty() {
+                    self.shade(value.as_cell());
+                }
+            }
+        }
+    }
+
+    /// Clear weak slots pointing to objects that are about to be swept.
+    pub(super) fn sweep_weak_old(&mut self) {
+        let discovered = std::mem::take(&mut self.old_weak);
+        for weak in discovered.refs {
+            let mut weak = weak.cast::<WeakRef>();
+            if !self.survives_marking(weak.target) {
+                weak.target = Value::undefined();
+            }
+        }
+        for map in discovered.maps {
+            map.cast::<WeakMap>().update_keys(|key| {
+                if self.survives_marking(key) {
+                    Some(key)
+                } else {
+                    None
+                }
+            });
+        }
+        for finalizer in std::mem::take(&mut self.finalizers) {
+            if self.survives_marking(finalizer.target) {
+                self.finalizers.push(finalizer);
+            } else {
+                self.pending_finalizers
+                    .push((finalizer.held, finalizer.callback));
+            }
+        }
+    }
+}
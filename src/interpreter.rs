@@ -23,6 +23,18 @@ macro_rules! cmp {
     };
 }
 
+/// Run `addr`, the JIT code or `interp_loop`, in `callframe`. JIT code that takes an OSR
+/// exit returns with the frame set up for the interpreter, which finishes the call here
+/// instead of in a nested `interp_loop` under the JIT frame.
+pub fn run_frame(addr: WaffleInternalFn, callframe: &mut callframe::CallFrame) -> WaffleResult {
+    let result = addr(callframe);
+    if result.is_osr_exit() {
+        interp_loop(callframe)
+    } else {
+        result
+    }
+}
+
 pub extern "C" fn interp_loop(callframe: &mut callframe::CallFrame) -> WaffleResult {
     let mut cb = callframe.code_block.unwrap();
     let code: &Vec<Ins> = unsafe { &*(&cb.instructions as *const _) };
@@ -73,13 +85,16 @@ pub extern "C" fn interp_loop(callframe: &mut callframe::CallFrame) -> WaffleRes
                     if cb.exc_counter >= crate::get_vm().jit_threshold {
                         use crate::jit::*;
                         log!("Triggering OSR after ~{} loop iterations", cb.exc_counter);
-                        let mut jit = JIT::new(&cb);
-                        jit.compile_without_linking();
-                        jit.link();
-                        if vm.disasm {
-                            jit.disasm();
+                        // another call may have compiled the code block already.
+                        if cb.jit_data().executable_addr == 0 {
+                            let mut jit = JIT::new(&cb);
+                            jit.compile_without_linking();
+                            jit.link();
+                            if vm.disasm {
+                                jit.disasm();
+                            }
                         }
-                        let addr = cb.jit_data().code_map.get(&(pc - 1)).copied().unwrap();
+                        let addr = cb.jit_data().code_map.get(&pc).copied().unwrap();
                         let trampoline = crate::get_vm()
                             .stubs
                             .get_stub(thunk_generator::osr_from_interpreter_to_jit_generator);
@@ -87,8 +102,13 @@ pub extern "C" fn interp_loop(callframe: &mut callframe::CallFrame) -> WaffleRes
                             &mut callframe::CallFrame,
                             *const u8,
                         ) -> WaffleResult = unsafe { std::mem::transmute(trampoline) };
-                        // TemplateJIT can't do OSR exit to interpreter, OptimizingJIT does OSR exit to template JIT.
-                        return trampoline_fn(callframe, addr);
+                        let result = trampoline_fn(callframe, addr);
+                        if !result.is_osr_exit() {
+                            return result;
+                        }
+                        // the exit jettisoned the code, continue where it left.
+                        pc = callframe.pc;
+                        continue;
                     }
                 }
                 pc += 1;
@@ -140,21 +160,7 @@ pub extern "C" fn interp_loop(callframe: &mut callframe::CallFrame) -> WaffleRes
                     .as_cell()
                     .cast::<WaffleString>();
                 let val = callframe.get_register(src);
-                if vm.globals.has(constant.str()) {
-                    vm.globals.insert(constant.str(), val);
-                } else {
-                    if let Some(mut m) = callframe
-                        .callee
-                        .as_cell()
-                        .cast::<function::Function>()
-                        .module
-                    {
-                        if let Some(_) = m.scope.get(constant.str()) {
-                            m.scope.insert(constant.str().to_owned(), val);
-                            pc += 1;
-                            continue;
-                        }
-                    }
+                if !store_global(callframe, constant.str(), val) {
                     catch!(Value::from(
                         WaffleString::new(
                             &mut vm.heap,
@@ -165,7 +171,7 @@ pub extern "C" fn interp_loop(callframe: &mut callframe::CallFrame) -> WaffleRes
                 }
                 pc += 1;
             }
-            Ins::Mod(dest, lhs, rhs) => {
+            Ins::Mod(dest, lhs, rhs) | Ins::Rem(dest, lhs, rhs) => {
                 let lhs = callframe.get_register(lhs);
                 let rhs = callframe.get_register(rhs);
                 let res = operation_value_mod(vm, lhs, rhs);
@@ -225,21 +231,7 @@ pub extern "C" fn interp_loop(callframe: &mut callframe::CallFrame) -> WaffleRes
             Ins::LShift(dest, lhs, rhs) => {
                 let lhs = callframe.get_register(lhs);
                 let rhs = callframe.get_register(rhs);
-                if lhs.is_number() && rhs.is_number() {
-                    if lhs.is_int32() && rhs.is_int32() {
-                        callframe
-                            .put_register(dest, Value::new_int(lhs.to_int32() << rhs.to_int32()));
-                    } else {
-                        callframe.put_register(
-                            dest,
-                            Value::new_int(
-                                (lhs.to_number().trunc() as i32) << rhs.to_number().trunc() as i32,
-                            ),
-                        );
-                    }
-                } else {
-                    callframe.put_register(dest, Value::undefined());
-                }
+                callframe.put_register(dest, operation_value_lshift(vm, lhs, rhs));
                 callframe.code_block.unwrap().metadata[pc as usize]
                     .arith_profile
                     .observe_lhs_and_rhs(lhs, rhs);
@@ -248,21 +240,7 @@ pub extern "C" fn interp_loop(callframe: &mut callframe::CallFrame) -> WaffleRes
             Ins::RShift(dest, lhs, rhs) => {
                 let lhs = callframe.get_register(lhs);
                 let rhs = callframe.get_register(rhs);
-                if lhs.is_number() && rhs.is_number() {
-                    if lhs.is_int32() && rhs.is_int32() {
-                        callframe
-                            .put_register(dest, Value::new_int(lhs.to_int32() >> rhs.to_int32()));
-                    } else {
-                        callframe.put_register(
-                            dest,
-                            Value::new_int(
-                                (lhs.to_number().trunc() as i32) >> rhs.to_number().trunc() as i32,
-                            ),
-                        );
-                    }
-                } else {
-                    callframe.put_register(dest, Value::undefined());
-                }
+                callframe.put_register(dest, operation_value_rshift(vm, lhs, rhs));
                 callframe.code_block.unwrap().metadata[pc as usize]
                     .arith_profile
                     .observe_lhs_and_rhs(lhs, rhs);
@@ -271,25 +249,7 @@ pub extern "C" fn interp_loop(callframe: &mut callframe::CallFrame) -> WaffleRes
             Ins::URShift(dest, lhs, rhs) => {
                 let lhs = callframe.get_register(lhs);
                 let rhs = callframe.get_register(rhs);
-                if lhs.is_number() && rhs.is_number() {
-                    if lhs.is_int32() && rhs.is_int32() {
-                        callframe.put_register(
-                            dest,
-                            Value::new_int((lhs.to_uint32() >> rhs.to_uint32()) as i32),
-                        );
-                    } else {
-                        callframe.put_register(
-                            dest,
-                            Value::new_int(
-                                ((lhs.to_number().trunc() as i32 as u32)
-                                    >> rhs.to_number().trunc() as i32 as u32)
-                                    as i32,
-                            ),
-                        );
-                    }
-                } else {
-                    callframe.put_register(dest, Value::undefined());
-                }
+                callframe.put_register(dest, operation_value_urshift(vm, lhs, rhs));
                 callframe.code_block.unwrap().metadata[pc as usize]
                     .arith_profile
                     .observe_lhs_and_rhs(lhs, rhs);
@@ -329,14 +289,14 @@ pub extern "C" fn interp_loop(callframe: &mut callframe::CallFrame) -> WaffleRes
                     if lhs.is_int32() && rhs.is_int32() {
                         callframe.put_register(
                             dest,
-                            Value::new_int((lhs.to_uint32() ^ rhs.to_uint32()) as i32),
+                            Value::new_int((lhs.to_uint32() | rhs.to_uint32()) as i32),
                         );
                     } else {
                         callframe.put_register(
                             dest,
                             Value::new_int(
                                 ((lhs.to_number().trunc() as i32 as u32)
-                                    ^ rhs.to_number().trunc() as i32 as u32)
+                                    | rhs.to_number().trunc() as i32 as u32)
                                     as i32,
                             ),
                         );
@@ -585,7 +545,8 @@ pub extern "C" fn interp_loop(callframe: &mut callframe::CallFrame) -> WaffleRes
             Ins::LoadId(dest, object, key) => {
                 let key = cb.constants[key as usize];
                 let object = callframe.get_register(object);
-                let res = operation_get_by(vm, object, key);
+                let cache = &mut cb.metadata[pc as usize].property_cache;
+                let res = operation_get_by_id(vm, object, key, cache);
                 if res.is_error() {
                     catch!(res.value());
                 }
@@ -596,7 +557,8 @@ pub extern "C" fn interp_loop(callframe: &mut callframe::CallFrame) -> WaffleRes
                 let key = cb.constants[id as usize];
                 let object = callframe.get_register(object);
                 let val = callframe.get_register(val);
-                let res = operation_put_by(vm, object, key, val);
+                let cache = &mut cb.metadata[pc as usize].property_cache;
+                let res = operation_put_by_id(vm, object, key, val, cache);
                 if res.is_error() {
                     catch!(res.value());
                 }
@@ -624,8 +586,8 @@ pub extern "C" fn interp_loop(callframe: &mut callframe::CallFrame) -> WaffleRes
             }
             Ins::LoadU(dest, idx) => {
                 if let Some(env) = callframe.callee.as_cell().cast::<function::Function>().env {
-                    let val = env.get_at(idx as _);
-                    callframe.put_register(dest, val);
+                    let cell = env.get_at(idx as _).as_cell().cast::<Upvalue>();
+                    callframe.put_register(dest, cell.value);
                 } else {
                     catch!(Value::from(
                         WaffleString::new(&mut vm.heap, "can't load upvalue, no environment found")
@@ -635,9 +597,12 @@ pub extern "C" fn interp_loop(callframe: &mut callframe::CallFrame) -> WaffleRes
                 pc += 1;
             }
             Ins::StoreU(src, idx) => {
-                if let Some(mut env) = callframe.callee.as_cell().cast::<function::Function>().env {
+                if let Some(env) = callframe.callee.as_cell().cast::<function::Function>().env {
                     let val = callframe.get_register(src);
-                    env.set_at(idx as _, val);
+                    let mut cell = env.get_at(idx as _).as_cell().cast::<Upvalue>();
+                    vm.heap.satb_barrier(cell.value);
+                    cell.value = val;
+                    vm.heap.write_barrier(cell.cast(), val);
                 } else {
                     catch!(Value::from(
                         WaffleString::new(
@@ -649,23 +614,59 @@ pub extern "C" fn interp_loop(callframe: &mut callframe::CallFrame) -> WaffleRes
                 }
                 pc += 1;
             }
-            Ins::Closure(f, count) => {
+            Ins::LoadUCell(dest, idx) => {
+                if let Some(env) = callframe.callee.as_cell().cast::<function::Function>().env {
+                    callframe.put_register(dest, env.get_at(idx as _));
+                } else {
+                    catch!(Value::from(
+                        WaffleString::new(&mut vm.heap, "can't load upvalue, no environment found")
+                            .cast()
+                    ))
+                }
+                pc += 1;
+            }
+            Ins::NewCell(dest, src) => {
+                let val = callframe.get_register(src);
+                let cell = Upvalue::new(&mut vm.heap, val);
+                callframe.put_register(dest, Value::from(cell.cast()));
+                pc += 1;
+            }
+            Ins::LoadCell(dest, cell) => {
+                let cell = callframe.get_register(cell).as_cell().cast::<Upvalue>();
+                callframe.put_register(dest, cell.value);
+                pc += 1;
+            }
+            Ins::StoreCell(cell, src) => {
+                let mut cell = callframe.get_register(cell).as_cell().cast::<Upvalue>();
+                vm.heap.satb_barrier(cell.value);
+                cell.value = callframe.get_register(src);
+                vm.heap.write_barrier(cell.cast(), cell.value);
+                pc += 1;
+            }
+            Ins::Closure(f, first, count) => {
                 let func = callframe.get_register(f);
                 if func.is_cell() {
                     debug_assert!(func.as_cell().is_function());
-                    let mut func = func.as_cell().cast::<function::Function>();
-                    let values = &callframe.regs
-                        [f.to_local() as usize + 1..f.to_local() as usize + count as usize];
-                    let mut array = Array::new(&mut vm.heap, values.len(), Value::undefined());
-                    for (i, val) in values.iter().enumerate() {
-                        array.set_at(i, *val);
+                    let func = func.as_cell().cast::<function::Function>();
+                    let start = first.to_local() as usize;
+                    let cells = &callframe.regs[start..start + count as usize];
+                    // allocation never collects, `env` needs no root until the closure holds it.
+                    let mut env = Array::new(&mut vm.heap, cells.len(), Value::undefined());
+                    for (i, cell) in cells.iter().enumerate() {
+                        env.set_at(i, *cell);
                     }
-                    func.env = Some(array);
+                    let closure = func.new_closure(&mut vm.heap, env);
+                    callframe.put_register(f, Value::from(closure.cast()));
                 } else {
                     unreachable!();
                 }
                 pc += 1;
             }
+            Ins::Throw(src) => {
+                let val = callframe.get_register(src);
+                catch!(val);
+                continue;
+            }
             Ins::Catch(dst) => {
                 let exc = crate::get_vm().exception;
                 callframe.put_register(dst, exc);
@@ -696,58 +697,57 @@ pub extern "C" fn interp_loop(callframe: &mut callframe::CallFrame) -> WaffleRes
                 callframe.put_register(dst, Value::from(object.cast::<Obj>()));
                 pc += 1;
             }
+            Ins::NewArray(dst, first, count) => {
+                let mut array = Array::new(&mut vm.heap, count as _, Value::undefined());
+                for i in 0..count {
+                    let val = callframe.get_register(virtual_register::virtual_register_for_local(
+                        first.to_local() + i as i32,
+                    ));
+                    array.set_at(i as _, val);
+                }
+                callframe.put_register(dst, Value::from(array.cast()));
+                pc += 1;
+            }
+            Ins::NewTuple(dst, first, count) => {
+                let start = first.to_local() as usize;
+                let tuple = Tuple::new(
+                    &mut vm.heap,
+                    &callframe.regs[start..start + count as usize],
+                );
+                callframe.put_register(dst, Value::from(tuple.cast()));
+                pc += 1;
+            }
+            Ins::Slice(dst, src, start, tail) => {
+                let src = callframe.get_register(src);
+                let res = operation_slice(vm, src, start, tail);
+                if res.is_error() {
+                    catch!(res.value());
+                }
+                callframe.put_register(dst, res.value());
+                pc += 1;
+            }
+            Ins::Inherit(class, parent) => {
+                let class = callframe.get_register(class);
+                let parent = callframe.get_register(parent);
+                let res = operation_inherit(vm, class, parent);
+                if res.is_error() {
+                    catch!(res.value());
+                }
+                pc += 1;
+            }
             Ins::New(dest, callee_r, argc) => {
                 let callee = callframe.get_register(callee_r);
-                if callee.is_cell() {
-                    if let Some(lookup) = callee.as_cell().vtable.lookup_fn {
-                        let ctor = lookup(vm, callee.as_cell(), vm.constructor);
-                        let proto = lookup(vm, callee.as_cell(), vm.prototype);
-                        if proto.is_error() {
-                            catch!(ctor.value());
-                        }
-                        let proto = if proto.value().is_cell() {
-                            proto.value()
-                        } else {
-                            Value::undefined()
-                        };
-                        if ctor.is_error() {
-                            catch!(ctor.value());
-                        } else if ctor.value().is_cell() && ctor.value().as_cell().is_function() {
-                            let this = RegularObj::new(&mut vm.heap, proto);
-                            let result = crate::jit::operations::operation_call_func(
-                                callframe,
-                                ctor.value(),
-                                callee_r,
-                                argc,
-                                Value::from(this.cast()),
-                            );
-                            if result.is_okay() {
-                                callframe.put_register(dest, Value::from(this.cast()));
-                            } else {
-                                catch!(result.value());
-                            }
-                        } else {
-                            catch!(Value::from(
-                                WaffleString::new(&mut vm.heap, "constructor is not a function!")
-                                    .cast()
-                            ));
-                        }
-                    } else {
-                        catch!(Value::from(
-                            WaffleString::new(&mut vm.heap, "Can't find constructor").cast()
-                        ));
-                    }
+                let result = operation_new(callframe, callee, callee_r, argc);
+                if result.is_okay() {
+                    callframe.put_register(dest, result.value());
                 } else {
-                    catch!(Value::from(
-                        WaffleString::new(&mut vm.heap, "callee is not an object/function!").cast()
-                    ));
+                    catch!(result.value());
                 }
                 pc += 1;
             }
             Ins::Safepoint => {
                 if vm.stop_world {
-                    let sp = false;
-                    vm.heap.collect(gc::Address::from_ptr(&sp));
+                    vm.heap.collect();
                 }
                 pc += 1;
             }
@@ -756,6 +756,10 @@ pub extern "C" fn interp_loop(callframe: &mut callframe::CallFrame) -> WaffleRes
                 callframe.put_register(dest, val);
                 pc += 1;
             }
+            Ins::StoreThis(src) => {
+                callframe.this = callframe.get_register(src);
+                pc += 1;
+            }
             Ins::Neg(dest, src) => {
                 let src = callframe.get_register(src);
                 if src.is_number() {
@@ -774,7 +778,7 @@ pub extern "C" fn interp_loop(callframe: &mut callframe::CallFrame) -> WaffleRes
                 callframe.put_register(dest, Value::new_bool(!src));
                 pc += 1;
             }
-            x => todo!("NYI {}", x),
+            Ins::Enter => pc += 1,
         }
     }
 }
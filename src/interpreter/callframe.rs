@@ -11,10 +11,14 @@ pub struct CallFrame {
     pub this: Value,
     pub callee: Value,
     pub pc: u32,
+    /// Bytecode index of the last call or safepoint site reached by JIT code running in this
+    /// frame, `NO_JIT_PC` for interpreted frames.
+    pub jit_pc: usize,
     pub caller: *mut Self,
 }
 
 impl CallFrame {
+    pub const NO_JIT_PC: usize = usize::MAX;
     pub fn new(args: &[Value], regc: u32) -> Self {
         Self {
             regs: vec![Value::undefined(); regc as usize + 1],
@@ -28,6 +32,7 @@ impl CallFrame {
             callee: Value::undefined(),
             this: Value::undefined(),
             pc: 0,
+            jit_pc: Self::NO_JIT_PC,
             caller: std::ptr::null_mut(),
         }
     }
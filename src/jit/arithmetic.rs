@@ -47,7 +47,7 @@ impl<'a> JIT<'a> {
         }
     }
     pub fn emit_op_bitxor(&mut self, op: &Ins) {
-        if let Ins::BitOr(dest, op1, op2) = op {
+        if let Ins::BitXor(dest, op1, op2) = op {
             let left_reg = T1;
             let right_reg = T2;
             let result_reg = T0;
@@ -303,7 +303,7 @@ impl<'a> JIT<'a> {
         match op {
             Ins::Add(dest, src1, src2) => {
                 let meta = self.code_block.metadata(self.bytecode_index as _);
-                let math_ic = self.code_block.add_jit_addic(&meta.arith_profile);
+                let math_ic = new_math_ic(&mut self.add_ics, &meta.arith_profile);
                 self.ins_to_mathic
                     .insert(op as *const Ins, math_ic as *mut MathIC<_> as *mut u8);
                 self.emit_mathic_fast_bin(
@@ -347,7 +347,7 @@ impl<'a> JIT<'a> {
         match op {
             Ins::Sub(dest, src1, src2) => {
                 let meta = self.code_block.metadata(self.bytecode_index as _);
-                let math_ic = self.code_block.add_jit_subic(&meta.arith_profile);
+                let math_ic = new_math_ic(&mut self.sub_ics, &meta.arith_profile);
                 self.ins_to_mathic
                     .insert(op as *const Ins, math_ic as *mut MathIC<_> as *mut u8);
                 self.emit_mathic_fast_bin(
@@ -391,7 +391,7 @@ impl<'a> JIT<'a> {
         match op {
             Ins::Mul(dest, src1, src2) => {
                 let meta = self.code_block.metadata(self.bytecode_index as _);
-                let math_ic = self.code_block.add_jit_mulic(&meta.arith_profile);
+                let math_ic = new_math_ic(&mut self.mul_ics, &meta.arith_profile);
                 self.ins_to_mathic
                     .insert(op as *const Ins, math_ic as *mut MathIC<_> as *mut u8);
                 self.emit_mathic_fast_bin(
@@ -489,7 +489,7 @@ impl<'a> JIT<'a> {
             .get_mut(&(ins as *const Ins))
             .unwrap() as *mut MathICGenerationState;
         let ic = *self.ins_to_mathic.get(&(ins as *const Ins)).unwrap();
-        self.masm.add_link_task(Box::new(move |link_buffer| {
+        self.add_link_task(Box::new(move |link_buffer| {
             let state = unsafe { &mut *state };
             let math_ic = unsafe { &mut *(ic as *mut MathIC<GEN>) };
             math_ic.finalize_inline_code(state, link_buffer);
@@ -671,3 +671,16 @@ impl<'a> JIT<'a> {
         self.emit_jump_slow_to_hot(j, target as _);
     }
 }
+
+/// Math IC for `profile` owned by the code being compiled, slow paths of the code get a
+/// pointer to it.
+fn new_math_ic<GEN: MathICGenerator>(
+    ics: &mut Vec<Box<MathIC<GEN>>>,
+    profile: *const ArithProfile,
+) -> &'static mut MathIC<GEN> {
+    let mut ic = Box::new(MathIC::new());
+    ic.arith_profile = Some(profile);
+    let ptr = &mut *ic as *mut MathIC<GEN>;
+    ics.push(ic);
+    unsafe { &mut *ptr }
+}
@@ -16,24 +16,34 @@ pub struct JIT<'a> {
     pub labels: Vec<Label>,
     pub jmptable: Vec<JumpTable>,
     pub slow_cases: Vec<SlowCaseEntry>,
-    pub exception_check: Vec<(Vec<Jump>, (u32, u32))>,
     pub calls: Vec<CallRecord>,
     pub call_compilation_info: Vec<CallCompilationInfo>,
     pub link_buffer: LinkBuffer<MacroAssemblerX86>,
     pub bytecode_index: usize,
-    pub try_end: u32,
-    pub try_start: u32,
+    /// Innermost `try` handler covering each instruction.
+    pub try_handlers: Vec<Option<u32>>,
     pub ins_to_mathic_state: HashMap<*const Ins, mathic::MathICGenerationState>,
     pub ins_to_mathic: HashMap<*const Ins, *mut u8>,
     pub osr_upgrade: Vec<Jump>,
     pub exception_sink: Vec<Jump>,
     pub comments: HashMap<u32, String>,
+    pub liveness: Vec<crate::bytecode::liveness::LocalSet>,
+    pub stack_map: super::stack_map::StackMap,
+    pub osr_exits: Vec<(Jump, super::osr_exit::OSRExit)>,
+    /// Handler stack of the interpreter at the current instruction.
+    pub handlers: Vec<u32>,
+    /// Structures checked by the code.
+    pub structures: Vec<*const crate::table::Structure>,
+    pub add_ics: Vec<Box<super::mathic::MathIC<super::add_generator::AddGenerator>>>,
+    pub sub_ics: Vec<Box<super::mathic::MathIC<super::sub_generator::SubGenerator>>>,
+    pub mul_ics: Vec<Box<super::mathic::MathIC<super::mul_generator::MulGenerator>>>,
+    /// Run by `link` once the code has its final address.
+    pub link_tasks: Vec<Box<dyn FnOnce(&mut JITLinkBuffer)>>,
 }
 impl<'a> JIT<'a> {
     pub fn new(code: &'a CodeBlock) -> Self {
         Self {
-            try_end: 0,
-            try_start: 0,
+            try_handlers: vec![],
             ins_to_lbl: HashMap::new(),
             jumps_to_finalize: vec![],
             exception_sink: vec![],
@@ -48,10 +58,18 @@ impl<'a> JIT<'a> {
             ins_to_mathic: HashMap::new(),
             slow_cases: vec![],
             call_compilation_info: vec![],
-            exception_check: vec![],
             bytecode_index: 0,
             osr_upgrade: vec![],
             link_buffer: LinkBuffer::new(0 as *mut _),
+            liveness: vec![],
+            stack_map: Default::default(),
+            osr_exits: vec![],
+            handlers: vec![],
+            structures: vec![],
+            add_ics: vec![],
+            sub_ics: vec![],
+            mul_ics: vec![],
+            link_tasks: vec![],
         }
     }
     pub fn add_comment(&mut self, s: &str) {
@@ -66,6 +84,9 @@ impl<'a> JIT<'a> {
     pub fn get_comment_for(&self, off: u32) -> Option<&String> {
         self.comments.get(&off)
     }
+    pub fn add_link_task(&mut self, task: Box<dyn FnOnce(&mut JITLinkBuffer)>) {
+        self.link_tasks.push(task);
+    }
     pub fn finalize(mut self, mem: &mut Memory, dism: bool) -> (*mut u8, usize) {
         use capstone::prelude::*;
 
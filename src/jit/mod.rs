@@ -14,7 +14,6 @@ pub mod arithmetic;
 pub mod call;
 use crate::object::*;
 use crate::vtable::VTable;
-use std::mem::size_of;
 pub mod bitop_generator;
 pub mod div_generator;
 #[cfg(target_pointer_width = "64")]
@@ -22,6 +21,9 @@ pub mod jit64;
 pub mod mathic;
 pub mod mul_generator;
 pub mod operations;
+pub mod osr_exit;
+pub mod property_access;
+pub mod stack_map;
 pub mod sub_generator;
 pub mod thunk_generator;
 use crate::bytecode::*;
@@ -32,6 +34,26 @@ pub type JITTrampoline = extern "C" fn(&mut CallFrame, usize) -> WaffleResult;
 
 pub extern "C" fn safepoint_slow_path(_sp: *mut u8) {}
 
+/// Free jettisoned code whose code block has no frame running JIT code. Frames don't know
+/// which compilation they run, any JIT frame of the code block keeps all its old code.
+pub fn free_jettisoned_code() {
+    let vm = get_vm();
+    let mut running = std::collections::HashSet::new();
+    let mut frame = vm.top_call_frame;
+    while !frame.is_null() {
+        let f = unsafe { &*frame };
+        match f.code_block {
+            Some(cb) if f.jit_pc != CallFrame::NO_JIT_PC => {
+                running.insert(cb.raw());
+            }
+            _ => (),
+        }
+        frame = f.caller;
+    }
+    vm.jettisoned_code
+        .retain(|code| running.contains(&code.code_block));
+}
+
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
 pub enum JITType {
     Interp,
@@ -108,7 +130,14 @@ impl<'a> JIT<'a> {
     pub fn emit_get_virtual_register(&mut self, src: virtual_register::VirtualRegister, dest: Reg) {
         if src.is_constant() {
             let value = self.code_block.get_constant(src);
-            self.masm.move_i64(unsafe { value.u.as_int64 }, dest);
+            if value.is_cell() {
+                // cells may be moved by the GC, load them from the constant pool.
+                let slot = &self.code_block.constants[src.to_constant_index() as usize];
+                self.masm.move_i64(slot as *const Value as i64, dest);
+                self.masm.load64(Mem::Base(dest, 0), dest);
+            } else {
+                self.masm.move_i64(unsafe { value.u.as_int64 }, dest);
+            }
         } else {
             if src.is_local() {
                 self.masm.load64(
@@ -169,15 +198,21 @@ impl<'a> JIT<'a> {
         self.emit_function_prologue();
         self.masm.move_rr(AGPR0, REG_CALLFRAME);
 
+        self.liveness = crate::bytecode::liveness::compute_liveness(
+            &self.code_block.instructions,
+            self.code_block.num_vars as usize,
+        );
         self.labels = Vec::with_capacity(self.code_block.instructions.len());
         self.labels
             .resize(self.code_block.instructions.len(), Label::default());
 
+        self.try_handlers = compute_try_handlers(&self.code_block.instructions);
         self.materialize_tag_check_regs();
 
         self.private_compile_bytecode();
         self.private_compile_link_pass();
         self.private_compile_slow_cases();
+        self.private_compile_osr_exits();
 
         if MAX_FRAME_EXTENT_FOR_SLOW_PATH_CALL != 0 {
             #[cfg(target_pointer_width = "64")]
@@ -191,8 +226,6 @@ impl<'a> JIT<'a> {
                     .add32i(-(MAX_FRAME_EXTENT_FOR_SLOW_PATH_CALL as i32), SP, SP);
             }
         }
-
-        self.link_buffer = JITLinkBuffer::from_masm(&mut self.masm);
     }
     pub fn update_top_frame(&mut self) {
         self.masm.move_i64(
@@ -201,6 +234,16 @@ impl<'a> JIT<'a> {
         );
         self.masm.store64(REG_CALLFRAME, Mem::Base(SCRATCH_REG, 0));
     }
+    /// Store current bytecode index into `CallFrame::jit_pc` and record locals live at it.
+    /// Emitted at the start of every instruction that may call into the runtime.
+    pub fn record_stack_map_site(&mut self) {
+        self.masm.store64_imm32(
+            self.bytecode_index as i32,
+            Mem::Base(REG_CALLFRAME, offset_of!(CallFrame, jit_pc) as i32),
+        );
+        let live = self.liveness[self.bytecode_index].clone();
+        self.stack_map.record(self.bytecode_index as u32, live);
+    }
     pub fn add_jump(&mut self, jump: Jump, relative_offset: i32) {
         self.jmptable.push(JumpTable {
             from: jump,
@@ -209,20 +252,28 @@ impl<'a> JIT<'a> {
     }
     /// This function assumes RET0 is `WaffleResult.a` and `RET1` is `WaffleResult.b` or exception value.
     pub fn check_exception(&mut self, force: bool) {
-        if (self.bytecode_index as u32 >= self.try_start
-            && self.bytecode_index < self.try_end as usize)
-            || force
-        {
-            assert!(crate::get_vm().exception_addr() != std::ptr::null());
-            let br = self
-                .masm
-                .branch64_imm32(RelationalCondition::Equal, 1, RET0);
-            self.exception_check.last_mut().unwrap().0.push(br);
+        let br = self
+            .masm
+            .branch64_imm32(RelationalCondition::Equal, 1, RET0);
+        match self.try_handlers[self.bytecode_index] {
+            Some(handler) => self.jump_to_handler(br, handler),
+            None => {
+                debug_assert!(!force, "no handler at [{}]", self.bytecode_index);
+                self.exception_sink.push(br);
+            }
+        }
+    }
+    /// Link `jump` to `Catch` at `handler`, the exception is expected in `RET1`.
+    fn jump_to_handler(&mut self, jump: Jump, handler: u32) {
+        let label = self.labels[handler as usize];
+        if label.asm_label().is_set() {
+            // slow paths are emitted after the handler.
+            jump.link_to(&mut self.masm, label);
         } else {
-            let br = self
-                .masm
-                .branch64_imm64(RelationalCondition::Equal, RET0, 1);
-            self.exception_sink.push(br);
+            self.jmptable.push(JumpTable {
+                from: jump,
+                to_bytecode_offset: handler,
+            });
         }
     }
     fn private_compile_link_pass(&mut self) {
@@ -254,12 +305,29 @@ impl<'a> JIT<'a> {
         for i in 0..self.code_block.instructions.len() {
             self.bytecode_index = i as _;
             self.labels[i] = self.masm.label();
+            // `try` ends at its handler, `TryEnd` may also be emitted by `break`/`continue`.
+            while self.handlers.last().map_or(false, |h| *h as usize <= i) {
+                self.handlers.pop();
+            }
             let ins = &self.code_block.instructions[i];
             let mut buf = String::new();
             self.code_block
                 .dump_ins(&mut buf, self.bytecode_index as _)
                 .unwrap();
             self.add_comment(&format!("[{:4}] {}", self.bytecode_index, buf));
+            match ins {
+                Ins::Enter
+                | Ins::Move(..)
+                | Ins::Jmp(_)
+                | Ins::LoopHint
+                | Ins::Try(_)
+                | Ins::TryEnd
+                | Ins::LoadThis(_)
+                | Ins::StoreThis(_)
+                | Ins::Catch(_)
+                | Ins::Return(_) => (),
+                _ => self.record_stack_map_site(),
+            }
             match ins {
                 Ins::BitAnd { .. } => self.emit_op_bitand(ins),
                 Ins::BitOr { .. } => self.emit_op_bitor(ins),
@@ -375,19 +443,26 @@ impl<'a> JIT<'a> {
                         self.add_jump(j, *off);
                     }
                 }
-                Ins::Mod(dest, lhs, rhs) => {
-                    self.masm
-                        .pass_ptr_as_arg(crate::get_vm() as *const _ as usize, 0);
-                    self.emit_get_virtual_registers(*lhs, *rhs, AGPR1, AGPR2);
-                    self.masm.prepare_call_with_arg_count(3);
-                    self.masm
-                        .call_ptr_argc(operations::operation_value_mod as _, 3);
-                    self.emit_put_virtual_register(*dest, RET0, RET1);
+                Ins::Mod(dest, lhs, rhs) | Ins::Rem(dest, lhs, rhs) => {
+                    self.emit_binary_op_call(*dest, *lhs, *rhs, operations::operation_value_mod)
+                }
+                Ins::LShift(dest, lhs, rhs) => {
+                    self.emit_binary_op_call(*dest, *lhs, *rhs, operations::operation_value_lshift)
+                }
+                Ins::RShift(dest, lhs, rhs) => {
+                    self.emit_binary_op_call(*dest, *lhs, *rhs, operations::operation_value_rshift)
+                }
+                Ins::URShift(dest, lhs, rhs) => {
+                    self.emit_binary_op_call(*dest, *lhs, *rhs, operations::operation_value_urshift)
                 }
                 Ins::JLess { .. } => self.emit_op_jless(ins),
                 Ins::JLessEq { .. } => self.emit_op_jlesseq(ins),
-                Ins::JGreater { .. } => self.emit_op_jnless(ins),
+                Ins::JGreater { .. } => self.emit_op_jgreater(ins),
                 Ins::JGreaterEq { .. } => self.emit_op_jgreatereq(ins),
+                Ins::JNLess { .. } => self.emit_op_jnless(ins),
+                Ins::JNLessEq { .. } => self.emit_op_jnlesseq(ins),
+                Ins::JNGreater { .. } => self.emit_op_jngreater(ins),
+                Ins::JNGreaterEq { .. } => self.emit_op_jngreatereq(ins),
                 Ins::Sub { .. } => self.emit_op_sub(ins),
                 Ins::Add { .. } => self.emit_op_add(ins),
                 Ins::Mul { .. } => self.emit_op_mul(ins),
@@ -410,8 +485,21 @@ impl<'a> JIT<'a> {
                     end.link(&mut self.masm);
                     self.emit_put_virtual_register(*dst, T0, T1);
                 }
+                Ins::ToBoolean(dst, src) => {
+                    self.emit_get_virtual_register(*src, T0);
+                    let mut br = self.branch_if_truthy(T0, T1, T2, FT0, FT1, false);
+                    self.box_boolean_payload_const(false, T0);
+                    let end = self.masm.jump();
+                    br.link(&mut self.masm);
+                    self.box_boolean_payload_const(true, T0);
+                    end.link(&mut self.masm);
+                    self.emit_put_virtual_register(*dst, T0, T1);
+                }
                 Ins::Call(dest, this, callee, argc) => {
-                    // TODO: Use code patching and fast path/slow path codegen for calls
+                    // the link thunk expects the `CallLinkInfo` in a register, the sixth
+                    // argument is passed on the stack on windows so calls are not linked there.
+                    let arg_count = if cfg!(windows) { 5 } else { 6 };
+                    self.masm.prepare_call_with_arg_count(arg_count);
                     self.masm.pass_reg_as_arg(REG_CALLFRAME, 0);
                     self.emit_get_virtual_register(*callee, AGPR1);
                     self.masm
@@ -419,9 +507,23 @@ impl<'a> JIT<'a> {
                     self.masm.pass_int32_as_arg(*argc as _, 3);
                     self.emit_get_virtual_register(*this, T0);
                     self.masm.pass_reg_as_arg(T0, 4);
-                    self.masm.prepare_call_with_arg_count(5);
-                    self.masm
-                        .call_ptr_argc(operations::operation_call_func as *const _, 5);
+                    #[cfg(windows)]
+                    {
+                        self.masm
+                            .call_ptr_argc(operations::operation_call_func as *const _, 5);
+                    }
+                    #[cfg(not(windows))]
+                    {
+                        let info = self.call_link_info();
+                        self.masm.pass_ptr_as_arg(info, 5);
+                        let call = self
+                            .masm
+                            .call_ptr_repatch_argc(thunk_generator::link_call_thunk(), 6);
+                        self.add_link_task(Box::new(move |link_buffer| {
+                            let info = unsafe { &mut *(info as *mut call_link_info::CallLinkInfo) };
+                            info.call_location = link_buffer.location_of_label(call.label) as usize;
+                        }));
+                    }
                     self.check_exception(false);
                     self.emit_put_virtual_register(*dest, RET1, RET0);
                 }
@@ -446,6 +548,71 @@ impl<'a> JIT<'a> {
                     self.masm.call_ptr_argc(new_obj as _, 1);
                     self.emit_put_virtual_register(*dest, RET1, RET0);
                 }
+                Ins::NewArray(dest, first, count) => {
+                    extern "C" fn new_array(
+                        cf: &mut CallFrame,
+                        first: virtual_register::VirtualRegister,
+                        count: u32,
+                    ) -> Value {
+                        let mut array =
+                            Array::new(&mut get_vm().heap, count as _, Value::undefined());
+                        for i in 0..count {
+                            let val = cf.get_register(virtual_register::virtual_register_for_local(
+                                first.to_local() + i as i32,
+                            ));
+                            array.set_at(i as _, val);
+                        }
+                        Value::from(array.cast())
+                    }
+                    self.masm.prepare_call_with_arg_count(3);
+                    self.masm.pass_reg_as_arg(REG_CALLFRAME, 0);
+                    self.masm
+                        .pass_int32_as_arg(unsafe { std::mem::transmute(*first) }, 1);
+                    self.masm.pass_int32_as_arg(*count as _, 2);
+                    self.masm.call_ptr_argc(new_array as _, 3);
+                    self.emit_put_virtual_register(*dest, RET0, RET1);
+                }
+                Ins::NewTuple(dest, first, count) => {
+                    extern "C" fn new_tuple(
+                        cf: &mut CallFrame,
+                        first: virtual_register::VirtualRegister,
+                        count: u32,
+                    ) -> Value {
+                        let start = first.to_local() as usize;
+                        let tuple =
+                            Tuple::new(&mut get_vm().heap, &cf.regs[start..start + count as usize]);
+                        Value::from(tuple.cast())
+                    }
+                    self.masm.prepare_call_with_arg_count(3);
+                    self.masm.pass_reg_as_arg(REG_CALLFRAME, 0);
+                    self.masm
+                        .pass_int32_as_arg(unsafe { std::mem::transmute(*first) }, 1);
+                    self.masm.pass_int32_as_arg(*count as _, 2);
+                    self.masm.call_ptr_argc(new_tuple as _, 3);
+                    self.emit_put_virtual_register(*dest, RET0, RET1);
+                }
+                Ins::Slice(dest, src, start, tail) => {
+                    self.masm.prepare_call_with_arg_count(4);
+                    self.masm
+                        .pass_ptr_as_arg(crate::get_vm() as *mut _ as usize, 0);
+                    self.emit_get_virtual_register(*src, AGPR1);
+                    self.masm.pass_int32_as_arg(*start as _, 2);
+                    self.masm.pass_int32_as_arg(*tail as _, 3);
+                    self.masm
+                        .call_ptr_argc(operations::operation_slice as _, 4);
+                    self.check_exception(false);
+                    self.emit_put_virtual_register(*dest, RET1, RET0);
+                }
+                Ins::Inherit(class, parent) => {
+                    self.masm.prepare_call_with_arg_count(3);
+                    self.masm
+                        .pass_ptr_as_arg(crate::get_vm() as *mut _ as usize, 0);
+                    self.emit_get_virtual_register(*class, AGPR1);
+                    self.emit_get_virtual_register(*parent, AGPR2);
+                    self.masm
+                        .call_ptr_argc(operations::operation_inherit as _, 3);
+                    self.check_exception(false);
+                }
                 Ins::LoadThis(dest) => {
                     self.masm.load64(
                         Mem::Base(REG_CALLFRAME, offset_of!(CallFrame, this) as i32),
@@ -453,6 +620,13 @@ impl<'a> JIT<'a> {
                     );
                     self.emit_put_virtual_register(*dest, T0, T1);
                 }
+                Ins::StoreThis(src) => {
+                    self.emit_get_virtual_register(*src, T0);
+                    self.masm.store64(
+                        T0,
+                        Mem::Base(REG_CALLFRAME, offset_of!(CallFrame, this) as i32),
+                    );
+                }
                 Ins::Safepoint => {
                     self.masm.move_i64(crate::get_vm() as *const VM as i64, T0);
                     self.masm
@@ -482,39 +656,39 @@ impl<'a> JIT<'a> {
                     };
                 }
                 Ins::Try(off) => {
-                    self.try_start = self.bytecode_index as _;
-                    self.try_end = self.bytecode_index as u32 + *off;
-                    self.exception_check
-                        .push((Vec::with_capacity(1), (self.try_start, self.try_end)));
+                    self.handlers.push(self.bytecode_index as u32 + *off);
                 }
+                // handlers are static ranges in JIT code, see `try_handlers`.
+                // `handlers` only mirrors the interpreter for OSR exits.
+                Ins::TryEnd => (),
                 Ins::Catch(dest) => {
-                    let (x, (prev_start, prev_end)) = self.exception_check.pop().unwrap();
-                    for jump in x {
-                        jump.link(&mut self.masm);
-                    }
                     self.emit_put_virtual_register(*dest, RET1, RET0);
-                    self.try_start = prev_start;
-                    self.try_end = prev_end;
                 }
                 Ins::Throw(reg) => {
-                    self.emit_get_virtual_register(*reg, NON_CALLEE_SAVE_T0);
-                    if cfg!(not(windows)) {
-                        self.masm.move_i32(1, RET0);
-                        self.masm.move_rr(NON_CALLEE_SAVE_T0, RET1);
-                    }
-                    self.function_epilogue(AGPR0);
-                    if cfg!(windows) {
-                        self.masm.store64_imm32(
-                            1,
-                            Mem::Base(AGPR0, offset_of!(crate::WaffleResult, a) as _),
-                        );
-                        self.masm.store64(
-                            NON_CALLEE_SAVE_T0,
-                            Mem::Base(AGPR0, offset_of!(crate::WaffleResult, b) as _),
-                        );
-                        self.masm.move_rr(AGPR0, RET0);
+                    if let Some(handler) = self.try_handlers[self.bytecode_index] {
+                        self.emit_get_virtual_register(*reg, RET1);
+                        let j = self.masm.jump();
+                        self.jump_to_handler(j, handler);
+                    } else {
+                        self.emit_get_virtual_register(*reg, NON_CALLEE_SAVE_T0);
+                        if cfg!(not(windows)) {
+                            self.masm.move_i32(1, RET0);
+                            self.masm.move_rr(NON_CALLEE_SAVE_T0, RET1);
+                        }
+                        self.function_epilogue(AGPR0);
+                        if cfg!(windows) {
+                            self.masm.store64_imm32(
+                                1,
+                                Mem::Base(AGPR0, offset_of!(crate::WaffleResult, a) as _),
+                            );
+                            self.masm.store64(
+                                NON_CALLEE_SAVE_T0,
+                                Mem::Base(AGPR0, offset_of!(crate::WaffleResult, b) as _),
+                            );
+                            self.masm.move_rr(AGPR0, RET0);
+                        }
+                        self.masm.ret();
                     }
-                    self.masm.ret();
                 }
                 Ins::Return(val) => {
                     self.emit_get_virtual_register(*val, NON_CALLEE_SAVE_T0);
@@ -535,57 +709,73 @@ impl<'a> JIT<'a> {
                     self.masm.ret();
                 }
                 Ins::LoadU(dest, idx) => {
-                    self.masm.load64(
-                        Mem::Base(REG_CALLFRAME, offset_of!(CallFrame, callee) as i32),
-                        T0,
-                    );
-                    self.masm.load64(
-                        Mem::Base(T0, offset_of!(function::Function, env) as i32),
-                        T0,
-                    );
-                    const PTR: i32 = size_of::<usize>() as i32;
+                    self.emit_load_upvalue_cell(*idx, T0);
                     self.masm
-                        .load64(Mem::Base(T0, PTR + PTR + PTR + 8 * (*idx) as i32), T1);
+                        .load64(Mem::Base(T0, Upvalue::offset_of_value()), T1);
                     self.emit_put_virtual_register(*dest, T1, T0);
                 }
                 Ins::StoreU(src, idx) => {
+                    self.emit_load_upvalue_cell(*idx, T0);
+                    self.emit_satb_barrier(Mem::Base(T0, Upvalue::offset_of_value()), T1);
                     self.emit_get_virtual_register(*src, T1);
-                    self.masm.load64(
-                        Mem::Base(REG_CALLFRAME, offset_of!(CallFrame, callee) as i32),
-                        T0,
-                    );
-                    self.masm.load64(
-                        Mem::Base(T0, offset_of!(function::Function, env) as i32),
-                        T0,
-                    );
-                    const PTR: i32 = size_of::<usize>() as i32;
+                    self.emit_load_upvalue_cell(*idx, T0);
+                    self.masm
+                        .store64(T1, Mem::Base(T0, Upvalue::offset_of_value()));
+                    self.emit_write_barrier(T0, T1, T2);
+                }
+                Ins::LoadUCell(dest, idx) => {
+                    self.emit_load_upvalue_cell(*idx, T1);
+                    self.emit_put_virtual_register(*dest, T1, T0);
+                }
+                Ins::NewCell(dest, src) => {
+                    extern "C" fn new_cell(value: Value) -> Value {
+                        Value::from(Upvalue::new(&mut get_vm().heap, value).cast())
+                    }
+                    self.masm.prepare_call_with_arg_count(1);
+                    self.emit_get_virtual_register(*src, AGPR0);
+                    self.masm.call_ptr_argc(new_cell as _, 1);
+                    self.emit_put_virtual_register(*dest, RET0, RET1);
+                }
+                Ins::LoadCell(dest, cell) => {
+                    self.emit_get_virtual_register(*cell, T0);
+                    self.masm
+                        .load64(Mem::Base(T0, Upvalue::offset_of_value()), T1);
+                    self.emit_put_virtual_register(*dest, T1, T0);
+                }
+                Ins::StoreCell(cell, src) => {
+                    self.emit_get_virtual_register(*cell, T0);
+                    self.emit_satb_barrier(Mem::Base(T0, Upvalue::offset_of_value()), T1);
+                    self.emit_get_virtual_register(*src, T1);
+                    self.emit_get_virtual_register(*cell, T0);
                     self.masm
-                        .store64(T1, Mem::Base(T0, PTR + PTR + PTR + 8 * (*idx) as i32));
+                        .store64(T1, Mem::Base(T0, Upvalue::offset_of_value()));
+                    self.emit_write_barrier(T0, T1, T2);
                 }
-                Ins::Closure(func, count) => {
+                Ins::Closure(func, first, count) => {
                     extern "C" fn closure(
                         callframe: &mut CallFrame,
-                        mut func: Ref<function::Function>,
+                        func: Ref<function::Function>,
+                        first: virtual_register::VirtualRegister,
                         count: u32,
-                        f: virtual_register::VirtualRegister,
-                    ) {
-                        let values = &callframe.regs
-                            [f.to_local() as usize + 1..f.to_local() as usize + count as usize];
-                        let mut array =
-                            Array::new(&mut crate::get_vm().heap, values.len(), Value::undefined());
-                        for (i, val) in values.iter().enumerate() {
-                            array.set_at(i, *val);
+                    ) -> Value {
+                        let start = first.to_local() as usize;
+                        let cells = &callframe.regs[start..start + count as usize];
+                        let heap = &mut crate::get_vm().heap;
+                        let mut env = Array::new(heap, cells.len(), Value::undefined());
+                        for (i, cell) in cells.iter().enumerate() {
+                            env.set_at(i, *cell);
                         }
-                        func.env = Some(array);
+                        Value::from(func.new_closure(heap, env).cast())
                     }
                     self.emit_get_virtual_register(*func, AGPR1);
                     let j = self.branch_if_not_cell(AGPR1, true);
                     let j2 = self.branch_if_not_type(AGPR1, &function::FUNCTION_VTBL);
                     self.masm.move_rr(REG_CALLFRAME, AGPR0);
-                    self.masm.move_i32(*count as _, AGPR2);
                     self.masm
-                        .move_i32(unsafe { std::mem::transmute(*func) }, AGPR3);
+                        .move_i32(unsafe { std::mem::transmute(*first) }, AGPR2);
+                    self.masm.move_i32(*count as _, AGPR3);
                     self.masm.call_ptr(closure as _);
+                    self.emit_put_virtual_register(*func, RET0, RET1);
                     let done = self.masm.jump();
                     j.link(&mut self.masm);
                     j2.link(&mut self.masm);
@@ -629,34 +819,8 @@ impl<'a> JIT<'a> {
                         .call_ptr_argc(operations::operation_put_by as _, 4);
                     self.check_exception(false);
                 }
-                Ins::LoadId(dest, object, key) => {
-                    self.masm.prepare_call_with_arg_count(3);
-                    self.masm
-                        .pass_ptr_as_arg(crate::get_vm() as *mut _ as usize, 0);
-                    self.emit_get_virtual_register(*object, AGPR1);
-                    self.masm.move_i64(
-                        unsafe { self.code_block.constants[*key as usize].u.as_int64 },
-                        AGPR2,
-                    );
-                    self.masm
-                        .call_ptr_argc(operations::operation_get_by as _, 3);
-                    self.check_exception(false);
-                    self.emit_put_virtual_register(*dest, RET1, RET0);
-                }
-                Ins::StoreId(object, key, value) => {
-                    self.masm.prepare_call_with_arg_count(4);
-                    self.masm
-                        .pass_ptr_as_arg(crate::get_vm() as *mut _ as usize, 0);
-                    self.emit_get_virtual_register(*object, AGPR1);
-                    self.masm.move_i64(
-                        unsafe { self.code_block.constants[*key as usize].u.as_int64 },
-                        AGPR2,
-                    );
-                    self.emit_get_virtual_register(*value, AGPR3);
-                    self.masm
-                        .call_ptr_argc(operations::operation_put_by as _, 4);
-                    self.check_exception(false);
-                }
+                Ins::LoadId { .. } => self.emit_op_get_by_id(ins),
+                Ins::StoreId { .. } => self.emit_op_put_by_id(ins),
                 Ins::Enter => {}
                 Ins::Jmp(off) => {
                     let j = self.masm.jump();
@@ -673,35 +837,32 @@ impl<'a> JIT<'a> {
                         );
                         let val = cf.get_register(src);
                         let s = c.as_cell().cast::<WaffleString>();
-                        let vm = get_vm();
-                        if vm.globals.has(s.str()) {
-                            vm.globals.insert(s.str(), val);
+                        if operations::store_global(cf, s.str(), val) {
                             WaffleResult::okay(Value::undefined())
                         } else {
                             WaffleResult::error(Value::from(
                                 WaffleString::new(
-                                    &mut vm.heap,
+                                    &mut get_vm().heap,
                                     &format!("global '{}' not found", runtime::val_str(c)),
                                 )
                                 .cast(),
                             ))
                         }
                     }
+                    self.masm.prepare_call_with_arg_count(3);
                     self.masm.pass_reg_as_arg(REG_CALLFRAME, 0);
                     self.masm
                         .pass_int32_as_arg(unsafe { std::mem::transmute(*src) }, 1);
                     self.masm.pass_int32_as_arg(*ix as _, 2);
-                    self.masm.prepare_call_with_arg_count(3);
                     self.masm.call_ptr_argc(store_global as _, 3);
+                    self.check_exception(false);
                 }
                 Ins::LoadGlobal(dest, ix) => {
                     unsafe extern "C" fn load_global(cf: &mut CallFrame, key: u32) -> WaffleResult {
                         let c = cf.code_block.unwrap().get_constant(
                             virtual_register::VirtualRegister::new_constant_index(key as _),
                         );
-                        libc::printf(b"%p\n".as_ptr().cast(), c.as_cell().ptr);
                         if c.is_cell() && c.as_cell().is_string() {
-                            println!("cell {}", c.as_cell().cast::<WaffleString>().str());
                             let val = get_vm()
                                 .globals
                                 .lookup(c.as_cell().cast::<WaffleString>().str());
@@ -725,16 +886,26 @@ impl<'a> JIT<'a> {
                             .cast(),
                         ))
                     }
-                    self.masm.prepare_call_with_arg_count(2);
-                    self.masm.pass_int32_as_arg(*ix as i32, 1);
-                    self.masm.pass_reg_as_arg(REG_CALLFRAME, 0);
-                    //self.masm.add64_imm32(8, SP, SP);
-                    self.masm.call_ptr_argc(load_global as *const _, 2);
-                    //self.masm.sub64_imm32(8, SP);
-                    self.check_exception(false);
-                    self.emit_put_virtual_register(*dest, RET1, RET0);
+                    if let Some(global) = self.speculated_global(*ix) {
+                        // compile the value as a constant, leave to the interpreter once the
+                        // global is redefined.
+                        self.masm.move_i64(&global.redefined as *const bool as i64, T0);
+                        self.masm.load8(Mem::Base(T0, 0), T0);
+                        let redefined = self.masm.branch32_test(ResultCondition::NonZero, T0, T0);
+                        self.add_osr_exit(redefined);
+                        self.masm.move_i64(unsafe { global.value.u.as_int64 }, T0);
+                        self.emit_put_virtual_register(*dest, T0, T1);
+                    } else {
+                        self.masm.prepare_call_with_arg_count(2);
+                        self.masm.pass_int32_as_arg(*ix as i32, 1);
+                        self.masm.pass_reg_as_arg(REG_CALLFRAME, 0);
+                        //self.masm.add64_imm32(8, SP, SP);
+                        self.masm.call_ptr_argc(load_global as *const _, 2);
+                        //self.masm.sub64_imm32(8, SP);
+                        self.check_exception(false);
+                        self.emit_put_virtual_register(*dest, RET1, RET0);
+                    }
                 }
-                _ => todo!("{:?}", ins),
             }
         }
         self.add_comment("\t(End of main path)");
@@ -807,6 +978,7 @@ impl<'a> JIT<'a> {
                     self.emit_put_virtual_register(*dst, RET0, RET1);
                 }
                 Ins::BitAnd(dest, lhs, rhs) => {
+                    self.link_all_slow_cases(&mut iter);
                     extern "C" fn and(x: Value, y: Value) -> Value {
                         if x.is_number() && y.is_number() {
                             Value::new_int(
@@ -823,6 +995,7 @@ impl<'a> JIT<'a> {
                     self.emit_put_virtual_register(*dest, RET0, RET1);
                 }
                 Ins::BitOr(dest, lhs, rhs) => {
+                    self.link_all_slow_cases(&mut iter);
                     extern "C" fn or(x: Value, y: Value) -> Value {
                         if x.is_number() && y.is_number() {
                             Value::new_int(
@@ -839,6 +1012,7 @@ impl<'a> JIT<'a> {
                     self.emit_put_virtual_register(*dest, RET0, RET1);
                 }
                 Ins::BitXor(dest, lhs, rhs) => {
+                    self.link_all_slow_cases(&mut iter);
                     extern "C" fn xor(x: Value, y: Value) -> Value {
                         if x.is_number() && y.is_number() {
                             Value::new_int(
@@ -880,64 +1054,135 @@ impl<'a> JIT<'a> {
                 }
                 Ins::JLessEq { .. } => {
                     self.emit_slow_op_jlesseq(curr, &mut iter);
-                    self.bytecode_index += 1;
                 }
                 Ins::JLess { .. } => {
                     self.emit_slow_op_jless(curr, &mut iter);
-                    self.bytecode_index += 1;
                 }
                 Ins::JNGreaterEq { .. } => {
-                    self.emit_slow_op_jlesseq(curr, &mut iter);
-                    self.bytecode_index += 1;
+                    self.emit_slow_op_jngreatereq(curr, &mut iter);
                 }
                 Ins::JNGreater { .. } => {
-                    self.emit_slow_op_jless(curr, &mut iter);
-                    self.bytecode_index += 1;
+                    self.emit_slow_op_jngreater(curr, &mut iter);
+                }
+                Ins::JNLessEq { .. } => {
+                    self.emit_slow_op_jnlesseq(curr, &mut iter);
+                }
+                Ins::JNLess { .. } => {
+                    self.emit_slow_op_jnless(curr, &mut iter);
                 }
                 Ins::JGreaterEq { .. } => {
                     self.emit_slow_op_jgreatereq(curr, &mut iter);
-                    self.bytecode_index += 1;
                 }
                 Ins::JGreater { .. } => {
                     self.emit_slow_op_jgreater(curr, &mut iter);
-                    self.bytecode_index += 1;
                 }
                 Ins::Add(_src1, _src2, _dest) => {
                     self.emit_slow_op_add(curr, &mut iter);
-                    self.bytecode_index += 1;
                 }
                 Ins::Div(..) => {
                     self.link_all_slow_cases(&mut iter);
-                    self.bytecode_index += 1;
                 }
                 Ins::Sub { .. } => {
                     self.emit_slow_op_sub(curr, &mut iter);
-                    self.bytecode_index += 1;
                 }
                 Ins::Mul { .. } => {
                     self.emit_slow_op_mul(curr, &mut iter);
-                    self.bytecode_index += 1;
+                }
+                Ins::LoadId { .. } => {
+                    self.emit_slow_op_get_by_id(curr, &mut iter);
+                }
+                Ins::StoreId { .. } => {
+                    self.emit_slow_op_put_by_id(curr, &mut iter);
                 }
                 Ins::Safepoint => {
                     self.link_all_slow_cases(&mut iter);
-                    extern "C" fn safepoint(_vm: &mut crate::VM, _stack_top: *const u8) {
-                        let vm = get_vm();
-                        vm.heap.collect(gc::Address::from_ptr(_stack_top));
+                    extern "C" fn safepoint(vm: &mut crate::VM) {
+                        vm.heap.collect();
                     }
-                    self.masm.prepare_call_with_arg_count(2);
+                    self.masm.prepare_call_with_arg_count(1);
                     self.masm
                         .pass_ptr_as_arg(crate::get_vm() as *const _ as usize, 0);
-                    self.masm.pass_reg_as_arg(SP, 1);
-                    self.masm.call_ptr_argc(safepoint as *const _, 2);
-                    self.bytecode_index += 1;
+                    self.masm.call_ptr_argc(safepoint as *const _, 1);
                 }
-                _ => (),
+                _ => unreachable!("no slow path for {}", curr),
             }
+            // continue with the next instruction on the main path.
             let jump = self.masm.jump();
-            self.emit_jump_slow_to_hot(jump, 0);
+            self.emit_jump_slow_to_hot(jump, 1);
         }
         self.add_comment("\t(End of Slow Path)");
     }
+    /// Address of the inline cache of the current `LoadId`/`StoreId`, shared with the
+    /// interpreter through the metadata of the code block.
+    pub fn property_cache(&self) -> usize {
+        &self.code_block.metadata[self.bytecode_index].property_cache as *const _ as usize
+    }
+    /// Address of the call link info of the current `Call`.
+    pub fn call_link_info(&self) -> usize {
+        &self.code_block.metadata[self.bytecode_index].call_link_info as *const _ as usize
+    }
+    /// Call `operation(vm, lhs, rhs)` and store its result to `dest`, used by operators
+    /// without an inline fast path.
+    pub fn emit_binary_op_call(
+        &mut self,
+        dest: virtual_register::VirtualRegister,
+        lhs: virtual_register::VirtualRegister,
+        rhs: virtual_register::VirtualRegister,
+        operation: extern "C" fn(&VM, Value, Value) -> Value,
+    ) {
+        self.masm
+            .pass_ptr_as_arg(crate::get_vm() as *const _ as usize, 0);
+        self.emit_get_virtual_registers(lhs, rhs, AGPR1, AGPR2);
+        self.masm.prepare_call_with_arg_count(3);
+        self.masm.call_ptr_argc(operation as _, 3);
+        self.emit_put_virtual_register(dest, RET0, RET1);
+    }
+    /// Load upvalue cell `idx` of the current closure into `dst`.
+    pub fn emit_load_upvalue_cell(&mut self, idx: u32, dst: Reg) {
+        self.masm.load64(
+            Mem::Base(REG_CALLFRAME, offset_of!(CallFrame, callee) as i32),
+            dst,
+        );
+        self.masm.load64(
+            Mem::Base(dst, offset_of!(function::Function, env) as i32),
+            dst,
+        );
+        self.masm
+            .load64(Mem::Base(dst, Array::offset_of_data()), dst);
+        self.masm.load64(Mem::Base(dst, 8 * idx as i32), dst);
+    }
+    /// Emit snapshot-at-the-beginning barrier for the value about to be overwritten at
+    /// `slot`. Calls into the runtime only while marking, the call clobbers caller saved
+    /// registers so the stored object has to be reloaded afterwards.
+    pub fn emit_satb_barrier(&mut self, slot: Mem, tmp: Reg) {
+        self.masm
+            .move_i64(&crate::get_vm().heap.marking as *const bool as i64, tmp);
+        self.masm.load8(Mem::Base(tmp, 0), tmp);
+        let not_marking = self.masm.branch32_test(ResultCondition::Zero, tmp, tmp);
+        self.masm.prepare_call_with_arg_count(1);
+        self.masm.load64(slot, AGPR0);
+        self.masm
+            .call_ptr_argc(operations::operation_satb_barrier as *const _, 1);
+        not_marking.link(&mut self.masm);
+    }
+    /// Emit write barrier for a store of `value` into `object`. Only stores of cells into
+    /// objects that are not remembered yet leave JIT code. `tmp` is clobbered.
+    pub fn emit_write_barrier(&mut self, object: Reg, value: Reg, tmp: Reg) {
+        let not_cell = self.branch_if_not_cell(value, true);
+        self.masm.load64(Mem::Base(object, 0), tmp);
+        let remembered = self.masm.branch64_test_imm32(
+            ResultCondition::NonZero,
+            tmp,
+            REMEMBERED_BIT as i32,
+        );
+        self.masm.prepare_call_with_arg_count(2);
+        self.masm.pass_reg_as_arg(object, 0);
+        self.masm.pass_reg_as_arg(value, 1);
+        self.masm
+            .call_ptr_argc(operations::operation_write_barrier as *const _, 2);
+        not_cell.link(&mut self.masm);
+        remembered.link(&mut self.masm);
+    }
     pub fn emit_jump_slow_to_hot(&mut self, j: Jump, relative_offset: i32) {
         let label = self.labels[(self.bytecode_index as i32 as i32 + relative_offset) as usize];
         j.link_to(&mut self.masm, label);
@@ -979,11 +1224,18 @@ impl<'a> JIT<'a> {
     ) {
         self.link_all_slow_cases_for_bytecode_index(iter, self.bytecode_index as _);
     }
+    /// Copy the code to memory owned by a new `JITCode` of the code block and link it. The
+    /// code the code block had before is retired, frames running it keep it alive.
     pub fn link(&mut self) {
-        let patch_buffer = &mut self.link_buffer;
-        if patch_buffer.did_fail_to_allocate() {
-            panic!("Cannot allocate link buf");
+        let size = self.masm.asm.data().len();
+        let mut memory = masm::linkbuffer::Memory::new();
+        let code = memory.allocate(size, 8).expect("Cannot allocate JIT code");
+        unsafe {
+            std::ptr::copy_nonoverlapping(self.masm.asm.data().as_ptr(), code, size);
         }
+        self.link_buffer = JITLinkBuffer::new(code);
+        self.link_buffer.size = size;
+        let patch_buffer = &mut self.link_buffer;
         while let Some(record) = self.calls.pop() {
             if record.callee != 0 {
                 patch_buffer.link_call(record.from, record.callee as *const _);
@@ -998,9 +1250,30 @@ impl<'a> JIT<'a> {
                 );
             }
         }
-        self.link_buffer.perform_finalization();
-        self.code_block.jit_data().code_map = code_map;
-        self.code_block.jit_data().executable_addr = self.link_buffer.code as usize;
+        // caches forget the call sites of the old code before the new code links its own.
+        self.code_block.retire_code();
+        for task in std::mem::take(&mut self.link_tasks) {
+            task(&mut self.link_buffer);
+        }
+        memory.set_readable_and_executable();
+        let mut jit_data = self.code_block.jit_data();
+        jit_data.code_map = code_map;
+        if jit_data.stack_map.is_empty() {
+            jit_data.stack_map = std::mem::take(&mut self.stack_map);
+        }
+        for (_, exit) in self.osr_exits.drain(..) {
+            jit_data.osr_exits.insert(exit.bytecode_index, exit);
+        }
+        jit_data.code = Some(JITCode {
+            code_block: self.code_block,
+            memory,
+            add_ics: std::mem::take(&mut self.add_ics),
+            sub_ics: std::mem::take(&mut self.sub_ics),
+            mul_ics: std::mem::take(&mut self.mul_ics),
+            structures: std::mem::take(&mut self.structures),
+            stubs: vec![],
+        });
+        jit_data.executable_addr = code as usize;
     }
 
     pub fn disasm(&mut self) {
@@ -1105,14 +1378,18 @@ impl<'a> JIT<'a> {
         }
         is_string.link(&mut self.masm);
 
-        let j = self.masm.branch64_imm64(
+        self.masm.load64(
+            Mem::Absolute(&crate::get_vm().empty_string as *const Value as usize),
+            scratch,
+        );
+        let j = self.masm.branch64(
             if invert {
                 RelationalCondition::Equal
             } else {
                 RelationalCondition::NotEqual
             },
             value,
-            unsafe { crate::get_vm().empty_string.u.as_int64 },
+            scratch,
         );
         truthy.push(j);
         done.push(self.masm.jump());
@@ -1207,6 +1484,21 @@ impl<'a> JIT<'a> {
     }
 }
 
+/// Innermost `try` handler of every instruction, `Try` covers instructions up to its handler.
+pub fn compute_try_handlers(code: &[Ins]) -> Vec<Option<u32>> {
+    let mut handlers = vec![None; code.len()];
+    for (i, ins) in code.iter().enumerate() {
+        if let Ins::Try(off) = ins {
+            let handler = i as u32 + *off;
+            // nested `try` comes later and overwrites the outer handler.
+            for h in handlers[i..handler as usize].iter_mut() {
+                *h = Some(handler);
+            }
+        }
+    }
+    handlers
+}
+
 pub fn disasm_code(comments: Option<&HashMap<u32, String>>, code: *const u8, len: usize) {
     let code_slice = unsafe { std::slice::from_raw_parts(code, len) };
     use capstone::prelude::*;
@@ -1228,3 +1520,213 @@ pub fn disasm_code(comments: Option<&HashMap<u32, String>>, code: *const u8, len
         println!("\t{}", i);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::compute_try_handlers;
+    use crate::bytecode::virtual_register::VirtualRegister;
+    use crate::bytecode::Ins;
+    use crate::testing::*;
+    use crate::*;
+
+    /// Programs covering every opcode the bytecompiler emits, each one is run by the
+    /// interpreter and by the baseline JIT and both results have to print the same.
+    const CASES: &[(&str, &str)] = &[
+        (
+            "int arithmetic",
+            "let a = 7\nlet b = 2\n(a + b, a - b, a * b, a / b, a % b, -a)",
+        ),
+        (
+            "double arithmetic",
+            "let a = 7.5\nlet b = 2\n(a + b, a - b, a * b, a / b, a % b, -a)",
+        ),
+        (
+            "bit operations",
+            "let a = 1000\n(a << 3, a >> 2, -a >>> 28, a & 12, a | 3, a ^ 5)",
+        ),
+        (
+            "comparisons",
+            "let a = 1\nlet b = 2.5\n(a < b, a <= b, a > b, a >= b, a == b, a != b, !a)",
+        ),
+        (
+            "branches",
+            "let a = 3\nlet r = 0\nif a > 2.5 { r = r + 1 }\nif a <= 1 { r = r + 10 }\n\
+             if a >= 3 { r = r + 100 }\nif a < 4.5 { r = r + 1000 }\nif a == 3 { r = r + 1 }\n\
+             if a != 3 { r = r + 1 }\nif !a { r = 0 }\nr",
+        ),
+        (
+            "loops",
+            "let i = 0\nlet s = 0\nwhile i < 100 { s = s + i\ni = i + 1 }\ns",
+        ),
+        ("globals", "print\nundefinedGlobal"),
+        (
+            "objects",
+            "function P(x) { this.x = x }\nlet p = new P(4)\np.y = p.x * 2\np.y",
+        ),
+        (
+            "property caches",
+            "function A(x) { this.x = x }\nfunction B(x) { this.y = 0\nthis.x = x }\n\
+             let o = 0\nlet s = 0\nlet i = 0\n\
+             while i < 10 { o = new A(i)\nif i > 5 { o = new B(i) }\no.x = o.x + 1\n\
+             s = s + o.x\ni = i + 1 }\ns",
+        ),
+        (
+            "linked calls",
+            "function f(x) { return x + 1 }\nfunction g(x) { return x * 2 }\n\
+             function k(x) { return x }\nlet h = f\nlet s = 0\nlet i = 0\n\
+             while i < 10 { if i > 4 { h = g }\nif i > 7 { h = k }\ns = s + h(i)\ni = i + 1 }\ns",
+        ),
+        (
+            "arrays",
+            "let a = [1, 2, 3]\na[1] = 5\n(a[0], a[1], a.length)",
+        ),
+        (
+            "closures",
+            "function counter() { let n = 0\nreturn function() { n = n + 1\nreturn n } }\n\
+             let c = counter()\nc()\nc()",
+        ),
+        ("exceptions", "try { throw 41 } catch e { e + 1 }"),
+        (
+            "classes",
+            "class A { function get() { return 1 } }\nclass B(A) { }\nlet b = new B()\nb.get()",
+        ),
+        (
+            "patterns",
+            "match [1, 2, 3] { [x, ..rest] => rest.length + x, _ => 0 }",
+        ),
+    ];
+
+    #[test]
+    fn test_tiers_agree() {
+        let _vm = lock();
+        for (name, src) in CASES {
+            let interpreted = run(src, false);
+            let compiled = run(src, true);
+            assert_eq!(interpreted, compiled, "'{}' differs between tiers", name);
+        }
+        // `f` is compiled with `answer` as a constant and has to exit once it is redefined.
+        get_vm().globals.insert("answer", value::Value::new_int(1));
+        let src = "function f() { return answer }\nlet a = f()\nanswer = 2\n(a, f())";
+        assert_eq!(run(src, true), "(1,2)");
+    }
+
+    #[test]
+    fn test_exceptions() {
+        let _vm = lock();
+        assert_eq!(run_both("try { throw 41 } catch e { e + 1 }"), "42");
+        assert_eq!(run_both("try { 1 } catch e { 2 }"), "1");
+        let src = "function f(x) { if x > 2 { throw x }\nreturn 0 }\n\
+                   let s = 0\nlet i = 0\n\
+                   while i < 5 { let r = try { f(i) } catch e { e * 10 }\n\
+                   s = s + r\ni = i + 1 }\ns";
+        assert_eq!(run_both(src), "70");
+        let src = "try { try { throw 1 } catch e { throw e + 1 } } catch e { e + 1 }";
+        assert_eq!(run_both(src), "3");
+        assert_eq!(run_both("throw 5"), "error: 5");
+    }
+
+    #[test]
+    fn test_literals() {
+        let _vm = lock();
+        assert_eq!(run_both("(true, false)"), "(true,false)");
+        assert_eq!(run_both("let f = |x| x * 2\nf(21)"), "42");
+        assert_eq!(run_both("function f() { return }\nf()"), "undefined");
+    }
+
+    #[test]
+    fn test_call_stubs() {
+        let _vm = lock();
+        // closures of `adder` share one stub, the site goes megamorphic after five functions.
+        let src = "function adder(n) { return |x| x + n }\nfunction f(x) { return x * 2 }\n\
+                   function g(x) { return x * 3 }\nfunction h(x) { return 0 }\n\
+                   function k(x) { return x }\nfunction m(x) { return 1 }\n\
+                   function callAll(fs) { let s = 0\nfor f in fs { s = s + f(2) }\nreturn s }\n\
+                   let a = callAll([adder(1), adder(2), f, g])\n\
+                   let b = callAll([adder(3), f, g, h, k, m, f])\n(a, b)";
+        assert_eq!(run_both(src), "(17,22)");
+        // the site in `g` relinks once `f` exits, is jettisoned and compiled again.
+        get_vm().globals.insert("answer", value::Value::new_int(1));
+        let src = "function f() { return answer }\nfunction g() { let s = 0\nlet i = 0\n\
+                   while i < 4 { if i == 2 { answer = 10 }\ns = s + f()\ni = i + 1 }\n\
+                   return s }\ng()";
+        assert_eq!(run(src, true), "22");
+    }
+
+    #[test]
+    fn test_property_stubs() {
+        let _vm = lock();
+        // `x` is inline in the first three shapes and out of line in `U`.
+        let shapes = "function P(x) { this.x = x }\nfunction Q(x) { this.a = 0\nthis.x = x }\n\
+                      function R(x) { this.b = 0\nthis.c = 0\nthis.x = x }\n\
+                      function U(x) { this.a = 0\nthis.b = 0\nthis.c = 0\nthis.d = 0\n\
+                      this.x = x }\n\
+                      function get(o) { return o.x }\nfunction set(o, v) { o.x = v }\n\
+                      function bump(os) { let s = 0\nfor o in os { set(o, get(o) + 1) }\n\
+                      for o in os { s = s + get(o) }\nreturn s }\n";
+        let src = format!(
+            "{}let a = bump([new P(1), new Q(2), new R(3), new U(4), new P(5)])\n\
+             let b = bump([new P(1), new Q(2), new R(3), new U(4), new P(5)])\n(a, b)",
+            shapes
+        );
+        assert_eq!(run_both(&src), "(20,20)");
+        // the sites go megamorphic once more than four shapes pass through them.
+        let src = format!(
+            "{}function V(x) {{ this.v = 0\nthis.x = x }}\n\
+             function W(x) {{ this.w = 0\nthis.x = x }}\n\
+             bump([new P(1), new Q(2), new R(3), new U(4), new V(5), new W(6)])",
+            shapes
+        );
+        assert_eq!(run_both(&src), "27");
+    }
+
+    #[test]
+    fn test_osr_exits() {
+        let _vm = lock();
+        let vm = get_vm();
+        // frames finish in the interpreter after an exit inside a loop, the loop tiers up
+        // again without the speculation.
+        vm.globals.insert("loopAnswer", value::Value::new_int(1));
+        let src = "let s = 0\nlet i = 0\nwhile i < 4 { if i == 2 { loopAnswer = 10 }\n\
+                   s = s + loopAnswer\ni = i + 1 }\ns";
+        assert_eq!(run(src, true), "22");
+        // no frame runs the jettisoned code anymore.
+        free_jettisoned_code();
+        assert!(vm.jettisoned_code.is_empty());
+        // stores to module variables work in both tiers, globals shadow them in both.
+        let src = "function f() { counter = counter + 1\nreturn counter }\nf()\nf()";
+        for jit in [false, true].iter() {
+            let result = run_in_module(src, *jit, |m| {
+                m.scope
+                    .insert("counter".to_owned(), value::Value::new_int(0));
+            });
+            assert_eq!(result, "2");
+        }
+        // the JIT run goes first, while `shadowed` was never redefined.
+        let src = "function f() { return shadowed }\nlet a = f()\nshadowed = 5\n(a, f())";
+        for jit in [true, false].iter() {
+            vm.globals.insert("shadowed", value::Value::new_int(1));
+            let result = run_in_module(src, *jit, |m| {
+                m.scope
+                    .insert("shadowed".to_owned(), value::Value::new_int(3));
+            });
+            assert_eq!(result, "(1,5)");
+        }
+    }
+
+    #[test]
+    fn test_try_handlers() {
+        let e = VirtualRegister::new_argument(0);
+        let code = [
+            Ins::Try(4),
+            Ins::Try(2),
+            Ins::TryEnd,
+            Ins::Catch(e),
+            Ins::Catch(e),
+            Ins::Safepoint,
+        ];
+        assert_eq!(
+            compute_try_handlers(&code),
+            vec![Some(4), Some(3), Some(3), Some(4), None, None]
+        );
+    }
+}
@@ -2,7 +2,11 @@ use super::{add_generator::*, mathic::*, sub_generator::*, *};
 use crate::value::*;
 use crate::*;
 use thunk_generator::*;
+use crate::bytecode::property_cache::{CacheState, PropertyCache};
+use property_access::{get_by_id_stub_generator, put_by_id_stub_generator};
 use virtual_register::*;
+#[cfg(not(windows))]
+use crate::bytecode::call_link_info::{CallLinkInfo, CallStub};
 macro_rules! catch {
     ($v: expr) => {
         return WaffleResult::error($v);
@@ -33,6 +37,44 @@ pub extern "C" fn operation_value_mod(_vm: &VM, op1: Value, op2: Value) -> Value
     Value::undefined()
 }
 
+/// Shift operators truncate both operands to 32 bit integers, the shift count is taken
+/// modulo 32. Non-numbers produce `undefined`.
+pub extern "C" fn operation_value_lshift(_vm: &VM, op1: Value, op2: Value) -> Value {
+    if op1.is_number() && op2.is_number() {
+        let x = to_int32(op1.to_number());
+        let y = to_int32(op2.to_number()) as u32;
+        return Value::new_int(x.wrapping_shl(y));
+    }
+    Value::undefined()
+}
+pub extern "C" fn operation_value_rshift(_vm: &VM, op1: Value, op2: Value) -> Value {
+    if op1.is_number() && op2.is_number() {
+        let x = to_int32(op1.to_number());
+        let y = to_int32(op2.to_number()) as u32;
+        return Value::new_int(x.wrapping_shr(y));
+    }
+    Value::undefined()
+}
+/// Result of `>>>` is unsigned, values above `i32::MAX` are returned as doubles.
+pub extern "C" fn operation_value_urshift(_vm: &VM, op1: Value, op2: Value) -> Value {
+    if op1.is_number() && op2.is_number() {
+        let x = to_int32(op1.to_number()) as u32;
+        let y = to_int32(op2.to_number()) as u32;
+        let res = x.wrapping_shr(y);
+        if res <= i32::MAX as u32 {
+            return Value::new_int(res as i32);
+        }
+        return Value::new_double(res as f64);
+    }
+    Value::undefined()
+}
+/// Wrap `x` to 32 bits like bitwise operators do, `NaN` and infinities become 0.
+pub fn to_int32(x: f64) -> i32 {
+    if !x.is_finite() {
+        return 0;
+    }
+    x.trunc() as i64 as i32
+}
 pub extern "C" fn operation_value_add_optimize(
     vm: &VM,
     op1: Value,
@@ -118,11 +160,63 @@ pub extern "C" fn operation_value_mul_optimize(
     operation_value_mul(vm, op1, op2)
 }
 
-pub unsafe extern "C" fn operation_link_call(
-    _callee_frame: *mut CallFrame,
-    _vm: &VM,
-) -> SlowPathReturn {
-    return SlowPathReturn::encode(0, 0);
+/// Slow path of the link thunk. Links the call site of `info` to `callee` and returns the
+/// code the thunk continues the call with. Callees without JIT code are not linked, the
+/// generic path counts their calls until they get compiled. Sites of retired code are not
+/// linked either.
+#[cfg(not(windows))]
+pub extern "C" fn operation_link_call(info: &mut CallLinkInfo, callee: Value) -> SlowPathReturn {
+    // `operation_call_func` ignores the `CallLinkInfo` passed as last argument.
+    let generic = operation_call_func as usize;
+    if info.call_location == 0 {
+        return SlowPathReturn::encode(generic, 0);
+    }
+    let target = match CallLinkInfo::target_of(callee) {
+        Some(target) => target,
+        None => return SlowPathReturn::encode(generic, 0),
+    };
+    let function = callee.as_cell().cast::<function::Function>();
+    let stub = if function.native {
+        CallStub {
+            target,
+            code_block: None,
+            entry: unsafe { std::mem::transmute(function.native_code) },
+        }
+    } else {
+        let cb = function.code_block.unwrap();
+        let addr = cb.jit_data().executable_addr;
+        if addr == 0 {
+            return SlowPathReturn::encode(generic, 0);
+        }
+        CallStub {
+            target,
+            code_block: Some(cb),
+            entry: unsafe { std::mem::transmute(addr) },
+        }
+    };
+    let code = if info.link(stub) {
+        let stub = polymorphic_call_stub_generator(info);
+        let code = stub.code as usize;
+        // the old stub jumped here, nothing returns into it.
+        info.stub = Some(stub);
+        code
+    } else {
+        info.stub = None;
+        generic
+    };
+    repatch_call(info.call_location, code);
+    SlowPathReturn::encode(code, 0)
+}
+
+/// Point the repatchable call ending at `call_location` to `target`.
+fn repatch_call(call_location: usize, target: usize) {
+    debug_assert!(call_location != 0);
+    unsafe {
+        X86Asm::repatch_pointer(
+            (call_location as *mut u8).offset(-(REPATCH_OFFSET_CALL_R11 as isize)),
+            target as *mut u8,
+        );
+    }
 }
 
 pub extern "C" fn operation_compare_eq(x: Value, y: Value) -> bool {
@@ -132,6 +226,9 @@ pub extern "C" fn operation_compare_eq(x: Value, y: Value) -> bool {
         }
         return x == y;
     }
+    if x.is_cell() != y.is_cell() {
+        return false;
+    }
     let x = x.as_cell();
     let y = y.as_cell();
     if x.is_string() && y.is_string() {
@@ -153,6 +250,12 @@ pub extern "C" fn operation_compare_eq(x: Value, y: Value) -> bool {
         }
         return true;
     }
+    if x.is_tuple() && y.is_tuple() {
+        let x = x.cast::<Tuple>();
+        let y = y.cast::<Tuple>();
+        return x.len() == y.len()
+            && (0..x.len()).all(|i| operation_compare_eq(x.get_at(i), y.get_at(i)));
+    }
     x.ptr == y.ptr
 }
 
@@ -238,30 +341,9 @@ pub extern "C" fn operation_call_func(
     argc: u32,
     this: Value,
 ) -> WaffleResult {
-    //println!("Callee {}",callee_r);
-    let args = if argc != 0 {
-        &cf.regs[callee_r.to_local() as usize + 1..callee_r.to_local() as usize + argc as usize + 1]
-    } else {
-        &cf.regs[callee_r.to_local() as usize..callee_r.to_local() as usize]
-    };
-    /*println!("[");
-    for arg in args.iter() {
-        runtime::print_val(*arg);
-    }
-    println!("]");*/
-    let vm = get_vm();
-    let passed = argc;
     if let Some((addr, _argc, vars, cb)) = get_executable_address_for(callee) {
-        let mut call_frame = vm.push_frame(args, vars);
-        call_frame.this = this;
-        call_frame.callee = callee;
-        call_frame.passed_argc = passed as u32;
-        call_frame.code_block = cb;
-        let vm = crate::get_vm();
-
-        let result = addr(call_frame);
-        vm.pop_frame();
-        return result;
+        let args = call_arguments(cf, callee_r, argc);
+        return call_in_new_frame(args, callee, &mut { this }, addr, vars, cb);
     }
 
     get_vm().throw_exception_str(&format!(
@@ -271,6 +353,86 @@ pub extern "C" fn operation_call_func(
     ))
 }
 
+/// `Call` to a callee linked by a call stub, `entry` is its JIT entry or native function.
+pub extern "C" fn operation_call_entry(
+    cf: &mut CallFrame,
+    callee: Value,
+    callee_r: VirtualRegister,
+    argc: u32,
+    this: Value,
+    entry: extern "C" fn(&mut CallFrame) -> WaffleResult,
+) -> WaffleResult {
+    let cb = callee.as_cell().cast::<function::Function>().code_block;
+    let vars = cb.map(|cb| cb.num_vars).unwrap_or(0);
+    let args = call_arguments(cf, callee_r, argc);
+    call_in_new_frame(args, callee, &mut { this }, entry, vars, cb)
+}
+
+/// The `argc` arguments passed after `callee_r`.
+fn call_arguments(cf: &CallFrame, callee_r: VirtualRegister, argc: u32) -> &[Value] {
+    if argc != 0 {
+        &cf.regs[callee_r.to_local() as usize + 1..callee_r.to_local() as usize + argc as usize + 1]
+    } else {
+        &cf.regs[callee_r.to_local() as usize..callee_r.to_local() as usize]
+    }
+}
+
+/// Push frame for the callee and run `addr` in it. `this` is rooted by the new frame while
+/// the callee runs and is updated in case a collection moved it.
+fn call_in_new_frame(
+    args: &[Value],
+    callee: Value,
+    this: &mut Value,
+    addr: extern "C" fn(&mut CallFrame) -> WaffleResult,
+    vars: u32,
+    cb: Option<Ref<CodeBlock>>,
+) -> WaffleResult {
+    let vm = get_vm();
+    let call_frame = vm.push_frame(args, vars);
+    call_frame.this = *this;
+    call_frame.callee = callee;
+    call_frame.passed_argc = args.len() as u32;
+    call_frame.code_block = cb;
+    let result = interpreter::run_frame(addr, call_frame);
+    *this = call_frame.this;
+    vm.pop_frame();
+    result
+}
+
+/// Leave JIT code at the exit to `bytecode_index` of the code block of `cf`. The code block
+/// is jettisoned, its assumption does not hold anymore. JIT code returns the result to its
+/// caller, which continues the frame in the interpreter.
+pub extern "C" fn operation_osr_exit(cf: &mut CallFrame, bytecode_index: u32) -> WaffleResult {
+    let mut cb = cf.code_block.unwrap();
+    log!("OSR exit to interpreter at [{:4}]", bytecode_index);
+    cf.pc = bytecode_index;
+    cf.handlers = cb.jit_data().osr_exits[&bytecode_index].handlers.clone();
+    cf.jit_pc = CallFrame::NO_JIT_PC;
+    cb.jettison();
+    WaffleResult::osr_exit()
+}
+
+/// Store `value` to global `name` or, like `LoadGlobal` resolves names, to variable `name`
+/// of the module of the running function. `false` if neither exists. Shared by both tiers so
+/// globals that JIT code speculates on change the same way.
+pub fn store_global(cf: &CallFrame, name: &str, value: Value) -> bool {
+    let vm = get_vm();
+    if vm.globals.has(name) {
+        vm.globals.insert(name, value);
+        return true;
+    }
+    if let Some(mut module) = cf.callee.as_cell().cast::<function::Function>().module {
+        if module.scope.contains_key(name) {
+            if let Some(old) = module.scope.insert(name.to_owned(), value) {
+                vm.heap.satb_barrier(old);
+            }
+            vm.heap.write_barrier(module.cast(), value);
+            return true;
+        }
+    }
+    false
+}
+
 pub extern "C" fn operation_new(
     cf: &mut CallFrame,
     callee: Value,
@@ -292,17 +454,15 @@ pub extern "C" fn operation_new(
             };
             if ctor.is_error() {
                 catch!(ctor.value());
-            } else if ctor.value().is_cell() && ctor.value().as_cell().is_function() {
-                let this = RegularObj::new(&mut vm.heap, proto);
-                let result = crate::jit::operations::operation_call_func(
-                    cf,
-                    ctor.value(),
-                    callee_r,
-                    argc,
-                    Value::from(this.cast()),
-                );
+            } else if let Some((addr, _argc, vars, cb)) = get_executable_address_for(ctor.value())
+            {
+                // the new object is reachable only from the frame of the constructor, a
+                // collection at one of its safepoints may move it.
+                let mut this = Value::from(RegularObj::new(&mut vm.heap, proto).cast());
+                let args = call_arguments(cf, callee_r, argc);
+                let result = call_in_new_frame(args, ctor.value(), &mut this, addr, vars, cb);
                 if result.is_okay() {
-                    return WaffleResult::okay(Value::from(this.cast()));
+                    return WaffleResult::okay(this);
                 } else {
                     catch!(result.value());
                 }
@@ -323,6 +483,70 @@ pub extern "C" fn operation_new(
     }
 }
 
+/// Slow path of the barrier emitted by `emit_satb_barrier`.
+pub extern "C" fn operation_satb_barrier(old: Value) {
+    get_vm().heap.satb_barrier(old);
+}
+
+/// Slow path of the write barrier emitted by `emit_write_barrier`.
+pub extern "C" fn operation_write_barrier(object: Ref<Obj>, value: Value) {
+    get_vm().heap.write_barrier(object, value);
+}
+
+/// Links `class.prototype` to `parent.prototype` so instances of `class` see the parent methods.
+pub extern "C" fn operation_inherit(_vm: &VM, class: Value, parent: Value) -> WaffleResult {
+    if !(parent.is_cell() && parent.as_cell().is_function()) {
+        catch!(Value::from(
+            WaffleString::new(
+                &mut get_vm().heap,
+                format!(
+                    "class parent '{}' is not a constructor",
+                    runtime::val_str(parent)
+                )
+            )
+            .cast()
+        ));
+    }
+    debug_assert!(class.is_cell() && class.as_cell().is_function());
+    let mut function = class.as_cell().cast::<function::Function>();
+    get_vm().heap.satb_barrier(function.parent);
+    function.parent = parent;
+    get_vm().heap.write_barrier(function.cast(), parent);
+    let proto = function.prototype;
+    if proto.is_cell() && proto.as_cell().is_robj() {
+        let mut proto = proto.as_cell().cast::<RegularObj>();
+        get_vm().heap.satb_barrier(proto.prototype);
+        proto.prototype = parent.as_cell().cast::<function::Function>().prototype;
+        get_vm().heap.write_barrier(proto.cast(), proto.prototype);
+    }
+    WaffleResult::okay(Value::undefined())
+}
+
+/// Copies `object[start..length - tail]` into a new array, used to bind `..rest` patterns.
+pub extern "C" fn operation_slice(vm: &VM, object: Value, start: u32, tail: u32) -> WaffleResult {
+    let len = operation_get_by(vm, object, vm.length);
+    if len.is_error() {
+        return len;
+    }
+    let len = len.value();
+    let len = if len.is_number() {
+        len.to_number() as usize
+    } else {
+        0
+    };
+    let end = len.saturating_sub(tail as usize);
+    // safe to keep unrooted, `operation_get_by` does not reach a safepoint.
+    let mut array = Array::new(&mut get_vm().heap, 0, Value::undefined());
+    for i in start as usize..end {
+        let val = operation_get_by(vm, object, Value::new_int(i as _));
+        if val.is_error() {
+            return val;
+        }
+        array.push(val.value());
+    }
+    WaffleResult::okay(Value::from(array.cast()))
+}
+
 pub fn get_executable_address_for(
     v: Value,
 ) -> Option<(
@@ -403,3 +627,70 @@ pub fn operation_put_by(vm: &VM, object: Value, key: Value, value: Value) -> Waf
         .cast(),
     ))
 }
+
+/// `LoadId` with the inline cache of the instruction, `key` is always a string constant.
+pub extern "C" fn operation_get_by_id(
+    vm: &VM,
+    object: Value,
+    key: Value,
+    cache: &mut PropertyCache,
+) -> WaffleResult {
+    if let Some(value) = cache.get(object) {
+        repatch_property_cache(cache, get_by_id_stub_generator, operation_get_by_id as _);
+        return WaffleResult::okay(value);
+    }
+    let res = operation_get_by(vm, object, key);
+    if res.is_okay() {
+        cache.record(object, key.as_cell().cast::<WaffleString>().str());
+        repatch_property_cache(cache, get_by_id_stub_generator, operation_get_by_id as _);
+    }
+    res
+}
+
+/// `StoreId` with the inline cache of the instruction, `key` is always a string constant.
+pub extern "C" fn operation_put_by_id(
+    vm: &VM,
+    object: Value,
+    key: Value,
+    value: Value,
+    cache: &mut PropertyCache,
+) -> WaffleResult {
+    if cache.put(object, value) {
+        repatch_property_cache(cache, put_by_id_stub_generator, operation_put_by_id as _);
+        return WaffleResult::okay(Value::new_bool(true));
+    }
+    let res = operation_put_by(vm, object, key, value);
+    if res.is_okay() {
+        cache.record(object, key.as_cell().cast::<WaffleString>().str());
+        repatch_property_cache(cache, put_by_id_stub_generator, operation_put_by_id as _);
+    }
+    res
+}
+
+/// Point the call of the JIT site of `cache` to a stub for its entries, megamorphic sites
+/// call `operation` directly.
+fn repatch_property_cache(
+    cache: &mut PropertyCache,
+    generator: fn(&PropertyCache) -> StubCode,
+    operation: usize,
+) {
+    if !cache.needs_repatch || cache.call_location == 0 {
+        return;
+    }
+    let target = match cache.state {
+        CacheState::Uninitialized => return,
+        CacheState::Megamorphic => {
+            cache.stub = None;
+            operation
+        }
+        _ => {
+            let stub = generator(cache);
+            let code = stub.code as usize;
+            // the old stub jumped here, nothing returns into it.
+            cache.stub = Some(stub);
+            code
+        }
+    };
+    repatch_call(cache.call_location, target);
+    cache.needs_repatch = false;
+}
@@ -0,0 +1,77 @@
+//! OSR exits from baseline JIT code back to `interp_loop`. JIT code keeps every virtual
+//! register in `CallFrame::regs`, so an exit only rebuilds `CallFrame::pc` and the handler
+//! stack of the interpreter. JIT code then returns `WaffleResult::osr_exit()` and whoever
+//! called it finishes the call in the interpreter, see `interpreter::run_frame`.
+//!
+//! Exits guard speculative assumptions of the JIT. The first frame that takes one throws
+//! the code of its code block away, later calls are interpreted until the code block tiers
+//! up again. Exits are keyed by bytecode index, every compilation has the same ones.
+use super::*;
+
+/// Interpreter state at an exit.
+pub struct OSRExit {
+    pub bytecode_index: u32,
+    /// Handlers `interp_loop` would have pushed at `bytecode_index`, innermost last.
+    pub handlers: Vec<u32>,
+}
+
+impl<'a> JIT<'a> {
+    /// Leave to the interpreter at the current instruction when `jump` is taken.
+    pub fn add_osr_exit(&mut self, jump: Jump) {
+        let exit = OSRExit {
+            bytecode_index: self.bytecode_index as u32,
+            handlers: self.handlers.clone(),
+        };
+        self.osr_exits.push((jump, exit));
+    }
+    /// Global named by constant `key` that JIT code may treat as a constant: it holds a
+    /// value that was never redefined and won't be moved by the GC. Globals shadow module
+    /// variables in loads and in `operations::store_global`, every store to the name
+    /// reaches the guarded variable.
+    pub fn speculated_global(&self, key: u32) -> Option<&'static crate::GlobalVariable> {
+        let name = self.code_block.constants[key as usize];
+        if !name.is_cell() || !name.as_cell().is_string() {
+            return None;
+        }
+        let vm = crate::get_vm();
+        let global = vm
+            .globals
+            .variable(name.as_cell().cast::<WaffleString>().str())?;
+        let value = global.value;
+        if global.redefined
+            || value.is_undefined()
+            || (value.is_cell() && vm.heap.young.contains(value.as_cell().address()))
+        {
+            return None;
+        }
+        Some(global)
+    }
+    /// Emit exit stubs, they call `operation_osr_exit` and return its result to the caller
+    /// of the JIT code.
+    pub fn private_compile_osr_exits(&mut self) {
+        let exits = std::mem::take(&mut self.osr_exits);
+        for (jump, exit) in exits.iter() {
+            self.add_comment(&format!("\t(OSR exit to [{:4}])", exit.bytecode_index));
+            jump.link(&mut self.masm);
+            self.masm.prepare_call_with_arg_count(2);
+            self.masm.pass_reg_as_arg(REG_CALLFRAME, 0);
+            self.masm.pass_int32_as_arg(exit.bytecode_index as i32, 1);
+            self.masm
+                .call_ptr_argc(operations::operation_osr_exit as *const _, 2);
+            self.function_epilogue(AGPR0);
+            if cfg!(windows) {
+                self.masm.store64(
+                    RET0,
+                    Mem::Base(AGPR0, offset_of!(crate::WaffleResult, a) as _),
+                );
+                self.masm.store64(
+                    RET1,
+                    Mem::Base(AGPR0, offset_of!(crate::WaffleResult, b) as _),
+                );
+                self.masm.move_rr(AGPR0, RET0);
+            }
+            self.masm.ret();
+        }
+        self.osr_exits = exits;
+    }
+}
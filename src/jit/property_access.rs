@@ -0,0 +1,229 @@
+use super::*;
+use crate::bytecode::property_cache::{CacheState, PropertyCache};
+use crate::table::INLINE_SLOTS;
+use thunk_generator::StubCode;
+impl<'a> JIT<'a> {
+    /// Structure and slot to compile inline, only monomorphic sites whose property lives
+    /// inside the object cell qualify.
+    fn inline_property_access(&self) -> Option<(i64, u32)> {
+        let cache = &self.code_block.metadata[self.bytecode_index].property_cache;
+        if cache.state != CacheState::Monomorphic {
+            return None;
+        }
+        let entry = cache.entries()[0];
+        if entry.slot as usize >= INLINE_SLOTS {
+            return None;
+        }
+        Some((entry.structure as i64, entry.slot))
+    }
+    /// Load `object` to `T0` and jump to the slow path unless it is a `RegularObj` with
+    /// `structure`. The code keeps `structure` alive, the cache may drop it before.
+    fn emit_structure_check(&mut self, object: virtual_register::VirtualRegister, structure: i64) {
+        crate::table::Structure::retain(structure as *const _);
+        self.structures.push(structure as *const _);
+        self.emit_get_virtual_register(object, T0);
+        let j = self.branch_if_not_cell(T0, true);
+        self.add_slow_case(j);
+        let j = self.masm.branch64_test(ResultCondition::Zero, T0, T0);
+        self.add_slow_case(j);
+        let j = self.branch_if_not_type(T0, &OBJECT_VTBL);
+        self.add_slow_case(j);
+        let j = self.masm.branch64_imm64_mem(
+            RelationalCondition::NotEqual,
+            structure,
+            Mem::Base(T0, RegularObj::offset_of_structure()),
+        );
+        self.add_slow_case(j);
+    }
+    pub fn emit_op_get_by_id(&mut self, op: &Ins) {
+        if let Ins::LoadId(dest, object, key) = op {
+            match self.inline_property_access() {
+                Some((structure, slot)) => {
+                    self.emit_structure_check(*object, structure);
+                    self.masm
+                        .load64(Mem::Base(T0, RegularObj::offset_of_inline_slot(slot)), T1);
+                    self.emit_put_virtual_register(*dest, T1, T2);
+                }
+                None => self.emit_get_by_id_call(*dest, *object, *key),
+            }
+        }
+    }
+    pub fn emit_op_put_by_id(&mut self, op: &Ins) {
+        if let Ins::StoreId(object, key, value) = op {
+            match self.inline_property_access() {
+                Some((structure, slot)) => {
+                    self.emit_structure_check(*object, structure);
+                    let slot = Mem::Base(T0, RegularObj::offset_of_inline_slot(slot));
+                    self.emit_satb_barrier(slot, T1);
+                    self.emit_get_virtual_register(*object, T0);
+                    self.emit_get_virtual_register(*value, T1);
+                    self.masm.store64(T1, slot);
+                    self.emit_write_barrier(T0, T1, T2);
+                }
+                None => self.emit_put_by_id_call(*object, *key, *value),
+            }
+        }
+    }
+    pub fn emit_slow_op_get_by_id(
+        &mut self,
+        op: &Ins,
+        slow_cases: &mut std::iter::Peekable<std::slice::Iter<'_, SlowCaseEntry>>,
+    ) {
+        self.link_all_slow_cases(slow_cases);
+        match op {
+            Ins::LoadId(dest, object, key) => self.emit_get_by_id_call(*dest, *object, *key),
+            _ => op_unreachable!(),
+        }
+    }
+    pub fn emit_slow_op_put_by_id(
+        &mut self,
+        op: &Ins,
+        slow_cases: &mut std::iter::Peekable<std::slice::Iter<'_, SlowCaseEntry>>,
+    ) {
+        self.link_all_slow_cases(slow_cases);
+        match op {
+            Ins::StoreId(object, key, value) => self.emit_put_by_id_call(*object, *key, *value),
+            _ => op_unreachable!(),
+        }
+    }
+    /// Generic `LoadId` through the runtime, which also updates the inline cache.
+    fn emit_get_by_id_call(
+        &mut self,
+        dest: virtual_register::VirtualRegister,
+        object: virtual_register::VirtualRegister,
+        key: u32,
+    ) {
+        let cache = self.property_cache();
+        self.masm.prepare_call_with_arg_count(4);
+        self.masm
+            .pass_ptr_as_arg(crate::get_vm() as *mut _ as usize, 0);
+        self.emit_get_virtual_register(object, AGPR1);
+        self.emit_get_virtual_register(
+            virtual_register::VirtualRegister::new_constant_index(key as _),
+            AGPR2,
+        );
+        self.masm.pass_ptr_as_arg(cache, 3);
+        let call = self
+            .masm
+            .call_ptr_repatch_argc(operations::operation_get_by_id as _, 4);
+        self.link_property_cache_call(call);
+        self.check_exception(false);
+        self.emit_put_virtual_register(dest, RET1, RET0);
+    }
+    /// Generic `StoreId` through the runtime, which also updates the inline cache.
+    fn emit_put_by_id_call(
+        &mut self,
+        object: virtual_register::VirtualRegister,
+        key: u32,
+        value: virtual_register::VirtualRegister,
+    ) {
+        let cache = self.property_cache();
+        self.masm.prepare_call_with_arg_count(5);
+        self.masm
+            .pass_ptr_as_arg(crate::get_vm() as *mut _ as usize, 0);
+        self.emit_get_virtual_register(object, AGPR1);
+        self.emit_get_virtual_register(
+            virtual_register::VirtualRegister::new_constant_index(key as _),
+            AGPR2,
+        );
+        self.emit_get_virtual_register(value, AGPR3);
+        self.masm.pass_ptr_as_arg(cache, 4);
+        let call = self
+            .masm
+            .call_ptr_repatch_argc(operations::operation_put_by_id as _, 5);
+        self.link_property_cache_call(call);
+        self.check_exception(false);
+    }
+    /// Tell the property cache of the current instruction where its call is, the runtime
+    /// repatches the call to a stub for the cached structures.
+    fn link_property_cache_call(&mut self, call: masm::Call) {
+        let cache = self.property_cache();
+        self.add_link_task(Box::new(move |link_buffer| {
+            let cache = unsafe { &mut *(cache as *mut PropertyCache) };
+            cache.call_location = link_buffer.location_of_label(call.label) as usize;
+            cache.needs_repatch = true;
+        }));
+    }
+}
+
+/// Jump to `miss` unless `object` is a `RegularObj`.
+fn emit_object_check(jit: &mut JIT<'_>, object: Reg, miss: &mut JumpList) {
+    miss.push(jit.branch_if_not_cell(object, true));
+    miss.push(jit.masm.branch64_test(ResultCondition::Zero, object, object));
+    miss.push(jit.branch_if_not_type(object, &OBJECT_VTBL));
+}
+
+/// Stub for a `LoadId` site, entered with the arguments of `operation_get_by_id`. Cached
+/// properties inside the object cell are loaded by the stub, other accesses continue in
+/// `operation_get_by_id`.
+pub fn get_by_id_stub_generator(cache: &PropertyCache) -> StubCode {
+    let cb = CodeBlock::new();
+    let mut jit = JIT::new(&cb);
+    let mut miss = JumpList::new();
+    emit_object_check(&mut jit, AGPR1, &mut miss);
+    for entry in cache.entries() {
+        if entry.slot as usize >= INLINE_SLOTS {
+            continue;
+        }
+        let next = jit.masm.branch64_imm64_mem(
+            RelationalCondition::NotEqual,
+            entry.structure as i64,
+            Mem::Base(AGPR1, RegularObj::offset_of_structure()),
+        );
+        jit.masm.load64(
+            Mem::Base(AGPR1, RegularObj::offset_of_inline_slot(entry.slot)),
+            RET1,
+        );
+        jit.masm.move_i64(0, RET0);
+        jit.masm.ret();
+        next.link(&mut jit.masm);
+    }
+    miss.link(&mut jit.masm);
+    jit.masm
+        .move_i64(operations::operation_get_by_id as i64, T0);
+    jit.masm.far_jump_r(T0);
+    StubCode::new(jit)
+}
+
+/// Stub for a `StoreId` site, entered with the arguments of `operation_put_by_id`. Stores
+/// that need a barrier call into the runtime and are left to `operation_put_by_id` like
+/// stores that add properties.
+pub fn put_by_id_stub_generator(cache: &PropertyCache) -> StubCode {
+    let cb = CodeBlock::new();
+    let mut jit = JIT::new(&cb);
+    let mut miss = JumpList::new();
+    jit.masm
+        .move_i64(&crate::get_vm().heap.marking as *const bool as i64, T0);
+    jit.masm.load8(Mem::Base(T0, 0), T0);
+    miss.push(jit.masm.branch32_test(ResultCondition::NonZero, T0, T0));
+    emit_object_check(&mut jit, AGPR1, &mut miss);
+    for entry in cache.entries() {
+        if entry.slot as usize >= INLINE_SLOTS {
+            continue;
+        }
+        let next = jit.masm.branch64_imm64_mem(
+            RelationalCondition::NotEqual,
+            entry.structure as i64,
+            Mem::Base(AGPR1, RegularObj::offset_of_structure()),
+        );
+        let not_cell = jit.branch_if_not_cell(AGPR3, true);
+        jit.masm.load64(Mem::Base(AGPR1, 0), T0);
+        miss.push(
+            jit.masm
+                .branch64_test_imm32(ResultCondition::Zero, T0, REMEMBERED_BIT as i32),
+        );
+        not_cell.link(&mut jit.masm);
+        jit.masm.store64(
+            AGPR3,
+            Mem::Base(AGPR1, RegularObj::offset_of_inline_slot(entry.slot)),
+        );
+        jit.masm.move_i64(0, RET0);
+        jit.masm.ret();
+        next.link(&mut jit.masm);
+    }
+    miss.link(&mut jit.masm);
+    jit.masm
+        .move_i64(operations::operation_put_by_id as i64, T0);
+    jit.masm.far_jump_r(T0);
+    StubCode::new(jit)
+}
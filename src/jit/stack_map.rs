@@ -0,0 +1,24 @@
+use crate::bytecode::liveness::LocalSet;
+use std::collections::HashMap;
+
+/// Locals holding live values at call and safepoint sites of JIT code. JIT code stores
+/// bytecode index of the site into `CallFrame::jit_pc` before leaving to the runtime, GC
+/// uses it to find out which registers of the frame are roots.
+#[derive(Default)]
+pub struct StackMap {
+    sites: HashMap<u32, LocalSet>,
+}
+
+impl StackMap {
+    pub fn record(&mut self, bytecode_index: u32, live: LocalSet) {
+        self.sites.insert(bytecode_index, live);
+    }
+
+    pub fn live_at(&self, bytecode_index: u32) -> Option<&LocalSet> {
+        self.sites.get(&bytecode_index)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sites.is_empty()
+    }
+}
@@ -1,49 +1,38 @@
 use super::*;
+#[cfg(not(windows))]
+use crate::bytecode::call_link_info::CallLinkInfo;
 use crate::*;
 
+/// Arguments of `Call` sites, thunks entered from a site pass them on unchanged.
+#[cfg(not(windows))]
+const CALL_ARGS: [Reg; 6] = [AGPR0, AGPR1, AGPR2, AGPR3, AGPR4, AGPR5];
+
+/// Calls are not linked on windows, see `Ins::Call` in `JIT::compile_without_linking`.
+#[cfg(not(windows))]
 fn slow_path_for(jit: &mut JIT<'_>, vm: &crate::VM, slow_path_func: *const u8) {
     jit.emit_function_prologue();
     jit.masm.store64(
         REG_CALLFRAME,
         Mem::Absolute(&vm.top_call_frame as *const _ as _),
     );
-    #[cfg(all(windows, target_arch = "x86_64"))]
-    {
-        // windows calling convention is weird: we have to return SlowPathReturn on stack instead of using rax and rdx
-        // so we simulate using these two regs by allocating space on stack and then loading return value from stack to
-        // these 2 registers.
+    CALL_ARGS.iter().for_each(|r| jit.masm.push(*r));
+    if MAX_FRAME_EXTENT_FOR_SLOW_PATH_CALL != 0 {
         jit.masm
             .add64_imm32(-(MAX_FRAME_EXTENT_FOR_SLOW_PATH_CALL as i32), SP, SP);
-        jit.masm.move_rr(T2, AGPR0);
-        jit.masm.move_rr(T3, AGPR2);
-        jit.masm.move_rr(AGPR0, AGPR3);
-        jit.masm.add64_imm32(32, SP, AGPR0);
-        jit.masm.move_rr(BP, AGPR1);
-        jit.masm.move_i64(slow_path_func as _, Reg::R10);
-        jit.masm.call_r(Reg::R10);
-        jit.masm.load64(Mem::Base(RET0, 8), RET1);
-        jit.masm.load64(Mem::Base(RET0, 0), RET0);
-        jit.masm
-            .add64_imm32(MAX_FRAME_EXTENT_FOR_SLOW_PATH_CALL as i32, SP, SP);
     }
-    #[cfg(not(windows))]
-    {
-        if MAX_FRAME_EXTENT_FOR_SLOW_PATH_CALL != 0 {
-            jit.masm
-                .add64_imm32(-(MAX_FRAME_EXTENT_FOR_SLOW_PATH_CALL as i32), SP, SP);
-        }
-        #[cfg(target_arch = "x86_64")]
-        const NON_ARG_GP0: Reg = Reg::R10;
+    #[cfg(target_arch = "x86_64")]
+    const NON_ARG_GP0: Reg = Reg::R10;
 
-        jit.masm.pass_reg_as_arg(T3, 0);
-        jit.masm.pass_reg_as_arg(T2, 1);
-        jit.masm.move_i64(slow_path_func as _, NON_ARG_GP0);
-        jit.masm.call_r(NON_ARG_GP0);
-        if MAX_FRAME_EXTENT_FOR_SLOW_PATH_CALL != 0 {
-            jit.masm
-                .add64_imm32(MAX_FRAME_EXTENT_FOR_SLOW_PATH_CALL as i32, SP, SP);
-        }
+    // `CallLinkInfo` of the site and the callee.
+    jit.masm.pass_reg_as_arg(AGPR5, 0);
+    jit.masm.pass_reg_as_arg(AGPR1, 1);
+    jit.masm.move_i64(slow_path_func as _, NON_ARG_GP0);
+    jit.masm.call_r(NON_ARG_GP0);
+    if MAX_FRAME_EXTENT_FOR_SLOW_PATH_CALL != 0 {
+        jit.masm
+            .add64_imm32(MAX_FRAME_EXTENT_FOR_SLOW_PATH_CALL as i32, SP, SP);
     }
+    CALL_ARGS.iter().rev().for_each(|r| jit.masm.pop(*r));
     jit.masm.function_epilogue();
     let do_not_trash = jit
         .masm
@@ -54,6 +43,7 @@ fn slow_path_for(jit: &mut JIT<'_>, vm: &crate::VM, slow_path_func: *const u8) {
     jit.masm.far_jump_r(RET0);
 }
 
+#[cfg(not(windows))]
 pub fn link_call_thunk_generator(vm: &VM) -> *const u8 {
     let cb = CodeBlock::new();
     let mut jit = JIT::new(&cb);
@@ -63,6 +53,83 @@ pub fn link_call_thunk_generator(vm: &VM) -> *const u8 {
     patch_buf.code
 }
 
+/// Thunk `Call` sites start with, it links the site to the callee and continues the call.
+#[cfg(not(windows))]
+pub fn link_call_thunk() -> *const u8 {
+    get_vm()
+        .stubs
+        .get_stub(|| link_call_thunk_generator(get_vm()))
+}
+
+/// Stub for the callees linked to `info`. It jumps to `operation_call_entry` with the JIT
+/// entry of a linked callee as last argument, other callees and calls after a callee was
+/// recompiled go back to the link thunk.
+#[cfg(not(windows))]
+pub fn polymorphic_call_stub_generator(info: &CallLinkInfo) -> StubCode {
+    let cb = CodeBlock::new();
+    let mut jit = JIT::new(&cb);
+    let vm = get_vm();
+    let mut miss = JumpList::new();
+    miss.push(jit.masm.branch64_imm64_mem(
+        RelationalCondition::NotEqual,
+        info.epoch() as i64,
+        Mem::Absolute(&vm.call_link_epoch as *const _ as _),
+    ));
+    miss.push(jit.branch_if_not_cell(AGPR1, true));
+    miss.push(jit.masm.branch64_test(ResultCondition::Zero, AGPR1, AGPR1));
+    miss.push(jit.branch_if_not_type(AGPR1, &function::FUNCTION_VTBL));
+    for stub in info.stubs() {
+        // natives have no code block, other functions have no native code.
+        let field = if stub.code_block.is_some() {
+            offset_of!(function::Function, code_block)
+        } else {
+            offset_of!(function::Function, native_code)
+        };
+        let next = jit.masm.branch64_imm64_mem(
+            RelationalCondition::NotEqual,
+            stub.target as i64,
+            Mem::Base(AGPR1, field as i32),
+        );
+        jit.masm.move_i64(stub.entry as i64, AGPR5);
+        jit.masm
+            .move_i64(operations::operation_call_entry as i64, T0);
+        jit.masm.far_jump_r(T0);
+        next.link(&mut jit.masm);
+    }
+    miss.link(&mut jit.masm);
+    jit.masm.move_i64(link_call_thunk() as i64, T0);
+    jit.masm.far_jump_r(T0);
+    StubCode::new(jit)
+}
+
+/// Machine code of an inline cache stub, freed once the cache replaces the stub.
+pub struct StubCode {
+    _memory: masm::linkbuffer::Memory,
+    pub code: *const u8,
+}
+
+impl StubCode {
+    /// Copy the code of `jit` to executable memory. Stubs use absolute addresses and jumps
+    /// inside the stub only, their code does not need linking.
+    pub fn new(jit: JIT<'_>) -> Self {
+        let comments = jit.comments;
+        let code = jit.masm.finalize();
+        let mut memory = masm::linkbuffer::Memory::new();
+        let ptr = memory.allocate(code.len(), 8).unwrap();
+        unsafe {
+            std::ptr::copy_nonoverlapping(code.as_ptr(), ptr, code.len());
+        }
+        memory.set_readable_and_executable();
+        if get_vm().disasm {
+            disasm_code(Some(&comments), ptr, code.len());
+        }
+        Self {
+            _memory: memory,
+            code: ptr,
+        }
+    }
+}
+
 #[repr(C)]
 pub struct SlowPathReturn {
     pub a: usize,
@@ -92,10 +159,9 @@ pub fn osr_from_interpreter_to_jit_generator() -> *const u8 {
         addr = AGPR1;
     }
     jit.masm.far_jump_r(addr);
-    jit.compile_without_linking();
-    jit.link();
-
-    jit.link_buffer.code
+    let mut patch_buf = JITLinkBuffer::from_masm(&mut jit.masm);
+    patch_buf.perform_finalization();
+    patch_buf.code
 }
 
 fn build_callframe(
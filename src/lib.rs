@@ -57,6 +57,8 @@ pub mod object;
 pub mod pure_nan;
 pub mod runtime;
 pub mod table;
+#[cfg(test)]
+pub(crate) mod testing;
 pub mod utils;
 pub mod value;
 pub mod vtable;
@@ -84,19 +86,48 @@ impl<'a, T> std::iter::Iterator for MutatingVecIter<'a, T> {
     }
 }
 
+/// Boxed so JIT code can read it directly.
+pub struct GlobalVariable {
+    pub value: value::Value,
+    /// Set once a defined value is replaced. JIT code may compile globals that were never
+    /// redefined as constants and has to leave through an OSR exit when this is set.
+    pub redefined: bool,
+}
+
 #[derive(Default)]
 pub struct Globals {
-    map: std::collections::HashMap<String, value::Value>,
+    map: std::collections::HashMap<String, Box<GlobalVariable>>,
 }
 impl Globals {
     pub fn lookup(&self, name: &str) -> Option<value::Value> {
-        self.map.get(name).copied()
+        self.map.get(name).map(|global| global.value)
+    }
+    pub fn variable(&self, name: &str) -> Option<&GlobalVariable> {
+        self.map.get(name).map(|global| &**global)
     }
     pub fn has(&self, name: &str) -> bool {
         self.map.contains_key(name)
     }
+    /// Define or overwrite global `name`. Initializing a global declared as `undefined` is
+    /// not a redefinition.
     pub fn insert(&mut self, name: &str, val: value::Value) {
-        self.map.insert(name.to_owned(), val);
+        match self.map.get_mut(name) {
+            Some(global) => {
+                if !global.value.is_undefined() && global.value != val {
+                    global.redefined = true;
+                }
+                global.value = val;
+            }
+            None => {
+                self.map.insert(
+                    name.to_owned(),
+                    Box::new(GlobalVariable {
+                        value: val,
+                        redefined: false,
+                    }),
+                );
+            }
+        }
     }
 }
 pub struct VM {
@@ -108,16 +139,26 @@ pub struct VM {
     pub length: value::Value,
     pub not_a_func_exc: value::Value,
     pub prototype: value::Value,
+    /// Holds native methods shared by all arrays (`push`, `pop`).
+    pub array_prototype: value::Value,
     pub stop_world: bool,
     pub dump_bc: bool,
     pub disasm: bool,
     pub opt_jit: bool,
     pub template_jit: bool,
     pub jit_threshold: u32,
+    /// Changes when linked calls may be stale, see `CallLinkInfo`.
+    pub call_link_epoch: u64,
     pub log: bool,
     pub heap: heap::Heap,
     pub stubs: JITStubs,
+    /// Jettisoned JIT code that frames may still run, see `jit::free_jettisoned_code`.
+    pub jettisoned_code: Vec<bytecode::JITCode>,
     pub globals: Globals,
+    /// Property names interned by `table::Atom`.
+    pub atoms: table::Atoms,
+    /// Root of the structure transition tree, see `table::Structure::empty`.
+    pub empty_structure: Box<table::Structure>,
     pub verbose_alloc: bool,
 }
 
@@ -150,11 +191,15 @@ impl VM {
             exception: value::Value::undefined(),
 
             globals: Default::default(),
+            atoms: Default::default(),
+            empty_structure: table::Structure::new_root(),
             jit_threshold: 25000,
+            call_link_epoch: 0,
             template_jit: true,
             verbose_alloc: false,
             disasm: false,
             stubs: JITStubs::new(),
+            jettisoned_code: vec![],
             dump_bc: false,
             stop_world: false,
             log: true,
@@ -163,10 +208,11 @@ impl VM {
             #[cfg(not(feature = "opt-jit"))]
             opt_jit: false,
             empty_string: value::Value::undefined(),
-            heap: heap::Heap::new(&_stack_start as *const *const bool as *const bool),
+            heap: heap::Heap::new(),
             length: value::Value::undefined(),
             constructor: value::Value::undefined(),
             prototype: value::Value::undefined(),
+            array_prototype: value::Value::undefined(),
             not_a_func_exc: value::Value::undefined(),
         };
         this.length =
@@ -256,6 +302,11 @@ impl WaffleResult {
         self.a == 0
     }
 
+    /// Returned by JIT code that took an OSR exit, see `interpreter::run_frame`.
+    pub fn is_osr_exit(&self) -> bool {
+        self.a == 2
+    }
+
     pub fn value(&self) -> value::Value {
         unsafe { std::mem::transmute(self.b) }
     }
@@ -272,5 +323,8 @@ impl WaffleResult {
             b: unsafe { std::mem::transmute(v) },
         }
     }
+    pub fn osr_exit() -> Self {
+        Self { a: 2, b: 0 }
+    }
 }
 pub type WaffleInternalFn = extern "C" fn(&mut interpreter::callframe::CallFrame) -> WaffleResult;
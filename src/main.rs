@@ -1,6 +1,8 @@
 use bytecompiler::*;
+use frontend::ast::*;
 use frontend::parser::*;
 use frontend::reader::*;
+use std::io::Write;
 use std::path::PathBuf;
 use structopt::StructOpt;
 use value::*;
@@ -35,11 +37,33 @@ struct Opts {
     jit_threshold: usize,
     #[structopt(short, long = "verbose")]
     verbose: bool,
-    /// Input file
+    /// Input file, starts interactive REPL when omitted
     #[structopt(parse(from_os_str))]
-    input: PathBuf,
+    input: Option<PathBuf>,
     #[structopt(long = "verboseAlloc", help = "Verbose log when allocating")]
     verbose_alloc: bool,
+    #[structopt(
+        long = "verifyBarriers",
+        help = "Check that no old-to-young store misses the write barrier (slow)"
+    )]
+    verify_barriers: bool,
+    #[structopt(long = "gcStats", help = "Print heap statistics at exit")]
+    gc_stats: bool,
+    #[structopt(
+        long = "gcStress",
+        help = "Collect at every safepoint (slow)"
+    )]
+    gc_stress: bool,
+    #[structopt(
+        long = "verifyHeap",
+        help = "Check every reachable reference before and after each collection (slow)"
+    )]
+    verify_heap: bool,
+    #[structopt(
+        long = "heapSnapshot",
+        help = "Write heap snapshot in Chrome .heapsnapshot format to this file at exit"
+    )]
+    heap_snapshot: Option<String>,
 }
 
 fn main() {
@@ -50,21 +74,43 @@ fn main() {
     vm.disasm = opt.disasm;
     vm.dump_bc = opt.dump_bc;
     vm.verbose_alloc = opt.verbose_alloc;
+    vm.heap.verify_barriers = opt.verify_barriers;
+    vm.heap.gc_stress = opt.gc_stress;
+    vm.heap.verify_heap = opt.verify_heap;
+    vm.stop_world = opt.gc_stress;
     vm.jit_threshold = opt.jit_threshold as _;
     wafflelink::LOG.store(opt.verbose, std::sync::atomic::Ordering::Relaxed);
     set_vm(&*vm);
     runtime::initialize();
-    let reader = Reader::from_file(opt.input.as_os_str().to_str().unwrap()).unwrap();
+    let input = match opt.input {
+        Some(input) => input,
+        None => {
+            repl();
+            if opt.gc_stats {
+                print_gc_stats();
+            }
+            if let Some(path) = opt.heap_snapshot {
+                write_heap_snapshot(&path);
+            }
+            return;
+        }
+    };
+    let reader = Reader::from_file(input.as_os_str().to_str().unwrap()).unwrap();
+    let filename = reader.filename().to_owned();
+    let src = reader.src().to_owned();
     let mut ast = vec![];
     let mut p = Parser::new(reader, &mut ast);
-    if let Err(e) = p.parse() {
-        eprintln!("{}", e);
+    if let Err(errors) = p.parse() {
+        for e in errors.iter() {
+            eprintln!("{}", e.render(&filename, &src));
+        }
+        eprintln!("aborting due to {} previous error(s)", errors.len());
         return;
     }
     let (m, code) = match compile(&ast) {
         Ok(c) => c,
         Err(e) => {
-            eprintln!("{}", e);
+            eprintln!("{}", e.render(&filename, &src));
             return;
         }
     };
@@ -86,6 +132,161 @@ fn main() {
     } else {
         runtime::print_val(res.value());
     }
+    if opt.gc_stats {
+        print_gc_stats();
+    }
+    if let Some(path) = opt.heap_snapshot {
+        write_heap_snapshot(&path);
+    }
+}
+
+fn write_heap_snapshot(path: &str) {
+    if let Err(e) = get_vm().heap.write_snapshot(path) {
+        eprintln!("cannot write heap snapshot to '{}': {}", path, e);
+    }
+}
+
+fn print_gc_stats() {
+    let mut b = String::new();
+    get_vm().heap.dump_stats(&mut b).unwrap();
+    eprint!("{}", b);
+}
+
+const REPL_HELP: &str = "\
+:bytecode   toggle bytecode dump of compiled input
+:disasm     toggle disassembly of JIT compiled code
+:help       show this message
+:quit       exit";
+
+fn repl() {
+    println!("WaffleLink REPL, type :help for commands");
+    let stdin = std::io::stdin();
+    let mut src = String::new();
+    loop {
+        print!("{}", if src.is_empty() { ">> " } else { ".. " });
+        std::io::stdout().flush().unwrap();
+        let mut line = String::new();
+        if stdin.read_line(&mut line).unwrap() == 0 {
+            break;
+        }
+        if src.is_empty() {
+            let vm = get_vm();
+            match line.trim() {
+                "" => continue,
+                ":quit" | ":q" => break,
+                ":help" => {
+                    println!("{}", REPL_HELP);
+                    continue;
+                }
+                ":bytecode" => {
+                    vm.dump_bc = !vm.dump_bc;
+                    println!("bytecode dump {}", if vm.dump_bc { "on" } else { "off" });
+                    continue;
+                }
+                ":disasm" => {
+                    vm.disasm = !vm.disasm;
+                    println!("disassembly {}", if vm.disasm { "on" } else { "off" });
+                    continue;
+                }
+                cmd if cmd.starts_with(':') => {
+                    println!("unknown command '{}', type :help for commands", cmd);
+                    continue;
+                }
+                _ => (),
+            }
+        }
+        src.push_str(&line);
+        // keep reading until every bracket is closed so blocks can span several lines.
+        if unclosed_brackets(&src) > 0 {
+            continue;
+        }
+        if let Some(res) = repl_eval(&src) {
+            if res.is_error() {
+                print!("Error: ");
+            }
+            runtime::print_val(res.value());
+        }
+        src.clear();
+    }
+}
+
+fn unclosed_brackets(src: &str) -> i32 {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut chars = src.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' if in_string => {
+                chars.next();
+            }
+            '"' => in_string = !in_string,
+            '{' | '(' | '[' if !in_string => depth += 1,
+            '}' | ')' | ']' if !in_string => depth -= 1,
+            _ => (),
+        }
+    }
+    depth
+}
+
+/// Compile and run one REPL input. Top level `let`/`var` bindings and named functions
+/// become globals so that later inputs see them. `None` when the input has errors.
+fn repl_eval(src: &str) -> Option<WaffleResult> {
+    let mut ast = vec![];
+    let mut p = Parser::new(Reader::from_string(src), &mut ast);
+    if let Err(errors) = p.parse() {
+        for e in errors.iter() {
+            eprintln!("{}", e.render("<repl>", src));
+        }
+        return None;
+    }
+    let ast = ast.into_iter().map(globalize).collect::<Vec<_>>();
+    let (m, code) = match compile(&ast) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e.render("<repl>", src));
+            return None;
+        }
+    };
+    let vm = get_vm();
+    if vm.dump_bc {
+        let mut b = String::new();
+        code.dump(&mut b).unwrap();
+        println!("{}", b);
+    }
+    let mut fun = function::Function::new(&mut vm.heap, code, "<repl>");
+    fun.module = Some(m);
+    let res = fun.execute(Value::undefined(), &[]);
+    for (name, val) in m.scope.iter() {
+        vm.globals.insert(name, *val);
+    }
+    Some(res)
+}
+
+fn globalize(e: Box<Expr>) -> Box<Expr> {
+    let pos = e.pos;
+    let (name, init) = match &e.expr {
+        ExprKind::Let(_, pat, init) => match &pat.decl {
+            PatternDecl::Ident(name) => (name.clone(), Some(init.clone())),
+            _ => return e,
+        },
+        ExprKind::Var(_, name, init) => (name.clone(), init.clone()),
+        _ => return e,
+    };
+    let globals = &mut get_vm().globals;
+    if !globals.has(&name) {
+        globals.insert(&name, Value::undefined());
+    }
+    let ident = Box::new(Expr {
+        pos,
+        expr: ExprKind::Ident(name),
+    });
+    match init {
+        Some(init) => Box::new(Expr {
+            pos,
+            expr: ExprKind::Assign(ident, init),
+        }),
+        None => ident,
+    }
 }
 
 /*
@@ -103,7 +304,7 @@ fn main() {
     let vm = VM::new(&x);
     //vm.log = true;
     set_vm(&vm);
-    let mut heap = Heap::new(&x);
+    let mut heap = Heap::new();
     let func = function::Function::new_native(&mut heap, foo);
     let mut cb = Box::new(CodeBlock::new());
     cb.constants.push(Value::new_int(2));
@@ -153,3 +354,34 @@ fn main() {
     println!("{}", res.value().to_int32());
 }
 */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unclosed_brackets() {
+        assert_eq!(unclosed_brackets("function f() {"), 1);
+        assert_eq!(unclosed_brackets("let a = [(1, 2)]"), 0);
+        assert_eq!(unclosed_brackets("let s = \"{\\\"(\""), 0);
+        assert_eq!(unclosed_brackets("}"), -1);
+    }
+
+    #[test]
+    fn test_bindings_persist_across_inputs() {
+        let x = false;
+        let vm = VM::new(&x);
+        set_vm(Box::into_raw(vm));
+        runtime::initialize();
+        let eval = |src: &str| runtime::val_str(repl_eval(src).unwrap().value());
+        assert!(!repl_eval("let x = 40").unwrap().is_error());
+        assert!(!repl_eval("function add(a) { return a + x }")
+            .unwrap()
+            .is_error());
+        assert_eq!(eval("x = x + 1\nadd(1)"), "42");
+        assert!(repl_eval("let = 1").is_none());
+        assert!(repl_eval("1 = 2").is_none());
+        assert!(repl_eval("f() = 1").is_none());
+        assert_eq!(eval("add(0)"), "41");
+    }
+}
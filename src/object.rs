@@ -12,6 +12,9 @@ pub struct Header {
 const MARK_BITS: usize = 2;
 const MARK_MASK: usize = (2 << MARK_BITS) - 1;
 const FWD_MASK: usize = !0 & !MARK_MASK;
+const MARK_BIT: usize = 1;
+/// Set while an old object is in the remembered set of the heap.
+pub const REMEMBERED_BIT: usize = 2;
 impl Header {
     pub const fn new() -> Header {
         Header {
@@ -46,30 +49,30 @@ impl Header {
     #[inline(always)]
     pub fn mark_non_atomic(&mut self) {
         let fwdptr = self.fwdptr.load(Ordering::Relaxed);
-        self.fwdptr.store(fwdptr | 1, Ordering::Relaxed);
+        self.fwdptr.store(fwdptr | MARK_BIT, Ordering::Relaxed);
     }
 
     #[inline(always)]
     pub fn unmark_non_atomic(&mut self) {
         let fwdptr = self.fwdptr.load(Ordering::Relaxed);
-        self.fwdptr.store(fwdptr & FWD_MASK, Ordering::Relaxed);
+        self.fwdptr.store(fwdptr & !MARK_BIT, Ordering::Relaxed);
     }
 
     #[inline(always)]
     pub fn is_marked_non_atomic(&self) -> bool {
         let fwdptr = self.fwdptr.load(Ordering::Relaxed);
-        (fwdptr & MARK_MASK) != 0
+        (fwdptr & MARK_BIT) != 0
     }
 
     #[inline(always)]
     pub fn try_mark_non_atomic(&self) -> bool {
         let fwdptr = self.fwdptr.load(Ordering::Relaxed);
 
-        if (fwdptr & MARK_MASK) != 0 {
+        if (fwdptr & MARK_BIT) != 0 {
             return false;
         }
 
-        self.fwdptr.store(fwdptr | 1, Ordering::Relaxed);
+        self.fwdptr.store(fwdptr | MARK_BIT, Ordering::Relaxed);
         true
     }
 
@@ -77,9 +80,31 @@ impl Header {
     pub fn try_mark(&self) -> bool {
         let old = self.fwdptr.load(Ordering::Relaxed);
         self.fwdptr
-            .compare_exchange(old, old | 1, Ordering::SeqCst, Ordering::Relaxed)
+            .compare_exchange(old, old | MARK_BIT, Ordering::SeqCst, Ordering::Relaxed)
             .is_ok()
     }
+
+    #[inline(always)]
+    pub fn is_remembered(&self) -> bool {
+        self.fwdptr.load(Ordering::Relaxed) & REMEMBERED_BIT != 0
+    }
+
+    /// Set remembered bit, returns false if it was already set.
+    #[inline(always)]
+    pub fn try_remember(&mut self) -> bool {
+        let fwdptr = self.fwdptr.load(Ordering::Relaxed);
+        if fwdptr & REMEMBERED_BIT != 0 {
+            return false;
+        }
+        self.fwdptr.store(fwdptr | REMEMBERED_BIT, Ordering::Relaxed);
+        true
+    }
+
+    #[inline(always)]
+    pub fn forget(&mut self) {
+        let fwdptr = self.fwdptr.load(Ordering::Relaxed);
+        self.fwdptr.store(fwdptr & !REMEMBERED_BIT, Ordering::Relaxed);
+    }
 }
 #[repr(C)]
 pub struct Obj {
@@ -88,6 +113,7 @@ pub struct Obj {
 }
 
 pub static OBJECT_VTBL: VTable = VTable {
+    name: "Object",
     element_size: 0,
     instance_size: std::mem::size_of::<RegularObj>(),
     parent: None,
@@ -95,7 +121,7 @@ pub static OBJECT_VTBL: VTable = VTable {
     index_fn: None,
     calc_size_fn: None,
     apply_fn: None,
-    destroy_fn: None,
+    destroy_fn: Some(destroy_obj),
     set_fn: Some(obj_set),
     trace_fn: Some(trace_obj),
     set_index_fn: None,
@@ -115,7 +141,7 @@ fn obj_lookup(vm: &crate::VM, this: Ref<Obj>, key: Value) -> WaffleResult {
             .cast(),
         ));
     }
-    WaffleResult::okay(this.map.get(&key.unwrap()).copied().unwrap_or_else(|| {
+    WaffleResult::okay(this.table.get(key.unwrap().str()).unwrap_or_else(|| {
         if this.prototype.is_cell() && !this.prototype.is_empty() {
             if let Some(fun) = this.prototype.as_cell().vtable.lookup_fn {
                 let res = fun(vm, this.prototype.as_cell(), keyv);
@@ -147,8 +173,12 @@ fn obj_set(_: &crate::VM, this: Ref<Obj>, key: Value, value: Value) -> WaffleRes
     let keyv = key;
     let key = key_from_val(key);
     if let Some(key) = key {
-        this.map.insert(key, value);
-        //this.table.table.set(key, value);
+        let old = this.table.set(key.str(), value);
+        let heap = &mut get_vm().heap;
+        if let Some(old) = old {
+            heap.satb_barrier(old);
+        }
+        heap.write_barrier(this.cast(), value);
         WaffleResult::okay(Value::new_bool(true))
     } else {
         WaffleResult::error(Value::from(
@@ -161,15 +191,20 @@ fn obj_set(_: &crate::VM, this: Ref<Obj>, key: Value, value: Value) -> WaffleRes
     }
 }
 
+fn destroy_obj(x: Ref<Obj>) {
+    let mut x = x.cast::<RegularObj>();
+    unsafe {
+        std::ptr::drop_in_place(&mut x.table);
+    }
+}
+
 fn trace_obj(x: Ref<Obj>, trace: &mut dyn FnMut(*const Ref<Obj>)) {
     let x = x.cast::<RegularObj>();
-    for (key, prop) in x.map.iter() {
-        trace(unsafe { std::mem::transmute(key) });
+    for (_, prop) in x.table.iter() {
         if prop.is_cell() {
             trace(prop.as_cell_ref());
         }
     }
-    // trace(x.table.cast());
     if x.prototype.is_cell() {
         trace(x.prototype.as_cell_ref());
     }
@@ -179,8 +214,7 @@ pub struct RegularObj {
     header: Header,
     pub vtable: &'static VTable,
     pub prototype: Value,
-    pub map: indexmap::IndexMap<Ref<WaffleString>, Value>,
-    //pub table: Ref<table::Table>,
+    pub table: crate::table::Table,
 }
 
 impl RegularObj {
@@ -191,14 +225,23 @@ impl RegularObj {
                 header: Header::new(),
                 vtable: &OBJECT_VTBL,
                 prototype: proto,
-                map: Default::default(),
-                //table: get_vm().allocate(table::Table::new(class)),
+                table: crate::table::Table::new(),
             });
             Ref {
                 ptr: std::ptr::NonNull::new(mem.to_mut_ptr()).unwrap(),
             }
         }
     }
+
+    /// Offset of the structure pointer, used by JIT.
+    pub fn offset_of_structure() -> i32 {
+        offset_of!(RegularObj, table) as i32 + crate::table::Table::offset_of_structure()
+    }
+
+    /// Offset of inline property `slot`, used by JIT.
+    pub fn offset_of_inline_slot(slot: u32) -> i32 {
+        offset_of!(RegularObj, table) as i32 + crate::table::Table::offset_of_inline_slot(slot)
+    }
 }
 
 #[repr(C)]
@@ -305,8 +348,15 @@ impl Obj {
         self.vtable as *const _ == &crate::function::FUNCTION_VTBL as *const _
     }
 
+    pub fn is_tuple(&self) -> bool {
+        self.vtable as *const _ == &crate::builtins::TUPLE_VTBL as *const _
+    }
+
+    pub fn is_upvalue(&self) -> bool {
+        self.vtable as *const _ == &crate::builtins::UPVALUE_VTBL as *const _
+    }
+
     pub fn size_for_vtblptr(&self, vtblptr: Address) -> usize {
-        println!("obj {:p}", self);
         let vtbl = unsafe { &*vtblptr.to_mut_ptr::<VTable>() };
         let instance_size = vtbl.instance_size;
 
@@ -360,45 +410,47 @@ impl Obj {
         }
     }
 }
-fn determine_array_size(obj: &Obj) -> usize {
-    let handle: Ref<Array> = Ref {
-        ptr: std::ptr::NonNull::new(obj as *const Obj as *mut Array).unwrap(),
-    };
-
-    let calc = Header::size() as usize
-        + std::mem::size_of::<usize>()
-        + std::mem::size_of::<Value>() * handle.len() as usize;
-    calc
+fn determine_array_size(_obj: &Obj) -> usize {
+    std::mem::size_of::<Array>()
 }
 
+/// Growable array of values. Elements live in a separately allocated backing store that
+/// is released by `ARRAY_VTBL.destroy_fn`, the heap counts it as external memory.
 #[repr(C)]
 pub struct Array {
     header: Header,
     pub vtable: &'static VTable,
     length: usize,
-    data: Value,
+    capacity: usize,
+    data: *mut Value,
 }
 
 impl Array {
     pub fn new(heap: &mut crate::heap::Heap, size: usize, init: Value) -> Ref<Self> {
-        let ssize = (std::mem::size_of::<Self>() - 8) + size * 8;
-        let mem = heap.allocate(ssize);
+        let mem = heap.allocate(std::mem::size_of::<Self>());
         let mut this = Ref {
             ptr: std::ptr::NonNull::new(mem.to_mut_ptr::<Self>()).unwrap(),
         };
         this.header = Header::new();
-        this.length = size;
         this.vtable = &crate::builtins::ARRAY_VTBL;
+        this.length = 0;
+        this.capacity = 0;
+        this.data = std::ptr::null_mut();
+        this.reserve(size);
+        this.length = size;
+        // fresh array is young, no barriers needed.
         for i in 0..size {
-            this.set_at(i, init);
+            unsafe {
+                *this.data.offset(i as isize) = init;
+            }
         }
         this
     }
     pub fn data(&self) -> *const Value {
-        &self.data as *const Value
+        self.data
     }
     pub fn get_at(&self, idx: usize) -> Value {
-        if idx < self.len() {
+        if idx >= self.len() {
             return Value::undefined();
         }
         unsafe { *self.data().offset(idx as isize) }
@@ -408,9 +460,73 @@ impl Array {
         if idx >= self.len() {
             panic!("Overflow idx");
         }
+        let heap = &mut get_vm().heap;
         unsafe {
+            heap.satb_barrier(*self.data().offset(idx as isize));
             *self.data_mut().offset(idx as isize) = val;
         }
+        let this: Ref<Obj> = Address::from_ptr(self as *const Self).into();
+        heap.write_barrier(this, val);
+    }
+
+    /// Make sure backing store can hold at least `capacity` elements.
+    pub fn reserve(&mut self, capacity: usize) {
+        if capacity <= self.capacity {
+            return;
+        }
+        let new_cap = capacity.max(self.capacity * 2).max(4);
+        get_vm()
+            .heap
+            .report_external_allocation((new_cap - self.capacity) * std::mem::size_of::<Value>());
+        self.data = unsafe {
+            libc::realloc(
+                self.data.cast(),
+                new_cap * std::mem::size_of::<Value>(),
+            )
+            .cast()
+        };
+        assert!(!self.data.is_null(), "array backing store allocation failed");
+        self.capacity = new_cap;
+    }
+
+    /// Set new length, new slots are filled with `undefined`.
+    pub fn resize(&mut self, len: usize) {
+        self.reserve(len);
+        for i in self.length..len {
+            unsafe {
+                *self.data.offset(i as isize) = Value::undefined();
+            }
+        }
+        self.length = len;
+    }
+
+    pub fn push(&mut self, val: Value) {
+        let len = self.length;
+        self.resize(len + 1);
+        self.set_at(len, val);
+    }
+
+    pub fn pop(&mut self) -> Value {
+        if self.length == 0 {
+            return Value::undefined();
+        }
+        self.length -= 1;
+        let val = unsafe { *self.data.offset(self.length as isize) };
+        get_vm().heap.satb_barrier(val);
+        val
+    }
+
+    /// Release backing store.
+    pub fn free_storage(&mut self) {
+        get_vm()
+            .heap
+            .report_external_free(self.capacity * std::mem::size_of::<Value>());
+        unsafe {
+            libc::free(self.data.cast());
+        }
+        self.data = std::ptr::null_mut();
+        self.capacity = 0;
+        self.length = 0;
     }
     pub fn header(&self) -> &Header {
         &self.header
@@ -424,12 +540,219 @@ impl Array {
         self.length
     }
 
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Offset of backing store pointer, used by JIT.
+    pub fn offset_of_data() -> i32 {
+        offset_of!(Array, data) as i32
+    }
+
     pub fn data_address(&self) -> Address {
         Address::from_ptr(self.data())
     }
 
     pub fn data_mut(&mut self) -> *mut Value {
-        &self.data as *const Value as *mut Value
+        self.data
+    }
+}
+
+/// Heap cell holding a local variable captured by closures, shared between the declaring
+/// frame and every closure that captures it.
+#[repr(C)]
+pub struct Upvalue {
+    header: Header,
+    pub vtable: &'static VTable,
+    pub value: Value,
+}
+
+impl Upvalue {
+    pub fn new(heap: &mut crate::heap::Heap, value: Value) -> Ref<Self> {
+        let mem = heap.allocate(std::mem::size_of::<Self>());
+        unsafe {
+            mem.to_mut_ptr::<Self>().write(Self {
+                header: Header::new(),
+                vtable: &crate::builtins::UPVALUE_VTBL,
+                value,
+            });
+        }
+        Ref {
+            ptr: std::ptr::NonNull::new(mem.to_mut_ptr()).unwrap(),
+        }
+    }
+
+    /// Offset of stored value, used by JIT.
+    pub fn offset_of_value() -> i32 {
+        offset_of!(Upvalue, value) as i32
+    }
+}
+
+/// Reference that does not keep its target alive. `target` becomes `undefined` once the
+/// target is collected.
+#[repr(C)]
+pub struct WeakRef {
+    header: Header,
+    pub vtable: &'static VTable,
+    pub target: Value,
+}
+
+impl WeakRef {
+    pub fn new(heap: &mut crate::heap::Heap, target: Ref<Obj>) -> Ref<Self> {
+        let mem = heap.allocate(std::mem::size_of::<Self>());
+        unsafe {
+            mem.to_mut_ptr::<Self>().write(Self {
+                header: Header::new(),
+                vtable: &crate::builtins::WEAK_REF_VTBL,
+                target: Value::from(target),
+            });
+        }
+        Ref {
+            ptr: std::ptr::NonNull::new(mem.to_mut_ptr()).unwrap(),
+        }
+    }
+
+    /// Target or `undefined` if it was collected.
+    pub fn get(&self) -> Value {
+        // while marking the target may be reachable only through this reference, it has
+        // to be shaded before the mutator can store it somewhere.
+        get_vm().heap.satb_barrier(self.target);
+        self.target
+    }
+}
+
+/// Ephemeron table keyed by object identity. Value of an entry is kept alive only while its
+/// key is reachable from outside of the map, entries with collected keys are removed.
+#[repr(C)]
+pub struct WeakMap {
+    header: Header,
+    pub vtable: &'static VTable,
+    /// Key address to key and value. Keys move when promoted so the table is rebuilt by
+    /// the collector.
+    entries: std::collections::HashMap<usize, (Value, Value)>,
+}
+
+impl WeakMap {
+    pub fn new(heap: &mut crate::heap::Heap) -> Ref<Self> {
+        let mem = heap.allocate(std::mem::size_of::<Self>());
+        unsafe {
+            mem.to_mut_ptr::<Self>().write(Self {
+                header: Header::new(),
+                vtable: &crate::builtins::WEAK_MAP_VTBL,
+                entries: Default::default(),
+            });
+        }
+        Ref {
+            ptr: std::ptr::NonNull::new(mem.to_mut_ptr()).unwrap(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn get(&self, key: Ref<Obj>) -> Option<Value> {
+        self.entries
+            .get(&key.address().to_usize())
+            .map(|(_, value)| *value)
+    }
+
+    pub fn set(&mut self, key: Ref<Obj>, value: Value) {
+        let heap = &mut get_vm().heap;
+        if let Some((_, old)) = self
+            .entries
+            .insert(key.address().to_usize(), (Value::from(key), value))
+        {
+            heap.satb_barrier(old);
+        }
+        let this: Ref<Obj> = Address::from_ptr(self as *const Self).into();
+        heap.write_barrier(this, Value::from(key));
+        heap.write_barrier(this, value);
+    }
+
+    pub fn remove(&mut self, key: Ref<Obj>) -> bool {
+        match self.entries.remove(&key.address().to_usize()) {
+            Some((key, value)) => {
+                let heap = &mut get_vm().heap;
+                heap.satb_barrier(key);
+                heap.satb_barrier(value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &(Value, Value)> {
+        self.entries.values()
+    }
+
+    pub fn entries_mut(&mut self) -> impl Iterator<Item = &mut (Value, Value)> {
+        self.entries.values_mut()
+    }
+
+    /// Replace every key by `update(key)` and drop entries for which it returns `None`.
+    pub fn update_keys(&mut self, mut update: impl FnMut(Value) -> Option<Value>) {
+        let entries = std::mem::take(&mut self.entries);
+        for (_, (key, value)) in entries {
+            if let Some(key) = update(key) {
+                self.entries
+                    .insert(key.as_cell().address().to_usize(), (key, value));
+            }
+        }
+    }
+
+    pub(crate) fn drop_entries(&mut self) {
+        unsafe {
+            std::ptr::drop_in_place(&mut self.entries);
+        }
+    }
+}
+
+/// Immutable fixed-size sequence of values, elements are stored inline after the header.
+#[repr(C)]
+pub struct Tuple {
+    header: Header,
+    pub vtable: &'static VTable,
+    length: usize,
+    data: [Value; 0],
+}
+
+impl Tuple {
+    pub fn new(heap: &mut crate::heap::Heap, values: &[Value]) -> Ref<Self> {
+        let mem = heap.allocate(Self::size_for(values.len()));
+        let mut this = Ref {
+            ptr: std::ptr::NonNull::new(mem.to_mut_ptr::<Self>()).unwrap(),
+        };
+        this.header = Header::new();
+        this.vtable = &crate::builtins::TUPLE_VTBL;
+        this.length = values.len();
+        unsafe {
+            std::ptr::copy_nonoverlapping(values.as_ptr(), this.data_mut(), values.len());
+        }
+        this
+    }
+
+    pub fn size_for(len: usize) -> usize {
+        std::mem::size_of::<Self>() + len * std::mem::size_of::<Value>()
+    }
+
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn get_at(&self, idx: usize) -> Value {
+        if idx >= self.len() {
+            return Value::undefined();
+        }
+        unsafe { *self.data.as_ptr().offset(idx as isize) }
+    }
+
+    pub fn data(&self) -> *const Value {
+        self.data.as_ptr()
+    }
+
+    fn data_mut(&mut self) -> *mut Value {
+        self.data.as_mut_ptr()
     }
 }
 
@@ -527,6 +850,7 @@ pub struct Module {
 }
 
 pub static MODULE_VTBL: VTable = VTable {
+    name: "Module",
     element_size: 0,
     instance_size: std::mem::size_of::<Module>(),
     parent: None,
@@ -534,12 +858,19 @@ pub static MODULE_VTBL: VTable = VTable {
     index_fn: None,
     calc_size_fn: None,
     apply_fn: None,
-    destroy_fn: None,
+    destroy_fn: Some(destroy_mod),
     set_fn: None,
     trace_fn: Some(trace_mod),
     set_index_fn: None,
 };
 
+fn destroy_mod(this: Ref<Obj>) {
+    let mut this = this.cast::<Module>();
+    unsafe {
+        std::ptr::drop_in_place(&mut this.scope);
+    }
+}
+
 fn trace_mod(this: Ref<Obj>, trace: &mut dyn FnMut(*const Ref<Obj>)) {
     let this = this.cast::<Module>();
     trace(unsafe { std::mem::transmute(&this.name) });
@@ -9,8 +9,25 @@ pub fn initialize() {
     use std::sync::Once;
     static RT_INIT: Once = Once::new();
     RT_INIT.call_once(|| {
-        let _vm = crate::get_vm();
+        let vm = crate::get_vm();
         register_global_fn(waffle_println, "print");
+        register_global_fn(waffle_gc_stats, "gcStats");
+        register_global_fn(waffle_heap_snapshot, "heapSnapshot");
+        register_global_fn(waffle_weak_ref, "weakRef");
+        register_global_fn(waffle_weak_map, "weakMap");
+        register_global_fn(waffle_register_finalizer, "registerFinalizer");
+        register_global_fn(waffle_parent_constructor, PARENT_CONSTRUCTOR);
+        let mut array_proto = RegularObj::new(&mut vm.heap, Value::undefined());
+        for (name, f) in [
+            ("push", builtins::array_push as extern "C" fn(&mut CallFrame) -> WaffleResult),
+            ("pop", builtins::array_pop),
+        ]
+        .iter()
+        {
+            let func = Function::new_native(&mut vm.heap, *f, name);
+            array_proto.table.set(name, Value::from(func.cast()));
+        }
+        vm.array_prototype = Value::from(array_proto.cast());
     });
 }
 
@@ -37,6 +54,117 @@ pub extern "C" fn waffle_println(cf: &mut CallFrame) -> WaffleResult {
     WaffleResult::okay(Value::new_int(cf.passed_argc as _))
 }
 
+/// `gcStats()` returns heap statistics as an object. Sizes are in bytes, pause times in
+/// milliseconds and `objects` maps type names to the number of cells in the heap.
+/// `allocated` includes `external`, the size of live array backing stores.
+pub extern "C" fn waffle_gc_stats(_: &mut CallFrame) -> WaffleResult {
+    let heap = &mut get_vm().heap;
+    let stats = heap.stats.clone();
+    let mut objects = RegularObj::new(heap, Value::undefined());
+    for (name, live) in heap.live_objects().iter() {
+        objects
+            .table
+            .set(name, Value::new_double(live.count as f64));
+    }
+    let ms = |d: std::time::Duration| Value::new_double(d.as_secs_f64() * 1000.0);
+    let external = stats.allocated[heap::EXTERNAL] - stats.freed[heap::EXTERNAL];
+    let fields = [
+        ("minorCollections", Value::new_double(stats.minor_collections as f64)),
+        ("majorCollections", Value::new_double(stats.major_collections as f64)),
+        ("pauseCount", Value::new_double(stats.pauses.count as f64)),
+        ("pauseTotal", ms(stats.pauses.total)),
+        ("pauseMax", ms(stats.pauses.max)),
+        ("allocated", Value::new_double(heap.allocated as f64)),
+        ("external", Value::new_double(external as f64)),
+        ("youngAllocated", Value::new_double(stats.young_allocated as f64)),
+        ("peak", Value::new_double(stats.peak as f64)),
+        ("objects", Value::from(objects.cast())),
+    ];
+    let mut result = RegularObj::new(heap, Value::undefined());
+    for (name, val) in fields.iter() {
+        result.table.set(name, *val);
+    }
+    WaffleResult::okay(Value::from(result.cast()))
+}
+
+/// `heapSnapshot(path)` writes a heap snapshot to `path`.
+pub extern "C" fn waffle_heap_snapshot(cf: &mut CallFrame) -> WaffleResult {
+    let path = cf.get_register(VirtualRegister::new_argument(0));
+    let vm = get_vm();
+    if cf.passed_argc == 0 || !path.is_cell() || !path.as_cell().is_string() {
+        return WaffleResult::error(Value::from(
+            WaffleString::new(&mut vm.heap, "heapSnapshot expects a file path").cast(),
+        ));
+    }
+    let path = path.as_cell().cast::<WaffleString>();
+    match vm.heap.write_snapshot(path.str()) {
+        Ok(()) => WaffleResult::okay(Value::undefined()),
+        Err(e) => WaffleResult::error(Value::from(
+            WaffleString::new(
+                &mut vm.heap,
+                format!("cannot write heap snapshot to '{}': {}", path.str(), e),
+            )
+            .cast(),
+        )),
+    }
+}
+
+/// Global called by the constructor the bytecompiler synthesizes for derived classes
+/// without one. `%` keeps it out of reach of Waffle code.
+pub const PARENT_CONSTRUCTOR: &str = "%parentConstructor";
+
+/// Calls the parent of the class whose constructor called it, with the same `this` and
+/// arguments.
+pub extern "C" fn waffle_parent_constructor(cf: &mut CallFrame) -> WaffleResult {
+    let ctor = unsafe { &mut *cf.caller };
+    let parent = ctor.callee.as_cell().cast::<Function>().parent;
+    if !parent.is_cell() {
+        return WaffleResult::okay(Value::undefined());
+    }
+    let args = (0..ctor.passed_argc)
+        .map(|i| ctor.get_register(VirtualRegister::new_argument(i as _)))
+        .collect::<Vec<_>>();
+    // `this` stays rooted by the constructor frame, `operation_new` reads it back from there.
+    parent.as_cell().cast::<Function>().execute(ctor.this, &args)
+}
+
+/// `weakRef(object)` returns a weak reference, `ref.target` is `undefined` once `object` is
+/// collected.
+pub extern "C" fn waffle_weak_ref(cf: &mut CallFrame) -> WaffleResult {
+    let target = cf.get_register(VirtualRegister::new_argument(0));
+    let vm = get_vm();
+    if !target.is_cell() || target.is_empty() {
+        return vm.throw_exception_str("weakRef expects an object");
+    }
+    WaffleResult::okay(Value::from(WeakRef::new(&mut vm.heap, target.as_cell()).cast()))
+}
+
+/// `weakMap()` returns an empty map with weakly held object keys.
+pub extern "C" fn waffle_weak_map(_: &mut CallFrame) -> WaffleResult {
+    WaffleResult::okay(Value::from(WeakMap::new(&mut get_vm().heap).cast()))
+}
+
+/// `registerFinalizer(object, callback, held)` calls `callback(held)` after `object` is
+/// collected.
+pub extern "C" fn waffle_register_finalizer(cf: &mut CallFrame) -> WaffleResult {
+    let target = cf.get_register(VirtualRegister::new_argument(0));
+    let callback = cf.get_register(VirtualRegister::new_argument(1));
+    let held = cf.get_register(VirtualRegister::new_argument(2));
+    let vm = get_vm();
+    if !target.is_cell() || target.is_empty() {
+        return vm.throw_exception_str("registerFinalizer expects an object");
+    }
+    if !callback.is_cell() || !callback.as_cell().is_function() {
+        return vm.throw_exception_str("finalizer callback is not a function");
+    }
+    vm.heap.register_finalizer(
+        target.as_cell(),
+        held,
+        heap::weak::FinalizerCallback::Script(callback),
+    );
+    WaffleResult::okay(Value::undefined())
+}
+
 pub fn print_val(v: Value) {
     let mut visited = HashSet::new();
     let mut buf = String::new();
@@ -82,35 +210,25 @@ pub fn write_val(
                     }
                 }
                 write!(buffer, "]")?;
+            } else if c.is_tuple() {
+                let tuple = c.cast::<Tuple>();
+                write!(buffer, "(")?;
+                for i in 0..tuple.len() {
+                    write_val(buffer, tuple.get_at(i), visited)?;
+                    if i != tuple.len() - 1 || tuple.len() == 1 {
+                        write!(buffer, ",")?;
+                    }
+                }
+                write!(buffer, ")")?;
             } else if c.is_robj() {
                 let obj = c.cast::<RegularObj>();
                 write!(buffer, "{{")?;
-                /*match &obj.table.table {
-                    TableEnum::Fast(cls, fields) => {
-                        if let Some(d) = &cls.descriptors {
-                            for (key, desc) in d.iter() {
-                                if let Descriptor::Property(ix) = desc {
-                                    write!(buffer, "{} => ", key.str())?;
-                                    write_val(buffer, fields[*ix as usize], visited)?;
-                                }
-                            }
-                        }
-                    }
-                    TableEnum::Slow(_, props) => {
-                        for (key, val) in props.iter() {
-                            write!(buffer, "{} => ", key.str())?;
-                            write_val(buffer, *val, visited)?;
-                        }
-                    }
-                }*/
-                let mut i = 0;
-                for (name, prop) in obj.map.iter() {
-                    write!(buffer, "{} => ", name.str())?;
+                for (i, (name, prop)) in obj.table.iter().enumerate() {
+                    write!(buffer, "{} => ", name)?;
                     write_val(buffer, *prop, visited)?;
-                    if i != obj.map.len() - 1 {
+                    if i != obj.table.len() - 1 {
                         write!(buffer, ",")?;
                     }
-                    i += 1;
                 }
                 write!(buffer, "}}")?;
             } else if c.is_function() {
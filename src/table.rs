@@ -1 +1,368 @@
+//! Hidden classes of `RegularObj`. Objects that get the same properties in the same order
+//! share a `Structure` that maps property names to slots, structures form a transition tree
+//! rooted at the structure of the empty object. Property values are stored in the object,
+//! the first `INLINE_SLOTS` of them inside the cell itself.
+//!
+//! Objects switch to dictionary mode, a hash map owned by the object, once they get more
+//! than `MAX_STRUCTURE_PROPERTIES` properties or add a property to a structure that already
+//! has `MAX_TRANSITIONS` transitions. Structures are reference counted by the objects, the
+//! child structures, the inline caches and the JIT code using them and freed with the last
+//! reference.
+//! Property names are interned as `Atom`s, structures hold no references into the heap.
+//! Atoms and the empty structure belong to the `VM` and, like the rest of it, are used by one
+//! thread at a time.
+use crate::value::Value;
+use indexmap::IndexMap;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 
+/// Number of property slots stored in the object cell.
+pub const INLINE_SLOTS: usize = 4;
+/// Adding a property to an object with this many properties switches it to dictionary mode.
+pub const MAX_STRUCTURE_PROPERTIES: usize = 64;
+/// Adding a property to an object whose structure has this many transitions switches it to
+/// dictionary mode, objects built with many different property orders don't grow the tree.
+pub const MAX_TRANSITIONS: usize = 16;
+
+/// Interned property name.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Atom(u32);
+
+/// Interned property names, see `VM::atoms`.
+#[derive(Default)]
+pub struct Atoms {
+    names: Vec<&'static str>,
+    ids: HashMap<&'static str, u32>,
+}
+
+impl Atom {
+    /// Atom of `name`, interned on first use. Names live as long as the process.
+    pub fn new(name: &str) -> Atom {
+        let atoms = &mut crate::get_vm().atoms;
+        if let Some(id) = atoms.ids.get(name) {
+            return Atom(*id);
+        }
+        let name: &'static str = Box::leak(name.to_owned().into_boxed_str());
+        let id = atoms.names.len() as u32;
+        atoms.names.push(name);
+        atoms.ids.insert(name, id);
+        Atom(id)
+    }
+
+    /// Atom of `name` if it was interned, names that never were can't be properties.
+    pub fn lookup(name: &str) -> Option<Atom> {
+        crate::get_vm().atoms.ids.get(name).map(|id| Atom(*id))
+    }
+
+    pub fn as_str(self) -> &'static str {
+        crate::get_vm().atoms.names[self.0 as usize]
+    }
+}
+
+pub struct Structure {
+    /// Structure this one was created from, null for the empty structure.
+    parent: *const Structure,
+    /// Property names in slot order.
+    names: Vec<Atom>,
+    slots: HashMap<Atom, u32>,
+    /// Children by added name. Children are not counted, they unregister when freed.
+    transitions: RefCell<HashMap<Atom, *const Structure>>,
+    refs: Cell<usize>,
+}
+
+impl Structure {
+    /// Root of a transition tree, the VM keeps one in `VM::empty_structure`.
+    pub fn new_root() -> Box<Structure> {
+        Box::new(Structure {
+            parent: std::ptr::null(),
+            names: vec![],
+            slots: HashMap::new(),
+            transitions: Default::default(),
+            // freed with the VM only.
+            refs: Cell::new(1),
+        })
+    }
+
+    /// Structure of objects without properties, root of the transition tree.
+    pub fn empty() -> &'static Structure {
+        &crate::get_vm().empty_structure
+    }
+
+    pub fn slot(&self, name: &str) -> Option<u32> {
+        self.slots.get(&Atom::lookup(name)?).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn names(&self) -> &[Atom] {
+        &self.names
+    }
+
+    pub fn transition_count(&self) -> usize {
+        self.transitions.borrow().len()
+    }
+
+    /// Structure with `name` added in the next slot. Every object taking the same
+    /// transition ends up with the same structure. `None` once the structure has
+    /// `MAX_TRANSITIONS` other transitions.
+    pub fn add_property(&self, name: Atom) -> Option<*const Structure> {
+        if let Some(next) = self.transitions.borrow().get(&name) {
+            return Some(*next);
+        }
+        if self.transition_count() == MAX_TRANSITIONS {
+            return None;
+        }
+        let mut names = self.names.clone();
+        names.push(name);
+        let mut slots = self.slots.clone();
+        slots.insert(name, self.names.len() as u32);
+        Self::retain(self);
+        let next: *const Structure = Box::into_raw(Box::new(Structure {
+            parent: self,
+            names,
+            slots,
+            transitions: Default::default(),
+            refs: Cell::new(0),
+        }));
+        self.transitions.borrow_mut().insert(name, next);
+        Some(next)
+    }
+
+    pub fn retain(structure: *const Structure) {
+        let structure = unsafe { &*structure };
+        structure.refs.set(structure.refs.get() + 1);
+    }
+
+    /// Drop a reference to `structure`, the last one frees it and releases its parent.
+    pub fn release(structure: *const Structure) {
+        let this = unsafe { &*structure };
+        let refs = this.refs.get() - 1;
+        this.refs.set(refs);
+        if refs != 0 {
+            return;
+        }
+        debug_assert!(this.transitions.borrow().is_empty());
+        let parent = this.parent;
+        let name = *this.names.last().unwrap();
+        unsafe {
+            (*parent).transitions.borrow_mut().remove(&name);
+            drop(Box::from_raw(structure as *mut Structure));
+        }
+        Self::release(parent);
+    }
+}
+
+/// Property storage of one object.
+#[repr(C)]
+pub struct Table {
+    /// Null in dictionary mode. Read by JIT code.
+    structure: *const Structure,
+    inline: [Value; INLINE_SLOTS],
+    /// Slots from `INLINE_SLOTS` on.
+    out_of_line: Vec<Value>,
+    dictionary: Option<Box<IndexMap<String, Value>>>,
+}
+
+impl Table {
+    pub fn new() -> Self {
+        let structure = Structure::empty();
+        Structure::retain(structure);
+        Self {
+            structure,
+            inline: [Value::undefined(); INLINE_SLOTS],
+            out_of_line: vec![],
+            dictionary: None,
+        }
+    }
+
+    pub fn offset_of_structure() -> i32 {
+        offset_of!(Table, structure) as i32
+    }
+
+    /// Offset of inline `slot`, used by JIT.
+    pub fn offset_of_inline_slot(slot: u32) -> i32 {
+        debug_assert!((slot as usize) < INLINE_SLOTS);
+        (offset_of!(Table, inline) + slot as usize * std::mem::size_of::<Value>()) as i32
+    }
+
+    /// `None` in dictionary mode.
+    pub fn structure(&self) -> Option<&Structure> {
+        if self.structure.is_null() {
+            None
+        } else {
+            Some(unsafe { &*self.structure })
+        }
+    }
+
+    pub fn is_dictionary(&self) -> bool {
+        self.dictionary.is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.dictionary {
+            Some(dictionary) => dictionary.len(),
+            None => self.structure().unwrap().len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Value in `slot` of the structure, callers check the structure first.
+    pub fn slot(&self, slot: u32) -> &Value {
+        let slot = slot as usize;
+        if slot < INLINE_SLOTS {
+            &self.inline[slot]
+        } else {
+            &self.out_of_line[slot - INLINE_SLOTS]
+        }
+    }
+
+    pub fn slot_mut(&mut self, slot: u32) -> &mut Value {
+        let slot = slot as usize;
+        if slot < INLINE_SLOTS {
+            &mut self.inline[slot]
+        } else {
+            &mut self.out_of_line[slot - INLINE_SLOTS]
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Value> {
+        match &self.dictionary {
+            Some(dictionary) => dictionary.get(name).copied(),
+            None => self
+                .structure()
+                .unwrap()
+                .slot(name)
+                .map(|slot| *self.slot(slot)),
+        }
+    }
+
+    /// Set property `name`, returns the previous value if the property existed.
+    pub fn set(&mut self, name: &str, value: Value) -> Option<Value> {
+        if let Some(dictionary) = &mut self.dictionary {
+            return dictionary.insert(name.to_owned(), value);
+        }
+        if let Some(slot) = self.structure().unwrap().slot(name) {
+            return Some(std::mem::replace(self.slot_mut(slot), value));
+        }
+        let structure = self.structure().unwrap();
+        let slot = structure.len();
+        let next = if slot == MAX_STRUCTURE_PROPERTIES {
+            None
+        } else {
+            structure.add_property(Atom::new(name))
+        };
+        let next = match next {
+            Some(next) => next,
+            None => {
+                self.convert_to_dictionary();
+                return self.set(name, value);
+            }
+        };
+        Structure::retain(next);
+        Structure::release(std::mem::replace(&mut self.structure, next));
+        if slot < INLINE_SLOTS {
+            self.inline[slot] = value;
+        } else {
+            self.out_of_line.push(value);
+        }
+        None
+    }
+
+    /// Properties in insertion order.
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (&str, &Value)> + '_> {
+        match &self.dictionary {
+            Some(dictionary) => Box::new(dictionary.iter().map(|(k, v)| (k.as_str(), v))),
+            None => Box::new(
+                self.structure()
+                    .unwrap()
+                    .names()
+                    .iter()
+                    .enumerate()
+                    .map(move |(slot, name)| (name.as_str(), self.slot(slot as u32))),
+            ),
+        }
+    }
+
+    fn convert_to_dictionary(&mut self) {
+        let dictionary = self
+            .iter()
+            .map(|(name, value)| (name.to_owned(), *value))
+            .collect::<IndexMap<_, _>>();
+        self.dictionary = Some(Box::new(dictionary));
+        Structure::release(std::mem::replace(&mut self.structure, std::ptr::null()));
+        self.inline = [Value::undefined(); INLINE_SLOTS];
+        self.out_of_line = vec![];
+    }
+}
+
+impl Default for Table {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        if !self.structure.is_null() {
+            Structure::release(self.structure);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_structures_are_shared_and_freed() {
+        // objects of other tests release structures when the shared VM collects them.
+        let _vm = crate::testing::lock();
+        let root = Structure::empty();
+        let transitions = root.transition_count();
+        let mut a = Table::new();
+        let mut b = Table::new();
+        a.set("structureTestX", Value::new_int(1));
+        b.set("structureTestX", Value::new_int(2));
+        assert_eq!(a.structure, b.structure);
+        assert_eq!(root.transition_count(), transitions + 1);
+        a.set("structureTestY", Value::new_int(3));
+        drop(b);
+        assert_eq!(root.transition_count(), transitions + 1);
+        drop(a);
+        assert_eq!(root.transition_count(), transitions);
+    }
+
+    #[test]
+    fn test_dictionary_mode_after_many_transitions() {
+        let _vm = crate::testing::lock();
+        let mut base = Table::new();
+        base.set("transitionTestBase", Value::new_int(0));
+        let mut tables = (0..MAX_TRANSITIONS + 1)
+            .map(|i| {
+                let mut table = Table::new();
+                table.set("transitionTestBase", Value::new_int(0));
+                table.set(&format!("transitionTest{}", i), Value::new_int(i as _));
+                table
+            })
+            .collect::<Vec<_>>();
+        assert!(tables[..MAX_TRANSITIONS].iter().all(|t| !t.is_dictionary()));
+        let last = tables.last_mut().unwrap();
+        assert!(last.is_dictionary());
+        assert!(last.get("transitionTestBase") == Some(Value::new_int(0)));
+        assert_eq!(
+            base.structure().unwrap().transition_count(),
+            MAX_TRANSITIONS
+        );
+        drop(tables);
+        assert_eq!(base.structure().unwrap().transition_count(), 0);
+    }
+}
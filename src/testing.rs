@@ -0,0 +1,72 @@
+//! Helpers for tests that run Waffle programs. There is a single global VM, tests that
+//! touch it hold the guard returned by `lock` so they never run at the same time.
+use crate::bytecompiler::compile;
+use crate::frontend::{parser::Parser, reader::Reader};
+use crate::*;
+
+static LOCK: parking_lot::Mutex<()> = parking_lot::const_mutex(());
+
+/// Lock the VM shared by tests, it is created and initialized by the first caller.
+pub fn lock() -> parking_lot::MutexGuard<'static, ()> {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    let guard = LOCK.lock();
+    INIT.call_once(|| {
+        let x = false;
+        let vm = VM::new(&x);
+        set_vm(Box::into_raw(vm));
+        runtime::initialize();
+    });
+    let vm = get_vm();
+    vm.template_jit = false;
+    vm.jit_threshold = 25000;
+    vm.heap.gc_stress = false;
+    vm.heap.verify_heap = false;
+    guard
+}
+
+/// Run `src` as a module and print its result, errors are prefixed with `error: `.
+/// With `jit` every function is compiled by the baseline JIT before its first call.
+pub fn run(src: &str, jit: bool) -> String {
+    run_in_module(src, jit, |_| ())
+}
+
+/// Like `run`, `init` sets up the module before the program runs.
+pub fn run_in_module(src: &str, jit: bool, init: impl FnOnce(&mut object::Module)) -> String {
+    let vm = get_vm();
+    vm.template_jit = jit;
+    vm.jit_threshold = 0;
+    let mut ast = vec![];
+    if let Err(e) = Parser::new(Reader::from_string(src), &mut ast).parse() {
+        panic!("cannot parse '{}': {:?}", src, e);
+    }
+    let (mut m, code) = match compile(&ast) {
+        Ok(c) => c,
+        Err(e) => panic!("cannot compile '{}': {:?}", src, e),
+    };
+    init(&mut m);
+    let mut fun = function::Function::new(&mut vm.heap, code, "<test>");
+    fun.module = Some(m);
+    let res = fun.execute(value::Value::undefined(), &[]);
+    let prefix = if res.is_error() { "error: " } else { "" };
+    format!("{}{}", prefix, runtime::val_str(res.value()))
+}
+
+/// Run `src` in both tiers, they have to agree. Returns the result.
+pub fn run_both(src: &str) -> String {
+    let interpreted = run(src, false);
+    let compiled = run(src, true);
+    assert_eq!(interpreted, compiled, "'{}' differs between tiers", src);
+    interpreted
+}
+
+/// Error the bytecompiler reports for `src`.
+pub fn compile_error(src: &str) -> String {
+    let mut ast = vec![];
+    if let Err(e) = Parser::new(Reader::from_string(src), &mut ast).parse() {
+        panic!("cannot parse '{}': {:?}", src, e);
+    }
+    match compile(&ast) {
+        Ok(_) => panic!("'{}' compiled", src),
+        Err(e) => e.msg.message(),
+    }
+}
@@ -3,6 +3,8 @@ use crate::value::Value;
 use crate::*;
 #[repr(C)]
 pub struct VTable {
+    /// Type name shown in heap statistics and snapshots.
+    pub name: &'static str,
     pub trace_fn: Option<fn(Ref<Obj>, &mut dyn FnMut(*const Ref<Obj>))>,
     pub lookup_fn: Option<fn(&VM, Ref<Obj>, Value) -> WaffleResult>,
     pub index_fn: Option<fn(&VM, Ref<Obj>, usize) -> WaffleResult>,
@@ -25,3 +27,22 @@ impl VTable {
         self as *const Self == &crate::builtins::ARRAY_VTBL as *const VTable
     }
 }
+
+/// Returns true if `vtable` is the vtable of one of the heap cell types.
+pub fn is_valid_vtable(vtable: *const VTable) -> bool {
+    [
+        &OBJECT_VTBL,
+        &MODULE_VTBL,
+        &function::FUNCTION_VTBL,
+        &bigint::BIGINT_VTBL,
+        &builtins::ARRAY_VTBL,
+        &builtins::TUPLE_VTBL,
+        &builtins::UPVALUE_VTBL,
+        &builtins::STRING_VTBL,
+        &builtins::WEAK_REF_VTBL,
+        &builtins::WEAK_MAP_VTBL,
+        &bytecode::CB_VTBL,
+    ]
+    .iter()
+    .any(|known| *known as *const VTable == vtable)
+}